@@ -0,0 +1,226 @@
+//! Serial console shared by the CPUs.
+//!
+//! Lines are written with the console lock held, and interrupts disabled, so
+//! that lines of several CPUs don't interleave. A CPU taking the lock again,
+//! e.g. when a log call panics or faults, writes without waiting for itself.
+//!
+//! The console is COM1, or the port set by the `KERNEL_CONSOLE` variable at
+//! build time, e.g. `KERNEL_CONSOLE=com2`.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::cpu::cpu_id;
+use crate::uart::{ComPort, Uart, UartError};
+
+pub static CONSOLE: Console<Uart> = Console::new(Uart::new(CONSOLE_PORT));
+
+const CONSOLE_PORT: ComPort = match option_env!("KERNEL_CONSOLE") {
+    None => ComPort::Com1,
+    Some(name) => match ComPort::parse(name) {
+        Some(port) => port,
+        None => panic!("KERNEL_CONSOLE must be com1, com2, com3 or com4"),
+    },
+};
+
+pub const CONSOLE_BAUD_RATE: u32 = 115_200;
+
+const NO_OWNER: u32 = u32::MAX;
+
+/// Console writing lines to W. W only holds the address of the device, so
+/// that every writer gets its own copy.
+pub struct Console<W> {
+    writer: W,
+    /// CPU holding the lock.
+    owner: AtomicU32,
+}
+
+/// Device the console reads from.
+pub trait ConsoleInput {
+    /// Returns the next received byte, if any.
+    fn try_read_byte(&mut self) -> Option<u8>;
+}
+
+impl ConsoleInput for Uart {
+    fn try_read_byte(&mut self) -> Option<u8> {
+        Uart::try_read_byte(self)
+    }
+}
+
+/// Holds the console lock, unless the CPU already held it.
+struct ConsoleGuard<'a, W> {
+    console: &'a Console<W>,
+    nested: bool,
+    interrupts: bool,
+}
+
+impl<W: Write + Copy> Console<W> {
+    pub const fn new(writer: W) -> Self {
+        Self {
+            writer,
+            owner: AtomicU32::new(NO_OWNER),
+        }
+    }
+
+    fn lock(&self) -> ConsoleGuard<'_, W> {
+        let interrupts = disable_interrupts();
+        let cpu = cpu_id();
+        let nested = self.owner.load(Ordering::Relaxed) == cpu;
+        if !nested {
+            while self
+                .owner
+                .compare_exchange_weak(NO_OWNER, cpu, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                core::hint::spin_loop();
+            }
+        }
+
+        ConsoleGuard {
+            console: self,
+            nested,
+            interrupts,
+        }
+    }
+
+    /// Writes args and a line break, atomically with the lines of other CPUs.
+    pub fn write_line(&self, args: fmt::Arguments<'_>) {
+        let _guard = self.lock();
+        let mut writer = self.writer;
+        // Nothing can be reported if the console itself fails.
+        let _ = write!(writer, "{args}\r\n");
+    }
+}
+
+impl<W: Write + Copy + ConsoleInput> Console<W> {
+    /// Waits for a byte.
+    pub fn read_byte(&self) -> u8 {
+        let mut reader = self.writer;
+        loop {
+            if let Some(byte) = reader.try_read_byte() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn echo(&self, s: &str) {
+        let _guard = self.lock();
+        let mut writer = self.writer;
+        let _ = writer.write_str(s);
+    }
+
+    /// Reads a line of printable ASCII characters into buf, echoing them, and
+    /// returns it. Backspace erases the last character, and characters past
+    /// the end of buf are dropped.
+    pub fn read_line<'a>(&self, buf: &'a mut [u8]) -> &'a str {
+        let mut len = 0;
+        loop {
+            match self.read_byte() {
+                b'\r' | b'\n' => break,
+                0x08 | 0x7f if len > 0 => {
+                    len -= 1;
+                    self.echo("\x08 \x08");
+                }
+                byte if (byte.is_ascii_graphic() || byte == b' ') && len < buf.len() => {
+                    buf[len] = byte;
+                    len += 1;
+                    // A single ASCII byte is valid UTF-8.
+                    self.echo(core::str::from_utf8(&buf[len - 1..len]).unwrap());
+                }
+                _ => {}
+            }
+        }
+        self.echo("\r\n");
+        // Only ASCII characters were stored.
+        core::str::from_utf8(&buf[..len]).unwrap()
+    }
+}
+
+impl Console<Uart> {
+    /// Initializes the UART of the console.
+    pub fn init(&self) -> Result<(), UartError> {
+        let _guard = self.lock();
+        self.writer.init(CONSOLE_BAUD_RATE)
+    }
+}
+
+impl<W> Drop for ConsoleGuard<'_, W> {
+    fn drop(&mut self) {
+        if !self.nested {
+            self.console.owner.store(NO_OWNER, Ordering::Release);
+        }
+        if self.interrupts {
+            x86_64::instructions::interrupts::enable();
+        }
+    }
+}
+
+/// Disables interrupts, returning true if they were enabled.
+#[cfg(not(test))]
+fn disable_interrupts() -> bool {
+    use x86_64::instructions::interrupts;
+
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    enabled
+}
+
+/// Host tests can't mask interrupts.
+#[cfg(test)]
+fn disable_interrupts() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use alloc::string::String;
+    use spin::Mutex;
+
+    static OUTPUT: Mutex<String> = Mutex::new(String::new());
+    static INPUT: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+
+    #[derive(Clone, Copy)]
+    struct Buffer;
+
+    impl Write for Buffer {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            OUTPUT.lock().push_str(s);
+            Ok(())
+        }
+    }
+
+    impl ConsoleInput for Buffer {
+        fn try_read_byte(&mut self) -> Option<u8> {
+            INPUT.lock().pop_front()
+        }
+    }
+
+    struct Nested<'a>(&'a Console<Buffer>);
+
+    impl fmt::Display for Nested<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.write_line(format_args!("nested"));
+            write!(f, "outer")
+        }
+    }
+
+    #[test]
+    fn reentrant_lines() {
+        let console = Console::new(Buffer);
+        console.write_line(format_args!("first"));
+        // Formatting the line writes to the console while it is locked.
+        console.write_line(format_args!("{}", Nested(&console)));
+        assert_eq!(console.owner.load(Ordering::Relaxed), NO_OWNER);
+
+        assert_eq!(*OUTPUT.lock(), "first\r\nnested\r\nouter\r\n");
+
+        OUTPUT.lock().clear();
+        INPUT.lock().extend(b"lx\x7f\x1bs -la\r");
+        let mut buf = [0; 4];
+        assert_eq!(console.read_line(&mut buf), "ls -");
+        assert_eq!(*OUTPUT.lock(), "lx\x08 \x08s -\r\n");
+    }
+}
@@ -0,0 +1,135 @@
+//! Frame pointer based stack unwinding.
+//!
+//! The kernel is built with frame pointers, so every frame starts with the
+//! saved RBP of its caller, followed by the return address:
+//!
+//! ```text
+//! rbp + 8: return address
+//! rbp:     caller rbp
+//! ```
+
+use core::arch::asm;
+use core::fmt;
+
+use x86_64::VirtAddr;
+
+use super::symbols;
+use crate::mm::paging::KERNEL_SPACE;
+
+/// Maximum number of frames walked.
+pub const MAX_DEPTH: usize = 32;
+
+/// Iterator over the return addresses of a frame pointer chain.
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    rbp: u64,
+    depth: usize,
+    /// Returns true if a frame can be read.
+    readable: fn(VirtAddr) -> bool,
+}
+
+impl Backtrace {
+    /// Walks the frames of the caller.
+    #[inline(always)]
+    pub fn current() -> Self {
+        let rbp: u64;
+        // SAFETY: reading rbp has no side effect.
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+        Self::from_rbp(rbp)
+    }
+
+    /// Walks the frames starting at rbp, e.g. the RBP of an exception frame.
+    pub fn from_rbp(rbp: u64) -> Self {
+        Self::with_check(rbp, kernel_mapped)
+    }
+
+    fn with_check(rbp: u64, readable: fn(VirtAddr) -> bool) -> Self {
+        Self {
+            rbp,
+            depth: 0,
+            readable,
+        }
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = VirtAddr;
+
+    fn next(&mut self) -> Option<VirtAddr> {
+        if self.depth == MAX_DEPTH || self.rbp == 0 || !self.rbp.is_multiple_of(8) {
+            return None;
+        }
+        let frame = VirtAddr::try_new(self.rbp).ok()?;
+        if !(self.readable)(frame) || !(self.readable)(frame + 8u64) {
+            return None;
+        }
+
+        // SAFETY: both words of the frame are mapped.
+        let (caller_rbp, ret) = unsafe {
+            let ptr = frame.as_ptr::<u64>();
+            (ptr.read(), ptr.add(1).read())
+        };
+        if ret == 0 {
+            return None;
+        }
+
+        // Stacks grow down, so callers' frames are above.
+        self.rbp = if caller_rbp > self.rbp { caller_rbp } else { 0 };
+        self.depth += 1;
+        Some(VirtAddr::new_truncate(ret))
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "backtrace:")?;
+        for (i, ret) in self.enumerate() {
+            write!(f, "\n  #{:<2} {:#018x}", i, ret.as_u64())?;
+            // The return address follows the call, which may be the last
+            // instruction of the caller.
+            if let Some(symbol) = symbols::lookup(ret.as_u64() - 1) {
+                write!(f, " {}+{:#x}", symbol.name, symbol.offset + 1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if addr is mapped in the kernel address space. The page
+/// tables may be locked by the code that faulted, in which case nothing is
+/// considered mapped.
+fn kernel_mapped(addr: VirtAddr) -> bool {
+    KERNEL_SPACE
+        .try_lock()
+        .is_some_and(|mut space| space.translate(addr).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    #[test]
+    fn walks_frame_chain() {
+        // Three frames, the outermost one ending the chain with a null rbp.
+        let mut stack = [0u64; 6];
+        let base = stack.as_ptr() as u64;
+        stack.copy_from_slice(&[base + 16, 0x1111, base + 32, 0x2222, 0, 0x3333]);
+
+        let backtrace = Backtrace::with_check(base, |_| true);
+        let rets: alloc::vec::Vec<u64> = backtrace.map(VirtAddr::as_u64).collect();
+        assert_eq!(rets, [0x1111, 0x2222, 0x3333]);
+        assert_eq!(
+            format!("{}", backtrace),
+            "backtrace:\n  #0  0x0000000000001111\n  #1  0x0000000000002222\n  #2  0x0000000000003333"
+        );
+
+        // Unreadable or looping frames stop the walk.
+        assert_eq!(Backtrace::with_check(base, |_| false).count(), 0);
+        stack[2] = base;
+        assert_eq!(
+            Backtrace::with_check(stack.as_ptr() as u64, |_| true).count(),
+            2
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Exception fixup table.
+//!
+//! Instructions that may fault, e.g. rdmsr on an unsupported MSR, are listed
+//! in the `kernel_extable` section with the address to resume at if they do.
+//! The exception handler then records the fault and jumps to the fixup
+//! address, instead of panicking:
+//!
+//! ```ignore
+//! let value = catch(|| {
+//!     let (low, high): (u32, u32);
+//!     asm!(
+//!         "2: rdmsr",
+//!         "3:",
+//!         extable!("2b", "3b"),
+//!         in("ecx") msr, out("eax") low, out("edx") high,
+//!     );
+//!     ...
+//! });
+//! ```
+//!
+//! The fixup address must leave the outputs of the asm block initialized.
+
+use core::fmt;
+
+use spin::Mutex;
+
+use super::idt::{Exception, ExceptionFrame};
+
+/// Entry of the exception table. Addresses are stored relative to their
+/// field, so that the table needs no relocation.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ExtableEntry {
+    insn: i32,
+    fixup: i32,
+}
+
+impl ExtableEntry {
+    fn field_addr(field: &i32) -> u64 {
+        (field as *const i32 as u64).wrapping_add_signed((*field).into())
+    }
+
+    /// Returns the address of the instruction that may fault.
+    pub fn insn(&self) -> u64 {
+        Self::field_addr(&self.insn)
+    }
+
+    /// Returns the address to resume at if the instruction faults.
+    pub fn fixup(&self) -> u64 {
+        Self::field_addr(&self.fixup)
+    }
+}
+
+/// Adds an entry to the exception table, in an asm! template. insn and fixup
+/// are labels, e.g. "2b".
+///
+/// The section is retained (R flag) like the `#[used]` statics of the
+/// `kernel_tests` section, since only the linker symbols refer to it.
+#[macro_export]
+macro_rules! extable {
+    ($insn:literal, $fixup:literal) => {
+        concat!(
+            ".pushsection kernel_extable, \"aR\"\n",
+            ".balign 4\n",
+            ".long ",
+            $insn,
+            " - .\n",
+            ".long ",
+            $fixup,
+            " - .\n",
+            ".popsection"
+        )
+    };
+}
+
+/// Exception raised by an instruction of the exception table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub exception: Exception,
+    pub error_code: u64,
+    /// Address of the faulting instruction.
+    pub rip: u64,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at {:#x}, error code: {:#x}",
+            self.exception.mnemonic(),
+            self.rip,
+            self.error_code
+        )
+    }
+}
+
+/// Last fault fixed up, not yet taken.
+static FAULT: Mutex<Option<Fault>> = Mutex::new(None);
+
+/// Returns the entries of the `kernel_extable` section.
+#[cfg(not(test))]
+fn entries() -> &'static [ExtableEntry] {
+    extern "C" {
+        static __start_kernel_extable: u8;
+        static __stop_kernel_extable: u8;
+    }
+
+    // SAFETY: the linker defines both symbols around the kernel_extable
+    // section, which only holds ExtableEntry entries.
+    unsafe {
+        let start = core::ptr::addr_of!(__start_kernel_extable).cast::<ExtableEntry>();
+        let stop = core::ptr::addr_of!(__stop_kernel_extable).cast::<ExtableEntry>();
+        core::slice::from_raw_parts(start, stop.offset_from(start) as usize)
+    }
+}
+
+#[cfg(test)]
+fn entries() -> &'static [ExtableEntry] {
+    &[]
+}
+
+fn search(entries: &[ExtableEntry], rip: u64) -> Option<u64> {
+    entries
+        .iter()
+        .find(|entry| entry.insn() == rip)
+        .map(ExtableEntry::fixup)
+}
+
+/// Resumes at the fixup address if the faulting instruction is in the
+/// exception table, recording the fault. Returns false otherwise.
+pub fn fixup(frame: &mut ExceptionFrame) -> bool {
+    let Some(exception) = frame.exception() else {
+        return false;
+    };
+    let Some(fixup) = search(entries(), frame.rip) else {
+        return false;
+    };
+
+    *FAULT.lock() = Some(Fault {
+        exception,
+        error_code: frame.error_code,
+        rip: frame.rip,
+    });
+    frame.rip = fixup;
+    true
+}
+
+/// Runs f, which executes instructions of the exception table, and returns
+/// the fault they raised, if any.
+pub fn catch<R>(f: impl FnOnce() -> R) -> Result<R, Fault> {
+    FAULT.lock().take();
+    let ret = f();
+    match FAULT.lock().take() {
+        Some(fault) => Err(fault),
+        None => Ok(ret),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_entries() {
+        let mut table = [ExtableEntry { insn: 0, fixup: 0 }; 2];
+        let base = table.as_ptr() as u64;
+        // Entries pointing to 0x100 and 0x200 bytes past the table.
+        table[1] = ExtableEntry {
+            insn: 0x100 - 8,
+            fixup: 0x200 - 12,
+        };
+        assert_eq!(table[1].insn(), base + 0x100);
+        assert_eq!(table[1].fixup(), base + 0x200);
+        assert_eq!(table[0].fixup(), base + 4);
+
+        assert_eq!(search(&table, base + 0x100), Some(base + 0x200));
+        assert_eq!(search(&table, base + 0x200), None);
+    }
+
+    #[test]
+    fn catch_takes_the_fault() {
+        let mut frame = ExceptionFrame {
+            vector: Exception::GeneralProtection as u64,
+            ..Default::default()
+        };
+        // Nothing is in the host exception table.
+        assert!(!fixup(&mut frame));
+
+        let fault = Fault {
+            exception: Exception::GeneralProtection,
+            error_code: 0,
+            rip: 0x1000,
+        };
+        assert_eq!(catch(|| *FAULT.lock() = Some(fault)), Err(fault));
+        assert_eq!(catch(|| 42), Ok(42));
+        assert_eq!(fault.to_string(), "#GP at 0x1000, error code: 0x0");
+    }
+}
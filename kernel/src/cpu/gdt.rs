@@ -0,0 +1,78 @@
+//! GDT and TSS setup.
+//!
+//! The TSS provides dedicated stacks (IST) for the exceptions that may be
+//! raised with a broken stack: #DF, #NMI and #MC. A kernel stack overflow
+//! then ends in the double fault handler, instead of a triple fault.
+
+use spin::Once;
+use x86_64::{
+    instructions::{
+        segmentation::{Segment, CS, DS, ES, SS},
+        tables::load_tss,
+    },
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        tss::TaskStateSegment,
+    },
+};
+
+use crate::mm::stack::KernelStack;
+
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+const IST_STACK_SIZE: usize = 16 * 1024;
+
+#[derive(Debug)]
+struct Gdt {
+    gdt: GlobalDescriptorTable,
+    code: SegmentSelector,
+    data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+static TSS: Once<TaskStateSegment> = Once::new();
+static GDT: Once<Gdt> = Once::new();
+
+/// Loads the kernel GDT and the TSS, allocating the IST stacks.
+///
+/// The IST stacks are guarded kernel stacks, so the kernel heap and address
+/// space must be initialized.
+pub fn init_gdt() {
+    let tss = TSS.call_once(|| {
+        let mut tss = TaskStateSegment::new();
+        for index in [
+            DOUBLE_FAULT_IST_INDEX,
+            NMI_IST_INDEX,
+            MACHINE_CHECK_IST_INDEX,
+        ] {
+            let stack = KernelStack::new(IST_STACK_SIZE).expect("failed to allocate an IST stack");
+            tss.interrupt_stack_table[usize::from(index)] = stack.leak();
+        }
+        tss
+    });
+
+    let gdt = GDT.call_once(|| {
+        let mut gdt = GlobalDescriptorTable::new();
+        let code = gdt.append(Descriptor::kernel_code_segment());
+        let data = gdt.append(Descriptor::kernel_data_segment());
+        let tss = gdt.append(Descriptor::tss_segment(tss));
+        Gdt {
+            gdt,
+            code,
+            data,
+            tss,
+        }
+    });
+
+    gdt.gdt.load();
+    // SAFETY: the selectors point to the descriptors of the loaded GDT.
+    unsafe {
+        CS::set_reg(gdt.code);
+        SS::set_reg(gdt.data);
+        DS::set_reg(gdt.data);
+        ES::set_reg(gdt.data);
+        load_tss(gdt.tss);
+    }
+}
@@ -1,40 +1,541 @@
-use spin::Lazy;
-use x86_64::{registers::control::Cr2, structures::idt::*};
+//! Exception handling.
+//!
+//! Every architectural exception enters through an assembly stub that saves
+//! the general purpose registers into an [`ExceptionFrame`], and calls
+//! [`exception_dispatch`]. Unexpected exceptions dump the frame, the control
+//! registers and a backtrace, and panic.
+//!
+//! Instructions listed in the [`extable`] resume at their fixup address.
+//! Tests can also expect an exception with [`expect_exception`]:
+//!
+//! ```ignore
+//! let ud = expect_exception(Exception::InvalidOpcode, |frame| frame.rip += 2);
+//! unsafe { asm!("ud2") };
+//! assert_eq!(ud.hits(), 1);
+//! ```
 
-static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
-    let mut idt = InterruptDescriptorTable::new();
-    idt.page_fault.set_handler_fn(pf_handler);
-    idt.invalid_opcode.set_handler_fn(ud_handler);
-    idt.general_protection_fault.set_handler_fn(gp_handler);
-    idt
-});
+use core::arch::global_asm;
+use core::fmt;
+
+use spin::{Lazy, Mutex};
+use x86_64::{
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::idt::InterruptDescriptorTable,
+    VirtAddr,
+};
+
+use super::backtrace::Backtrace;
+use super::extable;
+use super::gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX};
+use super::symbols;
+use crate::mm::stack::is_stack_guard;
+
+/// Architectural exceptions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exception {
+    DivideError = 0,
+    Debug = 1,
+    NonMaskableInterrupt = 2,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRange = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    DoubleFault = 8,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    StackSegment = 12,
+    GeneralProtection = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    Virtualization = 20,
+    ControlProtection = 21,
+}
+
+impl Exception {
+    pub const ALL: [Exception; 20] = [
+        Self::DivideError,
+        Self::Debug,
+        Self::NonMaskableInterrupt,
+        Self::Breakpoint,
+        Self::Overflow,
+        Self::BoundRange,
+        Self::InvalidOpcode,
+        Self::DeviceNotAvailable,
+        Self::DoubleFault,
+        Self::InvalidTss,
+        Self::SegmentNotPresent,
+        Self::StackSegment,
+        Self::GeneralProtection,
+        Self::PageFault,
+        Self::X87FloatingPoint,
+        Self::AlignmentCheck,
+        Self::MachineCheck,
+        Self::SimdFloatingPoint,
+        Self::Virtualization,
+        Self::ControlProtection,
+    ];
+
+    pub fn from_vector(vector: u64) -> Option<Self> {
+        Self::ALL.into_iter().find(|&e| e as u64 == vector)
+    }
+
+    /// Returns the exception mnemonic, e.g. #GP.
+    pub const fn mnemonic(self) -> &'static str {
+        match self {
+            Self::DivideError => "#DE",
+            Self::Debug => "#DB",
+            Self::NonMaskableInterrupt => "NMI",
+            Self::Breakpoint => "#BP",
+            Self::Overflow => "#OF",
+            Self::BoundRange => "#BR",
+            Self::InvalidOpcode => "#UD",
+            Self::DeviceNotAvailable => "#NM",
+            Self::DoubleFault => "#DF",
+            Self::InvalidTss => "#TS",
+            Self::SegmentNotPresent => "#NP",
+            Self::StackSegment => "#SS",
+            Self::GeneralProtection => "#GP",
+            Self::PageFault => "#PF",
+            Self::X87FloatingPoint => "#MF",
+            Self::AlignmentCheck => "#AC",
+            Self::MachineCheck => "#MC",
+            Self::SimdFloatingPoint => "#XM",
+            Self::Virtualization => "#VE",
+            Self::ControlProtection => "#CP",
+        }
+    }
+}
+
+/// State saved on exception entry.
+///
+/// The layout is shared with `exception_common`: the general purpose
+/// registers pushed by the stub, the vector and error code (0 if the exception
+/// has none), then the frame pushed by the CPU.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
 
-extern "x86-interrupt" fn pf_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    panic!(
-        "Unhandled #PF happend - RIP: {:#018x}, errror code: {:#018x}, address: {:#018x}",
-        stack_frame.instruction_pointer,
-        error_code,
-        Cr2::read().unwrap()
-    );
+impl ExceptionFrame {
+    pub fn exception(&self) -> Option<Exception> {
+        Exception::from_vector(self.vector)
+    }
 }
 
-extern "x86-interrupt" fn ud_handler(stack_frame: InterruptStackFrame) {
-    panic!(
-        "Unhandled #UD happend - RIP: {:#018x}",
-        stack_frame.instruction_pointer,
-    );
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.exception().map_or("unknown", Exception::mnemonic);
+        writeln!(
+            f,
+            "{} (vector {}), error code: {:#x}",
+            name, self.vector, self.error_code
+        )?;
+        writeln!(
+            f,
+            "RIP: {:#06x}:{:#018x} RSP: {:#06x}:{:#018x} RFLAGS: {:#010x}",
+            self.cs, self.rip, self.ss, self.rsp, self.rflags
+        )?;
+
+        let regs = [
+            ("RAX", self.rax),
+            ("RBX", self.rbx),
+            ("RCX", self.rcx),
+            ("RDX", self.rdx),
+            ("RSI", self.rsi),
+            ("RDI", self.rdi),
+            ("RBP", self.rbp),
+            ("R8", self.r8),
+            ("R9", self.r9),
+            ("R10", self.r10),
+            ("R11", self.r11),
+            ("R12", self.r12),
+            ("R13", self.r13),
+            ("R14", self.r14),
+            ("R15", self.r15),
+        ];
+        for (i, (name, value)) in regs.iter().enumerate() {
+            let sep = match i % 3 {
+                2 if i + 1 < regs.len() => "\n",
+                _ if i + 1 == regs.len() => "",
+                _ => " ",
+            };
+            write!(f, "{:<3}: {:#018x}{}", name, value, sep)?;
+        }
+        Ok(())
+    }
+}
+
+/// Control registers, read when dumping an exception.
+#[derive(Debug, Clone, Copy)]
+pub struct ControlRegisters {
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl ControlRegisters {
+    pub fn read() -> Self {
+        let (frame, flags) = Cr3::read_raw();
+        Self {
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read_raw(),
+            cr3: frame.start_address().as_u64() | u64::from(flags),
+            cr4: Cr4::read_raw(),
+        }
+    }
+}
+
+impl fmt::Display for ControlRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "CR0: {:#018x} CR2: {:#018x} CR3: {:#018x} CR4: {:#018x}",
+            self.cr0, self.cr2, self.cr3, self.cr4
+        )
+    }
+}
+
+/// Handler of an expected exception. It may change the frame, e.g. to move
+/// RIP past the faulting instruction, before returning to it.
+pub type ExceptionHandler = fn(&mut ExceptionFrame);
+
+#[derive(Debug)]
+struct Expected {
+    exception: Exception,
+    handler: ExceptionHandler,
+    hits: usize,
+}
+
+static EXPECTED: Mutex<Option<Expected>> = Mutex::new(None);
+
+/// An exception expectation, removed when dropped.
+#[derive(Debug)]
+#[must_use = "the expectation is removed when dropped"]
+pub struct ExpectedException(());
+
+impl ExpectedException {
+    /// Returns the number of times the exception was raised.
+    pub fn hits(&self) -> usize {
+        EXPECTED.lock().as_ref().map_or(0, |expected| expected.hits)
+    }
 }
 
-extern "x86-interrupt" fn gp_handler(stack_frame: InterruptStackFrame, error_code: u64) {
-    panic!(
-        "Unhandled #GP happend - RIP: {:#018x}, error code: {:#018x}",
-        stack_frame.instruction_pointer, error_code
-    );
+impl Drop for ExpectedException {
+    fn drop(&mut self) {
+        *EXPECTED.lock() = None;
+    }
 }
 
+/// Handles exception with handler, instead of panicking, until the returned
+/// guard is dropped.
+///
+/// # Panics
+///
+/// Panics if an exception is already expected.
+pub fn expect_exception(exception: Exception, handler: ExceptionHandler) -> ExpectedException {
+    let mut expected = EXPECTED.lock();
+    if let Some(current) = expected.as_ref() {
+        panic!(
+            "{} is already expected, can't expect {}",
+            current.exception.mnemonic(),
+            exception.mnemonic()
+        );
+    }
+    *expected = Some(Expected {
+        exception,
+        handler,
+        hits: 0,
+    });
+    ExpectedException(())
+}
+
+/// Runs the expected exception handler, returning false if the exception
+/// isn't expected.
+fn handle_expected(frame: &mut ExceptionFrame) -> bool {
+    // The exception may be raised while the expectation is being changed.
+    let Some(mut expected) = EXPECTED.try_lock() else {
+        return false;
+    };
+    match expected.as_mut() {
+        Some(expected) if Some(expected.exception) == frame.exception() => {
+            expected.hits += 1;
+            (expected.handler)(frame);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Logs the frame, the symbol of RIP, the control registers and a backtrace.
+pub fn dump(frame: &ExceptionFrame) {
+    log::error!("{}", frame);
+    if let Some(symbol) = symbols::lookup(frame.rip) {
+        log::error!("RIP is at {}", symbol);
+    }
+    log::error!("{}", ControlRegisters::read());
+    log::error!("{}", Backtrace::from_rbp(frame.rbp));
+}
+
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    if handle_expected(frame) || extable::fixup(frame) {
+        return;
+    }
+
+    dump(frame);
+    let name = frame.exception().map_or("exception", Exception::mnemonic);
+    match frame.exception() {
+        // A #PF on a guard page can't be delivered on the overflowed stack,
+        // and ends in a #DF, CR2 still holding the guard page address.
+        Some(Exception::PageFault | Exception::DoubleFault) => {
+            let addr = Cr2::read_raw();
+            if is_stack_guard(VirtAddr::new_truncate(addr)) {
+                panic!(
+                    "Kernel stack overflow ({}) - RIP: {:#018x}, RSP: {:#018x}, address: {:#018x}",
+                    name, frame.rip, frame.rsp, addr
+                );
+            }
+            panic!(
+                "Unhandled {} happend - RIP: {:#018x}, error code: {:#018x}, address: {:#018x}",
+                name, frame.rip, frame.error_code, addr
+            );
+        }
+        _ => panic!(
+            "Unhandled {} happend - RIP: {:#018x}, error code: {:#018x}",
+            name, frame.rip, frame.error_code
+        ),
+    }
+}
+
+// Exception entry stubs, 16 bytes apart so that the stub of a vector is at
+// exception_stubs + 16 * vector. Stubs of exceptions without an error code
+// push a null one, so that every ExceptionFrame has the same layout.
+//
+// The CPU aligns RSP on 16 bytes before pushing its frame, so RSP is still
+// aligned when calling exception_dispatch.
+global_asm!(
+    ".macro exception_stub vector, error_code",
+    ".balign 16",
+    ".if \\error_code == 0",
+    "push 0",
+    ".endif",
+    "push \\vector",
+    "jmp exception_common",
+    ".endm",
+    "",
+    ".balign 16",
+    ".global exception_stubs",
+    "exception_stubs:",
+    "exception_stub 0, 0",
+    "exception_stub 1, 0",
+    "exception_stub 2, 0",
+    "exception_stub 3, 0",
+    "exception_stub 4, 0",
+    "exception_stub 5, 0",
+    "exception_stub 6, 0",
+    "exception_stub 7, 0",
+    "exception_stub 8, 1",
+    "exception_stub 9, 0",
+    "exception_stub 10, 1",
+    "exception_stub 11, 1",
+    "exception_stub 12, 1",
+    "exception_stub 13, 1",
+    "exception_stub 14, 1",
+    "exception_stub 15, 0",
+    "exception_stub 16, 0",
+    "exception_stub 17, 1",
+    "exception_stub 18, 0",
+    "exception_stub 19, 0",
+    "exception_stub 20, 0",
+    "exception_stub 21, 1",
+    "",
+    "exception_common:",
+    "push r15",
+    "push r14",
+    "push r13",
+    "push r12",
+    "push r11",
+    "push r10",
+    "push r9",
+    "push r8",
+    "push rbp",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push rcx",
+    "push rbx",
+    "push rax",
+    "mov rdi, rsp",
+    "cld",
+    "call {dispatch}",
+    "pop rax",
+    "pop rbx",
+    "pop rcx",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop rbp",
+    "pop r8",
+    "pop r9",
+    "pop r10",
+    "pop r11",
+    "pop r12",
+    "pop r13",
+    "pop r14",
+    "pop r15",
+    "add rsp, 16",
+    "iretq",
+    dispatch = sym exception_dispatch,
+);
+
+extern "C" {
+    fn exception_stubs();
+}
+
+/// Returns the address of the entry stub of exception.
+fn stub(exception: Exception) -> VirtAddr {
+    VirtAddr::from_ptr(exception_stubs as *const ()) + 16 * exception as u64
+}
+
+static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
+    // SAFETY: the stubs handle the error code of their exception, and the IST
+    // indexes are set up by init_gdt. Until then, exceptions using them
+    // triple fault, as without handlers.
+    unsafe {
+        idt.divide_error
+            .set_handler_addr(stub(Exception::DivideError));
+        idt.debug.set_handler_addr(stub(Exception::Debug));
+        idt.non_maskable_interrupt
+            .set_handler_addr(stub(Exception::NonMaskableInterrupt))
+            .set_stack_index(NMI_IST_INDEX);
+        idt.breakpoint.set_handler_addr(stub(Exception::Breakpoint));
+        idt.overflow.set_handler_addr(stub(Exception::Overflow));
+        idt.bound_range_exceeded
+            .set_handler_addr(stub(Exception::BoundRange));
+        idt.invalid_opcode
+            .set_handler_addr(stub(Exception::InvalidOpcode));
+        idt.device_not_available
+            .set_handler_addr(stub(Exception::DeviceNotAvailable));
+        idt.double_fault
+            .set_handler_addr(stub(Exception::DoubleFault))
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss
+            .set_handler_addr(stub(Exception::InvalidTss));
+        idt.segment_not_present
+            .set_handler_addr(stub(Exception::SegmentNotPresent));
+        idt.stack_segment_fault
+            .set_handler_addr(stub(Exception::StackSegment));
+        idt.general_protection_fault
+            .set_handler_addr(stub(Exception::GeneralProtection));
+        idt.page_fault.set_handler_addr(stub(Exception::PageFault));
+        idt.x87_floating_point
+            .set_handler_addr(stub(Exception::X87FloatingPoint));
+        idt.alignment_check
+            .set_handler_addr(stub(Exception::AlignmentCheck));
+        idt.machine_check
+            .set_handler_addr(stub(Exception::MachineCheck))
+            .set_stack_index(MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point
+            .set_handler_addr(stub(Exception::SimdFloatingPoint));
+        idt.virtualization
+            .set_handler_addr(stub(Exception::Virtualization));
+        idt.cp_protection_exception
+            .set_handler_addr(stub(Exception::ControlProtection));
+    }
+    idt
+});
+
 pub fn init_early_idt() {
     IDT.load();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    #[test]
+    fn exception_vectors() {
+        for exception in Exception::ALL {
+            assert_eq!(Exception::from_vector(exception as u64), Some(exception));
+        }
+        assert_eq!(Exception::from_vector(9), None);
+        assert_eq!(Exception::from_vector(32), None);
+        assert_eq!(Exception::GeneralProtection.mnemonic(), "#GP");
+    }
+
+    #[test]
+    fn frame_dump() {
+        let frame = ExceptionFrame {
+            rax: 1,
+            r15: 0xf,
+            vector: 13,
+            error_code: 0x18,
+            rip: 0xffff_8000_0000_1234,
+            cs: 0x8,
+            rflags: 0x202,
+            ss: 0x10,
+            ..Default::default()
+        };
+        let dump = format!("{}", frame);
+        let lines: alloc::vec::Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(lines[0], "#GP (vector 13), error code: 0x18");
+        assert_eq!(
+            lines[1],
+            "RIP: 0x0008:0xffff800000001234 RSP: 0x0010:0x0000000000000000 RFLAGS: 0x00000202"
+        );
+        assert!(lines[2].starts_with("RAX: 0x0000000000000001 RBX: "));
+        assert!(lines[6].ends_with("R15: 0x000000000000000f"));
+    }
+
+    #[test]
+    fn expected_exception() {
+        let frame = &mut ExceptionFrame {
+            vector: Exception::InvalidOpcode as u64,
+            ..Default::default()
+        };
+        assert!(!handle_expected(frame));
+
+        let ud = expect_exception(Exception::InvalidOpcode, |frame| frame.rip += 2);
+        assert!(handle_expected(frame));
+        assert_eq!(frame.rip, 2);
+        assert_eq!(ud.hits(), 1);
+
+        frame.vector = Exception::GeneralProtection as u64;
+        assert!(!handle_expected(frame));
+        assert_eq!(ud.hits(), 1);
+
+        drop(ud);
+        frame.vector = Exception::InvalidOpcode as u64;
+        assert!(!handle_expected(frame));
+    }
+}
@@ -1,5 +1,11 @@
 use core::arch::asm;
 
+use x86_64::{
+    instructions::tables::sgdt,
+    registers::model_specific::{FsBase, GsBase},
+};
+
+#[derive(Debug, Clone, Copy)]
 pub enum Segment {
     CS(u64),
     DS(u64),
@@ -7,16 +13,115 @@ pub enum Segment {
     SS(u64),
     FS(u64),
     GS(u64),
+    TR(u64),
+    LDTR(u64),
 }
 
 impl Segment {
+    /// Returns the current selector of the segment register.
     #[inline]
     pub fn read(self) -> u64 {
         let mut ret: u64;
+        // SAFETY: reading a segment register has no side effect.
+        unsafe {
+            match self {
+                Self::CS(_) => asm!("mov {ret}, cs", ret = out(reg) ret),
+                Self::DS(_) => asm!("mov {ret}, ds", ret = out(reg) ret),
+                Self::ES(_) => asm!("mov {ret}, es", ret = out(reg) ret),
+                Self::SS(_) => asm!("mov {ret}, ss", ret = out(reg) ret),
+                Self::FS(_) => asm!("mov {ret}, fs", ret = out(reg) ret),
+                Self::GS(_) => asm!("mov {ret}, gs", ret = out(reg) ret),
+                Self::TR(_) => asm!("str {ret}", ret = out(reg) ret),
+                Self::LDTR(_) => asm!("sldt {ret}", ret = out(reg) ret),
+            }
+        }
+        ret & 0xffff
+    }
+
+    /// Returns the base address of the segment.
+    ///
+    /// CS, DS, ES and SS are flat in 64-bit mode, FS and GS bases come from
+    /// their MSRs, and the TR and LDTR bases are read from the current GDT.
+    pub fn base(self) -> u64 {
         match self {
-            Self::CS(_) => unsafe { asm!("mov {ret}, cs", ret = out(reg) ret) },
-            _ => unimplemented!(),
+            Self::CS(_) | Self::DS(_) | Self::ES(_) | Self::SS(_) => 0,
+            Self::FS(_) => FsBase::read().as_u64(),
+            Self::GS(_) => GsBase::read().as_u64(),
+            Self::TR(_) | Self::LDTR(_) => system_segment_base(self.read()),
         }
-        ret
     }
+
+    /// Returns the segment limit, or None if the selector is invalid.
+    pub fn limit(self) -> Option<u32> {
+        lsl(self.read())
+    }
+
+    /// Returns the access rights of the segment, as returned by `lar`, or
+    /// None if the selector is invalid.
+    pub fn access_rights(self) -> Option<u32> {
+        lar(self.read())
+    }
+}
+
+/// Reads the base of a 16-byte system segment descriptor (TSS, LDT) from the
+/// current GDT.
+fn system_segment_base(selector: u64) -> u64 {
+    let index = selector & !0x7;
+    if index == 0 {
+        return 0;
+    }
+
+    let gdt = sgdt();
+    if index + 15 > u64::from(gdt.limit) {
+        return 0;
+    }
+
+    let desc = (gdt.base + index).as_ptr::<u32>();
+    // SAFETY: the descriptor lies within the limits of the loaded GDT.
+    let (low, high, upper) = unsafe {
+        (
+            desc.read_unaligned(),
+            desc.add(1).read_unaligned(),
+            desc.add(2).read_unaligned(),
+        )
+    };
+
+    u64::from(low >> 16)
+        | (u64::from(high & 0xff) << 16)
+        | (u64::from(high >> 24) << 24)
+        | (u64::from(upper) << 32)
+}
+
+/// Loads the access rights of the descriptor referenced by selector.
+#[inline]
+pub fn lar(selector: u64) -> Option<u32> {
+    let mut ret: u64;
+    let mut ok: u8;
+    // SAFETY: lar doesn't fault on invalid selectors, it clears ZF instead.
+    unsafe {
+        asm!(
+            "lar {ret}, {sel:e}; setz {ok}",
+            ret = out(reg) ret, sel = in(reg) selector, ok = out(reg_byte) ok,
+            options(nomem, nostack)
+        );
+    }
+
+    (ok == 1).then_some(ret as u32)
+}
+
+/// Loads the limit of the descriptor referenced by selector.
+#[inline]
+pub fn lsl(selector: u64) -> Option<u32> {
+    let mut ret: u64;
+    let mut ok: u8;
+    // SAFETY: lsl doesn't fault on invalid selectors, it clears ZF instead.
+    unsafe {
+        asm!(
+            "lsl {ret}, {sel:e}; setz {ok}",
+            ret = out(reg) ret, sel = in(reg) selector, ok = out(reg_byte) ok,
+            options(nomem, nostack)
+        );
+    }
+
+    (ok == 1).then_some(ret as u32)
 }
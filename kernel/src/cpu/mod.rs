@@ -1,3 +1,21 @@
+pub mod backtrace;
+pub mod extable;
+pub mod gdt;
 pub mod idt;
 pub mod insn;
 pub mod msr;
+pub mod percpu;
+pub mod symbols;
+pub mod tsc;
+
+/// Returns the initial APIC ID of the current CPU, cached in its per-CPU data.
+#[cfg(not(test))]
+pub fn cpu_id() -> u32 {
+    percpu::current().map_or_else(percpu::read_apic_id, |cpu| cpu.apic_id)
+}
+
+/// Host tests can't read the GS base MSR.
+#[cfg(test)]
+pub fn cpu_id() -> u32 {
+    0
+}
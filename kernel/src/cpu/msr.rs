@@ -1,6 +1,67 @@
+use core::arch::asm;
+
+use super::extable::{catch, Fault};
+use crate::extable;
+
 // Enable VMXE
 pub const IA32_VMX_BASIC: u32 = 0x480;
+pub const IA32_VMX_PINBASED_CTLS: u32 = 0x481;
+pub const IA32_VMX_PROCBASED_CTLS: u32 = 0x482;
+pub const IA32_VMX_EXIT_CTLS: u32 = 0x483;
+pub const IA32_VMX_ENTRY_CTLS: u32 = 0x484;
+pub const IA32_VMX_MISC: u32 = 0x485;
 pub const IA32_VMX_CR0_FIXED0: u32 = 0x486;
 pub const IA32_VMX_CR0_FIXED1: u32 = 0x487;
 pub const IA32_VMX_CR4_FIXED0: u32 = 0x488;
 pub const IA32_VMX_CR4_FIXED1: u32 = 0x489;
+pub const IA32_VMX_VMCS_ENUM: u32 = 0x48a;
+pub const IA32_VMX_PROCBASED_CTLS2: u32 = 0x48b;
+pub const IA32_VMX_EPT_VPID_CAP: u32 = 0x48c;
+pub const IA32_VMX_TRUE_PINBASED_CTLS: u32 = 0x48d;
+pub const IA32_VMX_TRUE_PROCBASED_CTLS: u32 = 0x48e;
+pub const IA32_VMX_TRUE_EXIT_CTLS: u32 = 0x48f;
+pub const IA32_VMX_TRUE_ENTRY_CTLS: u32 = 0x490;
+pub const IA32_VMX_VMFUNC: u32 = 0x491;
+
+// Local APIC base address and enable bits
+pub const IA32_APIC_BASE: u32 = 0x1b;
+
+// Page attribute table
+pub const IA32_PAT: u32 = 0x277;
+
+/// Reads msr, returning the #GP raised if the MSR isn't supported.
+pub fn try_rdmsr(msr: u32) -> Result<u64, Fault> {
+    catch(|| {
+        let (mut low, mut high) = (0u32, 0u32);
+        // SAFETY: a #GP resumes after rdmsr. The asm has no memory options,
+        // so that catch reads the recorded fault after it.
+        unsafe {
+            asm!(
+                "2: rdmsr",
+                "3:",
+                extable!("2b", "3b"),
+                in("ecx") msr, inout("eax") low, inout("edx") high,
+                options(nostack)
+            );
+        }
+        (u64::from(high) << 32) | u64::from(low)
+    })
+}
+
+/// Writes value to msr, returning the #GP raised if the MSR isn't supported
+/// or value is invalid.
+///
+/// # Safety
+///
+/// Caller should ensure that writing the MSR doesn't break memory safety.
+pub unsafe fn try_wrmsr(msr: u32, value: u64) -> Result<(), Fault> {
+    catch(|| {
+        asm!(
+            "2: wrmsr",
+            "3:",
+            extable!("2b", "3b"),
+            in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32,
+            options(nostack)
+        );
+    })
+}
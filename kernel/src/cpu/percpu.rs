@@ -0,0 +1,40 @@
+//! Per-CPU data, pointed to by the GS base of each CPU.
+//!
+//! VM exits restore the host GS base, so the data stays reachable while
+//! guests run with their own.
+
+use core::arch::x86_64::__cpuid;
+
+use spin::Once;
+use x86_64::registers::model_specific::GsBase;
+use x86_64::VirtAddr;
+
+/// Data of a CPU, read without locking by the CPU itself.
+#[derive(Debug)]
+pub struct PerCpu {
+    /// Initial APIC ID of the CPU.
+    pub apic_id: u32,
+}
+
+static BSP: Once<PerCpu> = Once::new();
+
+/// Returns the initial APIC ID of the current CPU, from CPUID.
+pub fn read_apic_id() -> u32 {
+    __cpuid(1).ebx >> 24
+}
+
+/// Sets the per-CPU data of the bootstrap processor. It should be done first,
+/// so that the logger doesn't run CPUID for every line.
+pub fn init_percpu() {
+    let data = BSP.call_once(|| PerCpu {
+        apic_id: read_apic_id(),
+    });
+    GsBase::write(VirtAddr::from_ptr(data));
+}
+
+/// Returns the data of the current CPU, unless init_percpu wasn't called yet.
+pub fn current() -> Option<&'static PerCpu> {
+    let base = GsBase::read();
+    // SAFETY: the GS base is only set by init_percpu, to data never freed.
+    (!base.is_null()).then(|| unsafe { &*base.as_ptr() })
+}
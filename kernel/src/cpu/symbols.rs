@@ -0,0 +1,200 @@
+//! Kernel symbol table, to symbolize backtraces.
+//!
+//! `run/build.rs` extracts the function symbols of the kernel ELF, and loads
+//! them as the ramdisk. The table is little-endian:
+//!
+//! ```text
+//! magic: b"KSYM"
+//! count: u32
+//! count entries, sorted by address:
+//!     address: u64  (link address)
+//!     size: u32
+//!     name: u32     (offset of the name in the names)
+//! names: NUL-terminated demangled names
+//! ```
+
+use core::fmt;
+
+use bootloader_api::BootInfo;
+use spin::Once;
+
+pub const SYMBOLS_MAGIC: [u8; 4] = *b"KSYM";
+
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 16;
+
+static SYMBOLS: Once<SymbolTable<'static>> = Once::new();
+
+/// A symbol table, and the offset the kernel was loaded at.
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolTable<'a> {
+    entries: &'a [u8],
+    names: &'a [u8],
+    load_offset: u64,
+}
+
+/// Symbol containing an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    /// Offset of the address in the symbol.
+    pub offset: u64,
+}
+
+impl fmt::Display for Symbol<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Parses a table, for a kernel loaded load_offset bytes above its link
+    /// addresses.
+    pub fn parse(data: &'a [u8], load_offset: u64) -> Option<Self> {
+        if data.len() < HEADER_SIZE || data[..4] != SYMBOLS_MAGIC {
+            return None;
+        }
+        let count = read_u32(data, 4) as usize;
+        let names_start = count
+            .checked_mul(ENTRY_SIZE)?
+            .checked_add(HEADER_SIZE)
+            .filter(|&end| end <= data.len())?;
+
+        Some(Self {
+            entries: &data[HEADER_SIZE..names_start],
+            names: &data[names_start..],
+            load_offset,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn address(&self, index: usize) -> u64 {
+        read_u64(self.entries, index * ENTRY_SIZE)
+    }
+
+    /// Returns the symbol containing addr, a runtime address.
+    pub fn lookup(&self, addr: u64) -> Option<Symbol<'a>> {
+        let addr = addr.checked_sub(self.load_offset)?;
+
+        // Index of the last symbol starting at or below addr.
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.address(mid) <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let index = low.checked_sub(1)?;
+
+        let entry = index * ENTRY_SIZE;
+        let offset = addr - self.address(index);
+        if offset >= u64::from(read_u32(self.entries, entry + 8)) {
+            return None;
+        }
+
+        let name = self
+            .names
+            .get(read_u32(self.entries, entry + 12) as usize..)?;
+        let len = name.iter().position(|&b| b == 0)?;
+        let name = core::str::from_utf8(&name[..len]).ok()?;
+        Some(Symbol { name, offset })
+    }
+}
+
+/// Loads the symbol table passed as the ramdisk, if any.
+pub fn init_symbols(boot_info: &BootInfo) {
+    let Some(addr) = boot_info.ramdisk_addr.into_option() else {
+        log::info!("No symbol table, backtraces aren't symbolized");
+        return;
+    };
+
+    // SAFETY: the bootloader maps the ramdisk, and never frees it.
+    let data =
+        unsafe { core::slice::from_raw_parts(addr as *const u8, boot_info.ramdisk_len as usize) };
+    match SymbolTable::parse(data, boot_info.kernel_image_offset) {
+        Some(table) => {
+            log::info!("Loaded {} symbols", table.len());
+            SYMBOLS.call_once(|| table);
+        }
+        None => log::error!("Invalid symbol table in the ramdisk"),
+    }
+}
+
+/// Returns the kernel symbol containing addr.
+pub fn lookup(addr: u64) -> Option<Symbol<'static>> {
+    SYMBOLS.get()?.lookup(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Builds a table as run/build.rs does.
+    fn table(symbols: &[(u64, u32, &str)]) -> Vec<u8> {
+        let mut data = Vec::from(SYMBOLS_MAGIC);
+        data.extend((symbols.len() as u32).to_le_bytes());
+        let mut names = Vec::new();
+        for (addr, size, name) in symbols {
+            data.extend(addr.to_le_bytes());
+            data.extend(size.to_le_bytes());
+            data.extend((names.len() as u32).to_le_bytes());
+            names.extend(name.as_bytes());
+            names.push(0);
+        }
+        data.extend(names);
+        data
+    }
+
+    #[test]
+    fn lookup_symbols() {
+        let data = table(&[
+            (0x1000, 0x20, "kernel::kernel_main"),
+            (0x1040, 0x10, "core::panicking::panic"),
+        ]);
+        let symbols = SymbolTable::parse(&data, 0xffff_8000_0000_0000).unwrap();
+        assert_eq!(symbols.len(), 2);
+
+        let symbol = symbols.lookup(0xffff_8000_0000_101c).unwrap();
+        assert_eq!(symbol.name, "kernel::kernel_main");
+        assert_eq!(symbol.to_string(), "kernel::kernel_main+0x1c");
+        assert_eq!(
+            symbols.lookup(0xffff_8000_0000_1040).unwrap().to_string(),
+            "core::panicking::panic+0x0"
+        );
+
+        // Before the first symbol, in a gap, past the last one.
+        assert_eq!(symbols.lookup(0xffff_8000_0000_0fff), None);
+        assert_eq!(symbols.lookup(0xffff_8000_0000_1030), None);
+        assert_eq!(symbols.lookup(0xffff_8000_0000_1050), None);
+        assert_eq!(symbols.lookup(0x1000), None);
+    }
+
+    #[test]
+    fn invalid_tables() {
+        assert!(SymbolTable::parse(b"KSY", 0).is_none());
+        assert!(SymbolTable::parse(b"ELF\0\0\0\0\0", 0).is_none());
+
+        let mut data = table(&[(0x1000, 0x20, "f")]);
+        data[4] = 2;
+        assert!(SymbolTable::parse(&data, 0).is_none());
+        assert!(SymbolTable::parse(&table(&[]), 0).unwrap().is_empty());
+    }
+}
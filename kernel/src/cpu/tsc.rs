@@ -0,0 +1,42 @@
+//! Time stamp counter.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+/// Returns the current value of the TSC.
+#[inline]
+pub fn rdtsc() -> u64 {
+    // SAFETY: rdtsc is available on x86_64, and has no side effect.
+    unsafe { _rdtsc() }
+}
+
+/// Returns the TSC frequency in Hz, if the CPU or the hypervisor reports it.
+pub fn frequency() -> Option<u64> {
+    let max_leaf = __cpuid(0).eax;
+
+    // Crystal clock frequency and TSC/crystal ratio.
+    if max_leaf >= 0x15 {
+        let leaf = __cpuid(0x15);
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            return Some(u64::from(leaf.ecx) * u64::from(leaf.ebx) / u64::from(leaf.eax));
+        }
+    }
+
+    // Timing information leaf of KVM and VMware, in kHz.
+    let hypervisor = __cpuid(1).ecx & (1 << 31) != 0;
+    if hypervisor && __cpuid(0x4000_0000).eax >= 0x4000_0010 {
+        let khz = __cpuid(0x4000_0010).eax;
+        if khz != 0 {
+            return Some(u64::from(khz) * 1000);
+        }
+    }
+
+    // Processor base frequency, in MHz.
+    if max_leaf >= 0x16 {
+        let mhz = __cpuid(0x16).eax & 0xffff;
+        if mhz != 0 {
+            return Some(u64::from(mhz) * 1_000_000);
+        }
+    }
+
+    None
+}
@@ -1,37 +1,44 @@
 use core::arch::asm;
-use core::fmt;
 
-pub struct IOPort(u16);
-
-impl IOPort {
-    pub const fn new(port: u16) -> Self {
-        Self(port)
-    }
-}
-
-impl fmt::Write for IOPort {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        for &b in s.as_bytes() {
-            // SAFETY: The caller should ensure writing to this port won't cause UB.
-            unsafe {
-                self.outb(b);
-            }
-        }
-
-        Ok(())
+/// Writes value to the 8-bit I/O port.
+///
+/// # Safety
+///
+/// Caller should ensure that writing to this port, e.g. a device register,
+/// doesn't break memory safety or the state of the device owner.
+#[inline(always)]
+pub unsafe fn outb(port: u16, value: u8) {
+    unsafe {
+        // https://github.com/rust-osdev/x86_64/blob/master/src/instructions/port.rs
+        asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
     }
 }
 
-impl IOPort {
-    pub unsafe fn outb(&self, value: u8) {
-        unsafe { outb(self.0, value) }
+/// Reads the 8-bit I/O port.
+///
+/// # Safety
+///
+/// Caller should ensure that reading this port, which may have side effects
+/// such as consuming received data, doesn't break the state of the device
+/// owner.
+#[inline(always)]
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    unsafe {
+        asm!("in al, dx", in("dx") port, out("al") value, options(nomem, nostack, preserves_flags));
     }
+    value
 }
 
+/// Writes value to the 32-bit I/O port.
+///
+/// # Safety
+///
+/// Caller should ensure that writing to this port, e.g. a device register,
+/// doesn't break memory safety or the state of the device owner.
 #[inline(always)]
-pub unsafe fn outb(port: u16, value: u8) {
+pub unsafe fn outl(port: u16, value: u32) {
     unsafe {
-        // https://github.com/rust-osdev/x86_64/blob/master/src/instructions/port.rs
-        asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+        asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
     }
 }
@@ -8,34 +8,65 @@ use alloc::boxed::Box;
 use bootloader_api::{
     config, config::Mapping, entry_point, info::Optional, BootInfo, BootloaderConfig,
 };
+use core::arch::asm;
+use kernel::cpu::gdt::init_gdt;
 use kernel::cpu::idt::init_early_idt;
+use kernel::cpu::percpu::init_percpu;
+use kernel::cpu::symbols::init_symbols;
 use kernel::logger::init_logger;
 use kernel::mm::alloc::init_mem;
-use kernel::virt::vmx::vmcs::VMCS;
+use kernel::virt::vmx::exit::{BasicExitReason, ExitAction, ExitHandlers, VmExit};
+use kernel::virt::vmx::vcpu::Vcpu;
 use kernel::virt::vmx::vmxon::VmxOn;
+use kernel::virt::VirtError;
 
-const CONFIG: BootloaderConfig = {
-    let mut config = BootloaderConfig::new_default();
-    config.kernel_stack_size = 100 * 1024; // 100 KiB
-    config.mappings.physical_memory = Some(Mapping::Dynamic);
-    config
-};
-bootloader_api::entry_point!(kernel_main, config = &CONFIG);
+bootloader_api::entry_point!(kernel_main, config = &kernel::BOOTLOADER_CONFIG);
+
+extern "C" fn guest_main() -> ! {
+    loop {
+        unsafe {
+            asm!("vmcall");
+            asm!("hlt");
+        }
+    }
+}
+
+fn handle_vmcall(vcpu: &mut Vcpu, _exit: &VmExit) -> Result<ExitAction, VirtError> {
+    vcpu.skip_instruction()?;
+    Ok(ExitAction::Resume)
+}
+
+fn handle_hlt(_vcpu: &mut Vcpu, _exit: &VmExit) -> Result<ExitAction, VirtError> {
+    Ok(ExitAction::Stop)
+}
 
 #[no_mangle]
 pub fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    init_percpu();
     init_logger().expect("failed to init logger");
     init_early_idt();
+    init_symbols(boot_info);
     init_mem(boot_info).expect("failed to init the kernel heap");
+    init_gdt();
 
     let mut vmxon = Box::new(VmxOn::new());
-    vmxon.setup().expect("failed to load vmxon region");
+    if let Err(e) = vmxon.setup() {
+        panic!("Failed to load the VMXON region: {}", e);
+    }
+
+    let mut vcpu = Vcpu::new(guest_main);
+    if let Err(e) = vcpu.setup() {
+        panic!("Failed to setup the vCPU: {}", e);
+    }
 
-    let mut vmcs = Box::new(VMCS::new());
-    vmcs.setup().expect("failed to load VMCS region");
+    let mut handlers = ExitHandlers::new();
+    handlers.register(BasicExitReason::Vmcall, handle_vmcall);
+    handlers.register(BasicExitReason::Hlt, handle_hlt);
 
-    if let Err(e) = vmcs.vmread(0) {
-        panic!("Failed to vmread. VM-instruction error: {:?}", e);
+    match vcpu.run_with(&handlers) {
+        Ok(VmExit::Hlt) => log::info!("Guest halted"),
+        Ok(exit) => panic!("Unexpected VM exit: {:?}", exit),
+        Err(e) => panic!("Failed to run the guest: {}", e),
     }
 
     log::info!("Entering kernel loop");
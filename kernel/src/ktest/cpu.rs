@@ -0,0 +1,70 @@
+//! CPU tables and exceptions tests.
+
+use core::arch::asm;
+
+use kernel::cpu::backtrace::Backtrace;
+use kernel::cpu::cpu_id;
+use kernel::cpu::gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX};
+use kernel::cpu::idt::{expect_exception, Exception};
+use kernel::cpu::insn::Segment;
+use kernel::cpu::msr::{try_rdmsr, IA32_PAT};
+use kernel::cpu::percpu;
+use kernel::kernel_test;
+use kernel::mm::paging::KERNEL_SPACE;
+use kernel::mm::stack::is_stack_guard;
+use x86_64::structures::tss::TaskStateSegment;
+
+kernel_test! {
+    fn tss_has_guarded_ist_stacks() {
+        assert_ne!(Segment::TR(0).read(), 0, "no TSS loaded");
+
+        // SAFETY: TR points to the kernel TSS, which is never freed.
+        let tss = unsafe { &*(Segment::TR(0).base() as *const TaskStateSegment) };
+        let mut space = KERNEL_SPACE.lock();
+        for index in [
+            DOUBLE_FAULT_IST_INDEX,
+            NMI_IST_INDEX,
+            MACHINE_CHECK_IST_INDEX,
+        ] {
+            let top = tss.interrupt_stack_table[usize::from(index)];
+            assert!(space.translate(top - 1u64).is_some());
+            assert!(is_stack_guard(top));
+        }
+    }
+
+    fn expected_exceptions_resume() {
+        let ud = expect_exception(Exception::InvalidOpcode, |frame| frame.rip += 2);
+        // SAFETY: the #UD is handled by skipping ud2.
+        unsafe { asm!("ud2") };
+        assert_eq!(ud.hits(), 1);
+        drop(ud);
+
+        // #BP is a trap, RIP already points past int3.
+        let bp = expect_exception(Exception::Breakpoint, |frame| frame.rax = 0x42);
+        let rax: u64;
+        // SAFETY: the #BP handler only changes rax.
+        unsafe { asm!("xor eax, eax", "int3", out("rax") rax) };
+        assert_eq!(bp.hits(), 1);
+        assert_eq!(rax, 0x42);
+    }
+
+    fn backtrace_walks_frames() {
+        let depth = Backtrace::current().count();
+        assert!(depth > 1, "backtrace of {} frames", depth);
+    }
+
+    fn unsupported_msr_raises_gp() {
+        let fault = try_rdmsr(0xdead_beef).expect_err("rdmsr of an unsupported MSR succeeded");
+        assert_eq!(fault.exception, Exception::GeneralProtection);
+        assert_eq!(fault.error_code, 0);
+
+        // The PAT is programmed by KernelAddressSpace::init.
+        assert_eq!(try_rdmsr(IA32_PAT), Ok(0x0007_0106_0007_0106));
+    }
+
+    fn cpu_id_is_cached() {
+        let cpu = percpu::current().expect("no per-CPU data");
+        assert_eq!(cpu.apic_id, percpu::read_apic_id());
+        assert_eq!(cpu_id(), cpu.apic_id);
+    }
+}
@@ -0,0 +1,30 @@
+#![no_main]
+#![no_std]
+
+extern crate alloc;
+
+use kernel::cpu::gdt::init_gdt;
+use kernel::cpu::idt::init_early_idt;
+use kernel::cpu::percpu::init_percpu;
+use kernel::cpu::symbols::init_symbols;
+use kernel::logger::init_logger;
+use kernel::mm::alloc::init_mem;
+use kernel::testing::{run_tests, tests};
+
+mod cpu;
+mod mm;
+mod vmx;
+
+bootloader_api::entry_point!(ktest_main, config = &kernel::BOOTLOADER_CONFIG);
+
+#[no_mangle]
+pub fn ktest_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    init_percpu();
+    init_logger().expect("failed to init logger");
+    init_early_idt();
+    init_symbols(boot_info);
+    init_mem(boot_info).expect("failed to init the kernel heap");
+    init_gdt();
+
+    run_tests(tests())
+}
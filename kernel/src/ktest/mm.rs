@@ -0,0 +1,141 @@
+//! Memory management tests.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use kernel::cpu::msr::IA32_APIC_BASE;
+use kernel::kernel_test;
+use kernel::mm::alloc::{AllocError, HEAP_SIZE};
+use kernel::mm::frame::{FRAMES, LOW_MEMORY_END};
+use kernel::mm::memory::{virt_to_phys, ROOT_MEM};
+use kernel::mm::paging::{CacheMode, MappingSize, PagingError, KERNEL_SPACE};
+use kernel::mm::stack::{is_stack_guard, KernelStack, BOOT_STACK_GUARD};
+use kernel::mm::stats;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageTableFlags, PhysFrame, Size2MiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+kernel_test! {
+    fn alloc_pages_direct_map() -> Result<(), AllocError> {
+        let before = ROOT_MEM.lock().page_stats();
+
+        for order in [0, 1, 9] {
+            let pages = ROOT_MEM.lock().alloc_pages(order)?;
+            assert!(pages.paddr.is_aligned(pages.size()));
+            assert_eq!(virt_to_phys(pages.vaddr), Some(pages.paddr));
+
+            // SAFETY: the pages are allocated and mapped in the direct map.
+            unsafe {
+                let ptr = pages.vaddr.as_mut_ptr::<u8>();
+                ptr.write_bytes(0xa5, pages.size() as usize);
+                assert_eq!(*ptr.add(pages.size() as usize - 1), 0xa5);
+
+                ROOT_MEM.lock().free_pages(pages);
+            }
+        }
+
+        // Refills from the frame allocator are kept by the page allocator.
+        assert_eq!(ROOT_MEM.lock().page_stats().allocated, before.allocated);
+        Ok(())
+    }
+
+    fn frames_alloc_free() {
+        let mut frames = FRAMES.lock();
+        let free = frames.free_frames();
+
+        let frame: PhysFrame<Size2MiB> = frames.allocate_frame().expect("no 2MiB frame");
+        assert!(frame.start_address() >= LOW_MEMORY_END);
+        assert_eq!(frames.free_frames(), free - 512);
+
+        // SAFETY: the frame was just allocated and isn't used.
+        unsafe { frames.deallocate_frame(frame) };
+        assert_eq!(frames.free_frames(), free);
+    }
+
+    fn heap_grows_on_demand() {
+        let before = ROOT_MEM.lock().heap_stats();
+
+        let buffer = vec![0x5au8; 2 * HEAP_SIZE];
+        assert!(buffer.iter().all(|&b| b == 0x5a));
+
+        let after = ROOT_MEM.lock().heap_stats();
+        assert!(after.size >= before.size + HEAP_SIZE);
+        assert!(after.size <= after.max_size);
+        assert!(after.grows > before.grows);
+    }
+
+    fn snapshot_diff_tracks_leaks() {
+        let before = stats::snapshot();
+
+        let small: Vec<u64> = Vec::with_capacity(4);
+        let large: Vec<u8> = Vec::with_capacity(8192);
+        let leaks = stats::snapshot().diff(&before);
+        // With heap-debug, the block allocator also counts the headers and
+        // redzones.
+        #[cfg(not(feature = "heap-debug"))]
+        assert_eq!(leaks.allocated, 32 + 8192);
+        #[cfg(feature = "heap-debug")]
+        assert!(leaks.allocated > 32 + 8192);
+        assert_eq!(leaks.live_large, 1);
+        assert_eq!(leaks.live_blocks.iter().sum::<isize>(), 1);
+
+        drop(small);
+        drop(large);
+        let leaks = stats::snapshot().diff(&before);
+        assert!(leaks.is_empty(), "leaked {}", leaks);
+    }
+
+    fn map_mmio_lapic_uncacheable() -> Result<(), PagingError> {
+        // The local APIC page isn't RAM, so an uncacheable mapping doesn't
+        // alias a write-back one.
+        // SAFETY: IA32_APIC_BASE exists on every x86_64 CPU.
+        let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() } & 0x000f_ffff_ffff_f000;
+        let apic_base = PhysAddr::new(apic_base);
+
+        let mmio = KERNEL_SPACE.lock().map_mmio(
+            apic_base,
+            0x1000,
+            CacheMode::Uncacheable,
+            &mut *FRAMES.lock(),
+        )?;
+
+        let mut space = KERNEL_SPACE.lock();
+        let mapping = space.translate(mmio).expect("MMIO range not mapped");
+        assert_eq!(mapping.paddr, apic_base);
+        assert_eq!(mapping.size, MappingSize::Size4KiB);
+        assert_eq!(mapping.cache, CacheMode::Uncacheable);
+        assert!(mapping.flags.contains(PageTableFlags::NO_EXECUTE));
+
+        // PAT entry 3 (PWT, PCD, no PAT bit) is UC.
+        let pte = space.walk(mmio).entries[3].expect("no PTE");
+        let pte = PageTableFlags::from_bits_retain(pte);
+        assert!(pte.contains(PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE));
+        assert!(!pte.contains(PageTableFlags::HUGE_PAGE), "PAT bit set");
+
+        space.unmap_mmio(mmio, 0x1000)?;
+        assert!(space.translate(mmio).is_none());
+        Ok(())
+    }
+
+    fn kernel_stack_has_guard_page() -> Result<(), PagingError> {
+        let stack = KernelStack::new(8192)?;
+        let (bottom, guard) = (stack.bottom(), stack.guard());
+
+        let mut space = KERNEL_SPACE.lock();
+        assert!(space.translate(bottom).is_some());
+        assert!(space.translate(stack.top() - 1u64).is_some());
+        assert!(space.translate(guard).is_none());
+        assert!(space.translate(VirtAddr::new(BOOT_STACK_GUARD)).is_none());
+        drop(space);
+        assert!(is_stack_guard(guard));
+
+        // SAFETY: the stack is mapped and unused.
+        unsafe { bottom.as_mut_ptr::<u64>().write_volatile(0x5a) };
+
+        drop(stack);
+        assert!(KERNEL_SPACE.lock().translate(bottom).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+//! VMX regression tests, exercising the instructions emulated by L0.
+
+use alloc::boxed::Box;
+use core::arch::asm;
+
+use kernel::cpu::extable::Fault;
+use kernel::cpu::idt::Exception;
+use kernel::kernel_test;
+use kernel::mm::stats;
+use kernel::testing::Outcome;
+use kernel::virt::vmx::asm::{
+    asm_invept, asm_invvpid, asm_vmcall_root, asm_vmptrld, InveptType, InvvpidType,
+};
+use kernel::virt::vmx::caps::{vmx_supported, VmxCaps};
+use kernel::virt::vmx::errors::VmInstructionError;
+use kernel::virt::vmx::exit::{BasicExitReason, ExitAction, ExitHandlers, VmExit};
+use kernel::virt::vmx::vcpu::Vcpu;
+use kernel::virt::vmx::vmcs::VMCS;
+use kernel::virt::vmx::vmxon::{vmx_enabled, VmxOn};
+use kernel::virt::{VMXResult, VirtError};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+/// Skips the test if the CPU doesn't support VMX, e.g. with TCG.
+macro_rules! require_vmx {
+    () => {
+        if !vmx_supported() {
+            return Ok(Outcome::Skipped("VMX unsupported"));
+        }
+    };
+}
+
+/// Enters VMX operation, left when the returned region is dropped.
+fn vmxon() -> Result<Box<VmxOn>, VirtError> {
+    let mut vmxon = Box::new(VmxOn::new());
+    vmxon.setup()?;
+    Ok(vmxon)
+}
+
+/// Returns a VMCS made current.
+fn current_vmcs() -> Result<Box<VMCS>, VirtError> {
+    let mut vmcs = Box::new(VMCS::new());
+    vmcs.setup()?;
+    Ok(vmcs)
+}
+
+fn assert_fail_valid<T: core::fmt::Debug>(
+    result: Result<T, VirtError>,
+    expected: VmInstructionError,
+) {
+    assert!(
+        matches!(
+            result,
+            Err(VirtError::VMInstruction(VMXResult::FailValid(error))) if error == expected
+        ),
+        "expected VMfailValid({}), got {:?}",
+        expected,
+        result
+    );
+}
+
+extern "C" fn guest_vmcall_hlt() -> ! {
+    loop {
+        unsafe {
+            asm!("vmcall");
+            asm!("hlt");
+        }
+    }
+}
+
+fn handle_vmcall(vcpu: &mut Vcpu, _exit: &VmExit) -> Result<ExitAction, VirtError> {
+    vcpu.skip_instruction()?;
+    Ok(ExitAction::Resume)
+}
+
+kernel_test! {
+    fn vmxon_vmxoff() -> Result<Outcome, VirtError> {
+        require_vmx!();
+
+        let vmxon = vmxon()?;
+        assert!(vmxon.is_active() && vmx_enabled());
+
+        vmxon.vmxoff()?;
+        assert!(!vmxon.is_active() && !vmx_enabled());
+        Ok(Outcome::Passed)
+    }
+
+    fn vmxon_without_vmxe_raises_ud() -> Result<Outcome, VirtError> {
+        if vmx_enabled() {
+            return Ok(Outcome::Skipped("already in VMX operation"));
+        }
+
+        let cr4 = Cr4::read();
+        let vmxon = Box::new(VmxOn::new());
+        // SAFETY: CR4.VMXE can be cleared outside of VMX operation, and is
+        // restored right after.
+        let result = unsafe {
+            Cr4::write(cr4 - Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS);
+            let result = vmxon.vmxon();
+            Cr4::write(cr4);
+            result
+        };
+
+        assert!(
+            matches!(
+                result,
+                Err(VirtError::Fault(Fault {
+                    exception: Exception::InvalidOpcode,
+                    ..
+                }))
+            ),
+            "vmxon returned {:?}",
+            result
+        );
+        Ok(Outcome::Passed)
+    }
+
+    fn vmxon_in_vmx_root() -> Result<Outcome, VirtError> {
+        require_vmx!();
+
+        let vmxon = vmxon()?;
+        let _vmcs = current_vmcs()?;
+
+        assert_fail_valid(vmxon.vmxon(), VmInstructionError::VmxonInVmxRoot);
+        Ok(Outcome::Passed)
+    }
+
+    fn vmptrld_vmxon_pointer() -> Result<Outcome, VirtError> {
+        require_vmx!();
+
+        let vmxon = vmxon()?;
+        let _vmcs = current_vmcs()?;
+
+        // SAFETY: the VMXON region is still allocated.
+        let result = unsafe { asm_vmptrld(vmxon.paddr()?) };
+        assert_fail_valid(result, VmInstructionError::VmptrldVmxonPointer);
+        Ok(Outcome::Passed)
+    }
+
+    fn vmptrst_tracks_current_vmcs() -> Result<Outcome, VirtError> {
+        require_vmx!();
+
+        let _vmxon = vmxon()?;
+        let vmcs = current_vmcs()?;
+        assert!(vmcs.is_current()?);
+
+        vmcs.vmclear()?;
+        assert!(!vmcs.is_current()?);
+        Ok(Outcome::Passed)
+    }
+
+    fn vmcall_in_vmx_root() -> Result<Outcome, VirtError> {
+        require_vmx!();
+
+        let _vmxon = vmxon()?;
+        let _vmcs = current_vmcs()?;
+
+        // SAFETY: vmcall fails in VMX root operation.
+        let result = unsafe { asm_vmcall_root(0, [0; 4]) };
+        assert_fail_valid(result, VmInstructionError::VmcallInVmxRoot);
+        Ok(Outcome::Passed)
+    }
+
+    fn invept_global() -> Result<Outcome, VirtError> {
+        require_vmx!();
+        if !VmxCaps::read().ept_vpid.invept_all_context {
+            return Ok(Outcome::Skipped("all-context INVEPT unsupported"));
+        }
+
+        let _vmxon = vmxon()?;
+        // SAFETY: the CPU is in VMX root operation.
+        unsafe { asm_invept(InveptType::Global, 0)? };
+        Ok(Outcome::Passed)
+    }
+
+    fn invvpid_all_context() -> Result<Outcome, VirtError> {
+        require_vmx!();
+        if !VmxCaps::read().ept_vpid.invvpid_all_context {
+            return Ok(Outcome::Skipped("all-context INVVPID unsupported"));
+        }
+
+        let _vmxon = vmxon()?;
+        // SAFETY: the CPU is in VMX root operation.
+        unsafe { asm_invvpid(InvvpidType::AllContext, 0, 0)? };
+        Ok(Outcome::Passed)
+    }
+
+    fn guest_runs_until_hlt() -> Result<Outcome, VirtError> {
+        require_vmx!();
+
+        let _vmxon = vmxon()?;
+        let mut vcpu = Vcpu::new(guest_vmcall_hlt);
+        vcpu.setup()?;
+
+        let mut handlers = ExitHandlers::new();
+        handlers.register(BasicExitReason::Vmcall, handle_vmcall);
+
+        let exit = vcpu.run_with(&handlers)?;
+        assert!(matches!(exit, VmExit::Hlt), "unexpected VM exit {:?}", exit);
+        Ok(Outcome::Passed)
+    }
+
+    fn vcpu_create_destroy_leaks_nothing() -> Result<Outcome, VirtError> {
+        require_vmx!();
+
+        let before = stats::snapshot();
+        {
+            let _vmxon = vmxon()?;
+            let mut vcpu = Vcpu::new(guest_vmcall_hlt);
+            vcpu.setup()?;
+
+            let mut handlers = ExitHandlers::new();
+            handlers.register(BasicExitReason::Vmcall, handle_vmcall);
+            vcpu.run_with(&handlers)?;
+        }
+
+        let leaks = stats::snapshot().diff(&before);
+        assert!(leaks.is_empty(), "leaked {}", leaks);
+        Ok(Outcome::Passed)
+    }
+}
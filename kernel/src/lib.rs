@@ -1,21 +1,39 @@
-#![no_main]
-#![no_std]
-#![feature(abi_x86_interrupt)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 
 extern crate alloc;
 
-use core::arch::asm;
-use core::panic::PanicInfo;
+use bootloader_api::{config::Mapping, BootloaderConfig};
+#[cfg(not(test))]
+use core::{arch::asm, panic::PanicInfo};
 
+pub mod console;
 pub mod cpu;
 pub mod io;
 pub mod logger;
 pub mod mm;
+pub mod testing;
+pub mod uart;
 pub mod virt;
 
+/// Bootloader configuration shared by the kernel binaries.
+pub const BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.kernel_stack_size = 100 * 1024; // 100 KiB
+
+    // The bootloader leaves the page at this address unmapped, and maps the
+    // stack right above it.
+    config.mappings.kernel_stack = Mapping::FixedAddress(mm::stack::BOOT_STACK_GUARD);
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     log::error!("Panic: {}", info);
+    log::error!("{}", cpu::backtrace::Backtrace::current());
+    testing::on_panic(info);
     loop {
         unsafe {
             asm!("hlt");
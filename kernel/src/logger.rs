@@ -1,23 +1,164 @@
-use super::io::IOPort;
+//! Serial logger.
+//!
+//! Records are prefixed with the time since boot, from the TSC, and the ID of
+//! the CPU logging them:
+//!
+//! ```text
+//! [    0.012345] cpu0 INFO  kernel::mm::alloc: message
+//! ```
+//!
+//! The levels are filtered per module by the `KERNEL_LOG` variable at build
+//! time, e.g. `KERNEL_LOG=info,kernel::mm=debug,kernel::virt=off`. Records of
+//! a module use the level of the longest matching module directive, or the
+//! default level, info if unset.
+
+use crate::console::CONSOLE;
+use crate::cpu::{cpu_id, tsc};
 use core::fmt;
-use log::SetLoggerError;
+use core::str::FromStr;
+use log::{LevelFilter, SetLoggerError};
 use spin::Lazy;
 
-static mut CONSOLE: IOPort = IOPort::new(0x03F8);
 static LOGGER: Lazy<Logger> = Lazy::new(Logger::new);
 
-#[derive(Default)]
-struct Logger;
+/// Filter used when KERNEL_LOG is unset or invalid.
+const DEFAULT_FILTER: &str = "info";
+
+/// Maximum number of module directives of a filter.
+pub const MAX_DIRECTIVES: usize = 16;
+
+/// Error parsing a filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterError {
+    InvalidLevel(&'static str),
+    TooManyDirectives,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLevel(directive) => write!(f, "invalid level in {:?}", directive),
+            Self::TooManyDirectives => write!(f, "more than {} directives", MAX_DIRECTIVES),
+        }
+    }
+}
+
+/// Per-module level filter.
+#[derive(Debug, Clone, Copy)]
+pub struct Filter {
+    default: LevelFilter,
+    directives: [(&'static str, LevelFilter); MAX_DIRECTIVES],
+    len: usize,
+}
+
+impl Filter {
+    /// Returns a filter logging every module at level.
+    pub const fn new(level: LevelFilter) -> Self {
+        Self {
+            default: level,
+            directives: [("", LevelFilter::Off); MAX_DIRECTIVES],
+            len: 0,
+        }
+    }
+
+    /// Parses comma-separated directives: a level sets the default level,
+    /// module=level the level of a module, and a lone module enables all of
+    /// its levels.
+    pub fn parse(spec: &'static str) -> Result<Self, FilterError> {
+        let mut filter = Self::new(LevelFilter::Info);
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let (module, level) = match directive.split_once('=') {
+                Some((module, level)) => (
+                    module.trim(),
+                    LevelFilter::from_str(level.trim())
+                        .map_err(|_| FilterError::InvalidLevel(directive))?,
+                ),
+                None => match LevelFilter::from_str(directive) {
+                    Ok(level) => ("", level),
+                    Err(_) => (directive, LevelFilter::Trace),
+                },
+            };
+
+            if module.is_empty() {
+                filter.default = level;
+            } else if filter.len == MAX_DIRECTIVES {
+                return Err(FilterError::TooManyDirectives);
+            } else {
+                filter.directives[filter.len] = (module, level);
+                filter.len += 1;
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Returns the level enabled for the records of target.
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.directives[..self.len]
+            .iter()
+            .filter(|(module, _)| {
+                target
+                    .strip_prefix(module)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    /// Returns the most verbose level enabled for any module.
+    pub fn max_level(&self) -> LevelFilter {
+        self.directives[..self.len]
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+struct Logger {
+    filter: Filter,
+    /// Parsing error of KERNEL_LOG, logged once the logger is set.
+    error: Option<FilterError>,
+    /// TSC at boot, and its frequency if known.
+    start: u64,
+    frequency: Option<u64>,
+}
 
 impl Logger {
-    const fn new() -> Self {
-        Self
+    fn new() -> Self {
+        let (filter, error) = match Filter::parse(option_env!("KERNEL_LOG").unwrap_or("")) {
+            Ok(filter) => (filter, None),
+            Err(e) => (Filter::parse(DEFAULT_FILTER).unwrap(), Some(e)),
+        };
+
+        Self {
+            filter,
+            error,
+            start: tsc::rdtsc(),
+            frequency: tsc::frequency(),
+        }
+    }
+}
+
+/// Time since boot, or TSC cycles if the TSC frequency is unknown.
+struct Timestamp {
+    cycles: u64,
+    frequency: Option<u64>,
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.frequency {
+            Some(hz) => {
+                let micros = u128::from(self.cycles) * 1_000_000 / u128::from(hz);
+                write!(f, "{:5}.{:06}", micros / 1_000_000, micros % 1_000_000)
+            }
+            None => write!(f, "{:12}", self.cycles),
+        }
     }
 }
 
 impl log::Log for Logger {
-    fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
     }
 
     fn log(&self, record: &log::Record<'_>) {
@@ -25,29 +166,85 @@ impl log::Log for Logger {
             return;
         }
 
-        match record.metadata().level() {
-            log::Level::Warn | log::Level::Debug | log::Level::Trace => {
-                unimplemented!();
-            }
-            log::Level::Error => _log(format_args!("[ERROR] {}", record.args())),
-            log::Level::Info => _log(format_args!("[KERNEL] {}", record.args())),
-        }
+        let timestamp = Timestamp {
+            cycles: tsc::rdtsc().wrapping_sub(self.start),
+            frequency: self.frequency,
+        };
+        _log(format_args!(
+            "[{}] cpu{} {:<5} {}: {}",
+            timestamp,
+            cpu_id(),
+            record.level(),
+            record.target(),
+            record.args()
+        ));
     }
 
     fn flush(&self) {}
 }
 
 pub fn init_logger() -> Result<(), SetLoggerError> {
+    let console = CONSOLE.init();
     log::set_logger(&*LOGGER)?;
-    log::set_max_level(log::LevelFilter::Trace);
+    log::set_max_level(LOGGER.filter.max_level());
+    if let Err(e) = console {
+        log::error!("Failed to init the serial console: {}", e);
+    }
+    if let Some(e) = LOGGER.error {
+        log::error!("KERNEL_LOG: {}, using {:?}", e, DEFAULT_FILTER);
+    }
     Ok(())
 }
 
 pub fn _log(args: fmt::Arguments<'_>) {
-    use core::fmt::Write;
-    // SAFETY: mutliple CPU are unsupported
-    unsafe {
-        #[allow(static_mut_refs)]
-        write!(CONSOLE, "{args}\r\n").unwrap();
+    CONSOLE.write_line(args);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn module_filters() {
+        let filter = Filter::parse("warn, kernel::mm=debug,kernel::mm::paging=off,ktest").unwrap();
+        assert_eq!(filter.level("kernel"), LevelFilter::Warn);
+        assert_eq!(filter.level("kernel::mm"), LevelFilter::Debug);
+        assert_eq!(filter.level("kernel::mm::alloc"), LevelFilter::Debug);
+        assert_eq!(filter.level("kernel::mm::paging"), LevelFilter::Off);
+        assert_eq!(filter.level("kernel::mmio"), LevelFilter::Warn);
+        assert_eq!(filter.level("ktest::vmx"), LevelFilter::Trace);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+
+        let filter = Filter::parse("").unwrap();
+        assert_eq!(filter.level("kernel"), LevelFilter::Info);
+        assert_eq!(filter.max_level(), LevelFilter::Info);
+    }
+
+    #[test]
+    fn invalid_filters() {
+        assert_eq!(
+            Filter::parse("kernel=loud").unwrap_err(),
+            FilterError::InvalidLevel("kernel=loud")
+        );
+        let many = "a,b,c,d,e,f,g,h,i,j,k,l,m,n,o,p,q";
+        assert_eq!(
+            Filter::parse(many).unwrap_err(),
+            FilterError::TooManyDirectives
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        let timestamp = Timestamp {
+            cycles: 3_500_000_000,
+            frequency: Some(2_000_000_000),
+        };
+        assert_eq!(timestamp.to_string(), "    1.750000");
+        let timestamp = Timestamp {
+            cycles: 1234,
+            frequency: None,
+        };
+        assert_eq!(timestamp.to_string(), "        1234");
     }
 }
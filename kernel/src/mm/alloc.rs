@@ -1,25 +1,42 @@
 use core::alloc::GlobalAlloc;
 use core::ptr;
 
-use super::block::FixedBlockAlloc;
-use super::frame::BootInfoFrameAllocator;
-use super::memory::{self, ROOT_MEM};
+use super::block::{BlockStats, FixedBlockAlloc};
+#[cfg(feature = "heap-debug")]
+use super::debug::HeapDebug;
+use super::frame::{BitmapFrameAllocator, FRAMES};
+use super::memory::ROOT_MEM;
+use super::page::PAGE_SIZE;
+use super::paging::{MapAttributes, PagingError, KERNEL_SPACE};
+use super::stack;
 use bootloader_api::BootInfo;
 use spinning_top::Spinlock;
-use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
-    VirtAddr,
-};
+use x86_64::VirtAddr;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 1000 * 1024;
+/// Size the heap may grow to, when allocations don't fit in HEAP_SIZE.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
 pub static mut PHYS_MEM_OFFSET: VirtAddr = VirtAddr::zero();
 
-#[global_allocator]
+#[cfg(not(feature = "heap-debug"))]
+#[cfg_attr(not(test), global_allocator)]
+#[cfg_attr(test, allow(dead_code))]
 static ALLOCATOR: KernelAlloc = KernelAlloc::new();
 
+#[cfg(feature = "heap-debug")]
+#[cfg_attr(not(test), global_allocator)]
+#[cfg_attr(test, allow(dead_code))]
+static ALLOCATOR: HeapDebug<KernelAlloc> = HeapDebug::new(KernelAlloc::new());
+
+/// Returns the block allocator behind the global allocator.
+fn kernel_alloc() -> &'static KernelAlloc {
+    #[cfg(feature = "heap-debug")]
+    return ALLOCATOR.inner();
+    #[cfg(not(feature = "heap-debug"))]
+    return &ALLOCATOR;
+}
+
 /// Custom error for the allocator
 #[derive(Debug)]
 pub enum AllocError {
@@ -33,43 +50,63 @@ pub enum AllocError {
     LayoutError,
 }
 
-/// Initialize the heap by mapping all pages.
-pub fn init_mem(boot_info: &'static mut BootInfo) -> Result<(), MapToError<Size4KiB>> {
+/// Initialize the kernel address space, and the heap by mapping its initial
+/// pages.
+pub fn init_mem(boot_info: &'static mut BootInfo) -> Result<(), PagingError> {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    // SAFETY: the kernel is still single-threaded here.
+    let mut space = KERNEL_SPACE.lock();
+    // SAFETY: the kernel is still single-threaded here, and the bootloader
+    // maps all the physical memory at phys_mem_offset.
     unsafe {
         PHYS_MEM_OFFSET = phys_mem_offset;
+        space.init(phys_mem_offset);
     }
-    // SAFETY: TODO(tleroy)
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
-
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE as u64 - 1;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
+    stack::init_boot_stack_guard(&mut space)?;
+    let mut frame_allocator = FRAMES.lock();
+    // SAFETY: the bootloader maps all the physical memory, and the usable
+    // regions are unused.
+    *frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset)
+            .map_err(|_| PagingError::FrameAllocationFailed)?
     };
 
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper
-                .map_to(page, frame, flags, &mut frame_allocator)?
-                .flush();
-        }
-    }
+    let heap_size = (HEAP_SIZE as u64).next_multiple_of(PAGE_SIZE);
+    space.map_anonymous(
+        VirtAddr::new(HEAP_START as u64),
+        heap_size,
+        MapAttributes::data(),
+        &mut *frame_allocator,
+    )?;
+
+    drop(frame_allocator);
+    drop(space);
 
-    ROOT_MEM.lock().init(HEAP_START, HEAP_SIZE);
+    let root_mem = &mut *ROOT_MEM.lock();
+    root_mem.init(HEAP_START, HEAP_SIZE, HEAP_MAX_SIZE);
+    root_mem.init_pages(phys_mem_offset);
 
     Ok(())
 }
 
+/// Returns the statistics of the global block allocator. With heap-debug,
+/// the sizes include the redzones and headers.
+pub fn block_stats() -> BlockStats {
+    kernel_alloc().0.lock().stats()
+}
+
+/// Returns the free blocks of the global block allocator to ROOT_MEM, and the
+/// number of bytes released.
+pub fn release_free_blocks() -> usize {
+    kernel_alloc().0.lock().release_free_blocks()
+}
+
+/// Returns the allocations freed recently, and held back to catch double
+/// frees and writes after free, to the block allocator.
+#[cfg(feature = "heap-debug")]
+pub fn flush_quarantine() {
+    ALLOCATOR.flush_quarantine();
+}
+
 /// Global allocator for the kernel
 struct KernelAlloc(Spinlock<FixedBlockAlloc>);
 
@@ -1,5 +1,5 @@
 use core::alloc::Layout;
-use core::ptr::{addr_of, addr_of_mut};
+use core::ptr::{addr_of, NonNull};
 use core::{fmt, mem};
 
 use x86_64::VirtAddr;
@@ -7,63 +7,184 @@ use x86_64::VirtAddr;
 use super::alloc::AllocError;
 use super::memory::ROOT_MEM;
 
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
-
-/// Node for the block linked-list
-#[repr(transparent)]
-struct BlockHeader {
-    next: Option<&'static mut Block>,
+/// Region the block allocator takes its blocks and large allocations from.
+pub trait BackingAlloc {
+    /// The regions holding blocks should be less than 32 GiB apart, the
+    /// limit of the links between free blocks.
+    fn alloc(&self, layout: Layout) -> Result<VirtAddr, AllocError>;
+
+    /// # Safety
+    ///
+    /// Caller should ensure that ptr was returned by alloc with the same
+    /// layout, and is no longer used.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout);
 }
 
-impl BlockHeader {
-    fn new(next: Option<&'static mut Block>) -> Self {
-        Self { next }
+/// The kernel heap, [`ROOT_MEM`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RootMem;
+
+impl BackingAlloc for RootMem {
+    fn alloc(&self, layout: Layout) -> Result<VirtAddr, AllocError> {
+        ROOT_MEM.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ROOT_MEM.lock().dealloc(ptr, layout)
     }
 }
 
-impl fmt::Debug for BlockHeader {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self.next)
+/// The smallest block holds the two links of a free block.
+pub const BLOCK_SIZES: &[usize] = &[16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Index of the largest blocks, the ones taken from the backing region.
+const TOP_INDEX: usize = BLOCK_SIZES.len() - 1;
+const TOP_SIZE: usize = BLOCK_SIZES[TOP_INDEX];
+
+/// Block allocator statistics.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BlockStats {
+    /// Bytes requested by the live allocations.
+    pub allocated: usize,
+    /// Highest value reached by allocated.
+    pub high_watermark: usize,
+    /// Live blocks per size class, in BLOCK_SIZES order.
+    pub live_blocks: [usize; BLOCK_SIZES.len()],
+    /// Live allocations too large for a block, served by the backing region.
+    pub live_large: usize,
+    pub failed: usize,
+    /// Allocations served by the backing region: the large ones, and the
+    /// largest blocks needed when no block was free.
+    pub fallbacks: usize,
+}
+
+impl BlockStats {
+    const fn empty() -> Self {
+        Self {
+            allocated: 0,
+            high_watermark: 0,
+            live_blocks: [0; BLOCK_SIZES.len()],
+            live_large: 0,
+            failed: 0,
+            fallbacks: 0,
+        }
     }
 }
 
-#[repr(transparent)]
+/// Free block, linked in the list of its size.
+///
+/// The links are offsets to the linked blocks, in units of the smallest block,
+/// so that the links and the tag fit in the smallest block.
+#[repr(C)]
 struct Block {
-    header: BlockHeader,
+    next: i32,
+    prev: i32,
+    /// Tag of the block address and size class, zeroed when the block is
+    /// taken from its list.
+    tag: u64,
 }
 
 impl Block {
-    fn new(header: BlockHeader) -> Self {
-        Self { header }
+    /// Returns the tag of a free block at addr, in the list corresponding to
+    /// index. It is a hash of addr, so that an allocated block only passes for
+    /// a free one if its data holds this exact value, and is never zero.
+    fn tag(addr: usize, index: usize) -> u64 {
+        let hash = (addr as u64 ^ 0x5bd1_e995_a3c5_9ac3).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        (hash & !0xf) | (index as u64 + 1)
+    }
+
+    /// Returns the link from this block to node.
+    fn link_to(&self, node: Option<NonNull<Block>>) -> i32 {
+        node.map_or(0, |node| {
+            let offset =
+                (node.as_ptr() as isize - self as *const Self as isize) / BLOCK_SIZES[0] as isize;
+            i32::try_from(offset).expect("free blocks too far apart")
+        })
+    }
+
+    fn follow(&self, link: i32) -> Option<NonNull<Block>> {
+        (link != 0).then(|| {
+            let offset = link as isize * BLOCK_SIZES[0] as isize;
+            // SAFETY: links are only created by link_to, to blocks.
+            unsafe { NonNull::new_unchecked((self as *const Self).byte_offset(offset).cast_mut()) }
+        })
+    }
+
+    fn next(&self) -> Option<NonNull<Block>> {
+        self.follow(self.next)
+    }
+
+    fn prev(&self) -> Option<NonNull<Block>> {
+        self.follow(self.prev)
+    }
+
+    fn set_next(&mut self, node: Option<NonNull<Block>>) {
+        self.next = self.link_to(node);
     }
-}
 
-impl fmt::Debug for Block {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?} -> {:?}", self as *const Self, self.header)
+    fn set_prev(&mut self, node: Option<NonNull<Block>>) {
+        self.prev = self.link_to(node);
     }
 }
 
 /// Fixed-size block allocator
-pub struct FixedBlockAlloc {
-    block_lists: [Option<&'static mut Block>; BLOCK_SIZES.len()],
+///
+/// Blocks are taken from the backing region with the largest block size, and
+/// split in halves down to the requested size. Freed blocks are merged with
+/// their free buddy back up to the largest size, and the free blocks of the
+/// largest size can be returned with release_free_blocks.
+///
+/// Free blocks are doubly linked, and tagged with their size class, so that
+/// merging never walks the lists.
+pub struct FixedBlockAlloc<B: BackingAlloc = RootMem> {
+    block_lists: [Option<NonNull<Block>>; BLOCK_SIZES.len()],
+    stats: BlockStats,
+    backing: B,
 }
 
-impl FixedBlockAlloc {
+// SAFETY: the free blocks are only accessed through the allocator, which owns
+// them.
+unsafe impl<B: BackingAlloc + Send> Send for FixedBlockAlloc<B> {}
+
+impl FixedBlockAlloc<RootMem> {
     pub const fn empty() -> Self {
-        const EMPTY: Option<&'static mut Block> = None;
+        Self::new(RootMem)
+    }
+}
+
+impl<B: BackingAlloc> FixedBlockAlloc<B> {
+    pub const fn new(backing: B) -> Self {
         Self {
-            block_lists: [EMPTY; BLOCK_SIZES.len()],
+            block_lists: [None; BLOCK_SIZES.len()],
+            stats: BlockStats::empty(),
+            backing,
         }
     }
 
     pub fn alloc(&mut self, layout: Layout) -> Result<VirtAddr, AllocError> {
+        let result = self.alloc_block(layout);
+        let stats = &mut self.stats;
+        match (&result, list_index(layout)) {
+            (Err(_), _) => {
+                stats.failed += 1;
+                return result;
+            }
+            (Ok(_), Some(index)) => stats.live_blocks[index] += 1,
+            (Ok(_), None) => stats.live_large += 1,
+        }
+        stats.allocated += layout.size();
+        stats.high_watermark = stats.high_watermark.max(stats.allocated);
+        result
+    }
+
+    pub fn stats(&self) -> BlockStats {
+        self.stats
+    }
+
+    fn alloc_block(&mut self, layout: Layout) -> Result<VirtAddr, AllocError> {
         match list_index(layout) {
-            Some(index) => match self.block_lists[index].take() {
-                Some(node) => {
-                    self.block_lists[index] = node.header.next.take();
-                    Ok(VirtAddr::from_ptr(addr_of!(*node)))
-                }
+            Some(index) => match self.pop_head(index) {
+                Some(node) => Ok(VirtAddr::from_ptr(node.as_ptr())),
                 None => {
                     let block_size = BLOCK_SIZES[index];
                     let block_align = block_size;
@@ -72,84 +193,184 @@ impl FixedBlockAlloc {
                     self.fallback_alloc(layout)
                 }
             },
-            None => ROOT_MEM.lock().alloc(layout),
+            None => {
+                self.stats.fallbacks += 1;
+                self.backing.alloc(layout)
+            }
         }
     }
 
+    /// Allocates a block of exactly layout.size(), a block size, by splitting
+    /// the smallest larger free block, taken from the backing region if none
+    /// is free.
     fn fallback_alloc(&mut self, layout: Layout) -> Result<VirtAddr, AllocError> {
+        let index = list_index(layout).ok_or(AllocError::TooBig)?;
+
         // Find first non-empty list for bigger blocks
-        let Some(upper_index) = self.first_list_with_block(layout) else {
-            return ROOT_MEM.lock().alloc(layout);
+        let upper_index = match self.first_list_with_block(layout) {
+            Some(upper_index) => upper_index,
+            None => {
+                self.refill()?;
+                TOP_INDEX
+            }
         };
 
-        if layout.size() <= BLOCK_SIZES[upper_index - 1] || layout.size() > BLOCK_SIZES[upper_index]
-        {
-            self.split_and_downgrade(upper_index)?;
-            return self.fallback_alloc(layout);
+        for i in (index + 1..=upper_index).rev() {
+            self.split_and_downgrade(i)?;
         }
 
-        let node = self.pop_head(upper_index).ok_or(AllocError::LayoutError)?;
-        Ok(VirtAddr::from_ptr(addr_of!(*node)))
+        let node = self.pop_head(index).ok_or(AllocError::LayoutError)?;
+        Ok(VirtAddr::from_ptr(node.as_ptr()))
     }
 
-    fn split_head(
-        &mut self,
-        index: usize,
-    ) -> Result<(&'static mut Block, &'static mut Block), AllocError> {
-        // unlink the first free node
-        let head = self.pop_head(index).ok_or(AllocError::LayoutError)?;
+    /// Takes a block of the largest size from the backing region.
+    fn refill(&mut self) -> Result<(), AllocError> {
+        let layout =
+            Layout::from_size_align(TOP_SIZE, TOP_SIZE).map_err(|_| AllocError::LayoutError)?;
 
-        let new_node_ptr = addr_of_mut!(*head).wrapping_byte_add(BLOCK_SIZES[index - 1]);
-        // SAFETY: we trust the previous memory block to be valid. Therefore, the address
-        // of the middle of the block is valid. This address will be the address
-        // of the new block. We also trust the allocator to ensure that a block never
-        // overlaps to other valid block, so the second half of the block never overlaps
-        // with something else.
-        // Finally, we'll initialize the new second block afterwards. This will create
-        // a valid reference.
-        let new_node = unsafe { &mut *new_node_ptr };
+        self.stats.fallbacks += 1;
+        let addr = self.backing.alloc(layout)?.as_u64() as usize;
+        // SAFETY: the backing region returned an unused block, large and
+        // aligned enough for a block header.
+        unsafe { self.put_head(TOP_INDEX, addr) };
 
-        Ok((head, new_node))
+        Ok(())
+    }
+
+    /// Returns the free blocks of the largest size to the backing region, and
+    /// the number of bytes released.
+    pub fn release_free_blocks(&mut self) -> usize {
+        let layout = Layout::from_size_align(TOP_SIZE, TOP_SIZE).expect("invalid block layout");
+
+        let mut released = 0;
+        while let Some(node) = self.pop_head(TOP_INDEX) {
+            // SAFETY: blocks of the largest size are only created by refill,
+            // or by merging the halves of such a block.
+            unsafe { self.backing.dealloc(node.as_ptr().cast(), layout) };
+            released += TOP_SIZE;
+        }
+        released
     }
 
     fn split_and_downgrade(&mut self, index: usize) -> Result<(), AllocError> {
         assert!(index > 0);
-        assert!(index < BLOCK_SIZES[BLOCK_SIZES.len() - 1]);
+        assert!(index < BLOCK_SIZES.len());
 
-        let (node1, node2) = self.split_head(index)?;
+        let head = self.pop_head(index).ok_or(AllocError::LayoutError)?;
+        let addr = head.as_ptr() as usize;
 
-        self.put_head(index - 1, node2);
-        self.put_head(index - 1, node1);
+        // SAFETY: the halves of the free block are free, and don't overlap
+        // any other block.
+        unsafe {
+            self.put_head(index - 1, addr + BLOCK_SIZES[index - 1]);
+            self.put_head(index - 1, addr);
+        }
 
         Ok(())
     }
 
-    /// Puts node as current head of the list corresponding to index.
-    fn put_head(&mut self, index: usize, node: &'static mut Block) {
-        node.header.next = self.block_lists[index].take();
+    /// Puts the block at addr as current head of the list corresponding to
+    /// index.
+    ///
+    /// # Safety
+    ///
+    /// The block should be free, of the size of the list, and not in any list.
+    unsafe fn put_head(&mut self, index: usize, addr: usize) {
+        let mut node = NonNull::new_unchecked(addr as *mut Block);
+        let next = self.block_lists[index];
+        node.as_ptr().write(Block {
+            next: 0,
+            prev: 0,
+            tag: Block::tag(addr, index),
+        });
+        node.as_mut().set_next(next);
+        if let Some(mut next) = next {
+            next.as_mut().set_prev(Some(node));
+        }
         self.block_lists[index] = Some(node);
     }
 
     /// Pops the current head of the list corresponding to index.
-    /// Sets current head's next node as new current head.
-    fn pop_head(&mut self, index: usize) -> Option<&'static mut Block> {
-        let node = self.block_lists[index].take()?;
-        self.block_lists[index] = node.header.next.take();
+    fn pop_head(&mut self, index: usize) -> Option<NonNull<Block>> {
+        let node = self.block_lists[index]?;
+        // SAFETY: node is in the list.
+        unsafe { self.unlink(index, node) };
         Some(node)
     }
 
+    /// Unlinks node from the list corresponding to index.
+    ///
+    /// # Safety
+    ///
+    /// node should be in the list.
+    unsafe fn unlink(&mut self, index: usize, mut node: NonNull<Block>) {
+        let (next, prev) = (node.as_ref().next(), node.as_ref().prev());
+        match prev {
+            Some(mut prev) => prev.as_mut().set_next(next),
+            None => self.block_lists[index] = next,
+        }
+        if let Some(mut next) = next {
+            next.as_mut().set_prev(prev);
+        }
+        node.as_mut().tag = 0;
+    }
+
+    /// Returns true if the block at addr is free, in the list corresponding
+    /// to index.
+    ///
+    /// # Safety
+    ///
+    /// addr should be a block of the size of the list, split from a block of
+    /// the largest size taken by refill.
+    unsafe fn is_free(&self, index: usize, addr: usize) -> bool {
+        let tag = addr_of!((*(addr as *const Block)).tag);
+        // The block may be allocated, and written to: read its data once.
+        tag.read_volatile() == Block::tag(addr, index)
+    }
+
+    /// Returns the free blocks of the list corresponding to index.
+    fn free_blocks(&self, index: usize) -> impl Iterator<Item = NonNull<Block>> + '_ {
+        // SAFETY: the blocks of the lists are free, and initialized.
+        core::iter::successors(self.block_lists[index], |node| unsafe {
+            node.as_ref().next()
+        })
+    }
+
+    /// Frees the block or large allocation at ptr, merging blocks with their
+    /// free buddies.
+    ///
+    /// # Safety
+    ///
+    /// Caller should ensure that ptr was returned by alloc with the same
+    /// layout, and is no longer used.
     pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.stats.allocated -= layout.size();
         match list_index(layout) {
-            Some(index) => {
-                assert!(mem::size_of::<BlockHeader>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<BlockHeader>() <= BLOCK_SIZES[index]);
-
-                let current_top = Block::new(BlockHeader::new(self.block_lists[index].take()));
-                let new_node_ptr = addr_of_mut!(*ptr).cast::<Block>();
-                new_node_ptr.write(current_top);
-                self.block_lists[index] = Some(&mut *new_node_ptr);
+            Some(mut index) => {
+                self.stats.live_blocks[index] -= 1;
+
+                assert!(mem::size_of::<Block>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<Block>() <= BLOCK_SIZES[index]);
+
+                // Blocks are aligned on their size, inside blocks of the
+                // largest size: merge with the free buddy while there is one.
+                let mut addr = ptr as usize;
+                while index < TOP_INDEX {
+                    let buddy = addr ^ BLOCK_SIZES[index];
+                    if !self.is_free(index, buddy) {
+                        break;
+                    }
+                    self.unlink(index, NonNull::new_unchecked(buddy as *mut Block));
+                    addr = addr.min(buddy);
+                    index += 1;
+                }
+
+                self.put_head(index, addr);
+            }
+            None => {
+                self.stats.live_large -= 1;
+                self.backing.dealloc(ptr, layout)
             }
-            None => ROOT_MEM.lock().dealloc(ptr, layout),
         }
     }
 
@@ -166,11 +387,15 @@ impl FixedBlockAlloc {
     }
 }
 
-impl fmt::Debug for FixedBlockAlloc {
+impl<B: BackingAlloc> fmt::Debug for FixedBlockAlloc<B> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "FixedBlockAlloc:")?;
-        for (i, b) in self.block_lists.iter().enumerate().rev() {
-            writeln!(f, "\t{}\t-> {:?}", BLOCK_SIZES[i], b)?;
+        for i in (0..BLOCK_SIZES.len()).rev() {
+            write!(f, "\t{}\t->", BLOCK_SIZES[i])?;
+            for node in self.free_blocks(i) {
+                write!(f, " {:?}", node)?;
+            }
+            writeln!(f)?;
         }
         Ok(())
     }
@@ -184,9 +409,256 @@ pub fn list_index(layout: Layout) -> Option<usize> {
 
 fn size_to_index(size: usize) -> Option<usize> {
     let res = (size.ilog2() - BLOCK_SIZES[0].ilog2()) as usize;
-    if res > 8 {
+    if res > TOP_INDEX {
         return None;
     }
 
     Some(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::alloc::{alloc, dealloc};
+    use std::cell::RefCell;
+    use std::vec::Vec;
+
+    /// Backing region over the host allocator, tracking what it handed out.
+    #[derive(Default)]
+    struct HostMem {
+        regions: RefCell<Vec<(usize, Layout)>>,
+    }
+
+    impl HostMem {
+        fn live_bytes(&self) -> usize {
+            self.regions.borrow().iter().map(|(_, l)| l.size()).sum()
+        }
+    }
+
+    impl BackingAlloc for HostMem {
+        fn alloc(&self, layout: Layout) -> Result<VirtAddr, AllocError> {
+            // SAFETY: the allocator never requests zero-sized layouts.
+            let ptr = unsafe { alloc(layout) };
+            if ptr.is_null() {
+                return Err(AllocError::OutOfMemory);
+            }
+            self.regions.borrow_mut().push((ptr as usize, layout));
+            Ok(VirtAddr::from_ptr(ptr))
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let mut regions = self.regions.borrow_mut();
+            let i = regions
+                .iter()
+                .position(|&r| r == (ptr as usize, layout))
+                .expect("dealloc of a region not allocated");
+            regions.swap_remove(i);
+            dealloc(ptr, layout);
+        }
+    }
+
+    impl Drop for HostMem {
+        fn drop(&mut self) {
+            for &(ptr, layout) in self.regions.borrow().iter() {
+                // SAFETY: the region was allocated with layout, and the block
+                // allocator owning it is being dropped.
+                unsafe { dealloc(ptr as *mut u8, layout) };
+            }
+        }
+    }
+
+    /// xorshift64*, enough to generate reproducible alloc/free sequences.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    fn new_alloc() -> FixedBlockAlloc<HostMem> {
+        FixedBlockAlloc::new(HostMem::default())
+    }
+
+    /// Free bytes cached in the block lists.
+    fn cached_bytes(alloc: &FixedBlockAlloc<HostMem>) -> usize {
+        (0..BLOCK_SIZES.len())
+            .map(|index| alloc.free_blocks(index).count() * BLOCK_SIZES[index])
+            .sum()
+    }
+
+    #[test]
+    fn index_mapping() {
+        let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+        assert_eq!(list_index(layout(1, 1)), Some(0));
+        assert_eq!(list_index(layout(8, 8)), Some(0));
+        assert_eq!(list_index(layout(16, 16)), Some(0));
+        assert_eq!(list_index(layout(17, 1)), Some(1));
+        assert_eq!(list_index(layout(4, 64)), Some(2));
+        assert_eq!(list_index(layout(2048, 8)), Some(7));
+        assert_eq!(list_index(layout(2049, 8)), None);
+
+        for (index, &size) in BLOCK_SIZES.iter().enumerate() {
+            assert_eq!(size_to_index(size), Some(index));
+        }
+        assert_eq!(size_to_index(4096), None);
+    }
+
+    #[test]
+    fn splits_down_to_the_smallest_block() {
+        let mut alloc = new_alloc();
+        let layout = Layout::from_size_align(2048, 8).unwrap();
+        let large = alloc.alloc(layout).unwrap();
+        // SAFETY: large was just allocated with layout.
+        unsafe { alloc.dealloc(large.as_mut_ptr(), layout) };
+
+        // Every list but the largest is empty: the 2KiB block is split down to
+        // 16 bytes.
+        let small = alloc.alloc(Layout::new::<u8>()).unwrap();
+        assert_eq!(small, large);
+        assert_eq!(alloc.stats().fallbacks, 1);
+        assert_eq!(cached_bytes(&alloc), 2048 - 16);
+        assert!(alloc.block_lists[..TOP_INDEX].iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn coalesces_buddies() {
+        let mut alloc = new_alloc();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let blocks: Vec<_> = (0..4).map(|_| alloc.alloc(layout).unwrap()).collect();
+        assert_eq!(alloc.stats().fallbacks, 1);
+
+        // SAFETY: the blocks were allocated with layout.
+        unsafe {
+            alloc.dealloc(blocks[1].as_mut_ptr(), layout);
+            alloc.dealloc(blocks[2].as_mut_ptr(), layout);
+        }
+        // 1 and 2 aren't buddies.
+        assert!(alloc.block_lists[2].is_some());
+
+        // SAFETY: the blocks were allocated with layout.
+        unsafe {
+            alloc.dealloc(blocks[0].as_mut_ptr(), layout);
+            alloc.dealloc(blocks[3].as_mut_ptr(), layout);
+        }
+        assert!(alloc.block_lists[..TOP_INDEX].iter().all(Option::is_none));
+        assert_eq!(cached_bytes(&alloc), 2048);
+
+        assert_eq!(alloc.release_free_blocks(), 2048);
+        assert_eq!(alloc.backing.live_bytes(), 0);
+        assert_eq!(alloc.release_free_blocks(), 0);
+    }
+
+    #[test]
+    fn refills_take_the_largest_block_size() {
+        let mut alloc = new_alloc();
+        let layout = Layout::from_size_align(16, 16).unwrap();
+        let per_block = TOP_SIZE / 16;
+        for refills in 1..=4 {
+            for _ in 0..per_block {
+                alloc.alloc(layout).unwrap();
+            }
+            assert_eq!(alloc.stats().fallbacks, refills);
+            assert_eq!(alloc.backing.live_bytes(), refills * TOP_SIZE);
+        }
+    }
+
+    #[test]
+    fn splits_the_largest_block_list() {
+        let mut alloc = new_alloc();
+        let layout = Layout::from_size_align(2048, 2048).unwrap();
+        let block = alloc.alloc(layout).unwrap();
+        // SAFETY: block was just allocated with layout.
+        unsafe { alloc.dealloc(block.as_mut_ptr(), layout) };
+
+        alloc.split_and_downgrade(TOP_INDEX).unwrap();
+        let half = Layout::from_size_align(1024, 1024).unwrap();
+        assert_eq!(alloc.alloc(half).unwrap(), block);
+        assert_eq!(alloc.alloc(half).unwrap(), block + 1024u64);
+    }
+
+    #[test]
+    fn large_allocations_go_to_the_backing_region() {
+        let mut alloc = new_alloc();
+        let layout = Layout::from_size_align(8192, 4096).unwrap();
+        let ptr = alloc.alloc(layout).unwrap();
+        assert!(ptr.is_aligned(4096u64));
+        assert_eq!(alloc.stats().live_large, 1);
+        assert_eq!(alloc.backing.live_bytes(), 8192);
+
+        // SAFETY: ptr was just allocated with layout.
+        unsafe { alloc.dealloc(ptr.as_mut_ptr(), layout) };
+        assert_eq!(alloc.stats().live_large, 0);
+        assert_eq!(alloc.backing.live_bytes(), 0);
+    }
+
+    /// Random alloc/free sequences: allocations are aligned, never overlap,
+    /// keep their content, and all the memory is merged back into the largest
+    /// blocks, and released, once everything is freed.
+    #[test]
+    fn random_alloc_free() {
+        for seed in 1..=64 {
+            let mut rng = Rng(seed);
+            let mut alloc = new_alloc();
+            let mut live: Vec<(usize, Layout, u8)> = Vec::new();
+
+            for step in 0..2000 {
+                if live.is_empty() || rng.below(3) != 0 {
+                    let size = match rng.below(8) {
+                        0 => 2049 + rng.below(8192) as usize,
+                        _ => 1 + rng.below(2048) as usize,
+                    };
+                    let align = 1 << rng.below(13);
+                    let layout = Layout::from_size_align(size, align).unwrap();
+                    let ptr = alloc.alloc(layout).unwrap().as_u64() as usize;
+
+                    assert_eq!(ptr % align, 0, "seed {seed} step {step}: misaligned");
+                    for &(other, other_layout, _) in &live {
+                        assert!(
+                            ptr + size <= other || other + other_layout.size() <= ptr,
+                            "seed {seed} step {step}: overlap"
+                        );
+                    }
+
+                    let tag = step as u8;
+                    // SAFETY: ptr was just allocated with size bytes.
+                    unsafe { (ptr as *mut u8).write_bytes(tag, size) };
+                    live.push((ptr, layout, tag));
+                } else {
+                    let (ptr, layout, tag) =
+                        live.swap_remove(rng.below(live.len() as u64) as usize);
+                    // SAFETY: ptr is live, and was allocated with layout.
+                    unsafe {
+                        let data = std::slice::from_raw_parts(ptr as *const u8, layout.size());
+                        assert!(data.iter().all(|&b| b == tag), "seed {seed}: corrupted");
+                        alloc.dealloc(ptr as *mut u8, layout);
+                    }
+                }
+            }
+
+            for (ptr, layout, _) in live.drain(..) {
+                // SAFETY: ptr is live, and was allocated with layout.
+                unsafe { alloc.dealloc(ptr as *mut u8, layout) };
+            }
+
+            let stats = alloc.stats();
+            assert_eq!(stats.allocated, 0);
+            assert_eq!(stats.live_blocks, [0; BLOCK_SIZES.len()]);
+            assert_eq!(stats.live_large, 0);
+            assert_eq!(stats.failed, 0);
+            assert!(alloc.block_lists[..TOP_INDEX].iter().all(Option::is_none));
+            let cached = cached_bytes(&alloc);
+            assert_eq!(cached, alloc.backing.live_bytes());
+            assert_eq!(alloc.release_free_blocks(), cached);
+            assert_eq!(alloc.backing.live_bytes(), 0);
+        }
+    }
+}
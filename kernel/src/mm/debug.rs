@@ -0,0 +1,340 @@
+//! Heap debugging allocator, enabled with the `heap-debug` feature.
+//!
+//! Every allocation carries a header and is surrounded by redzones:
+//!
+//! ```text
+//! | padding | Header | front redzone | data | back redzone |
+//!                                    ^ returned pointer
+//! ```
+//!
+//! Fresh allocations are filled with [`ALLOC_POISON`], and freed ones with
+//! [`FREE_POISON`]. Freed allocations stay in a quarantine before going back
+//! to the inner allocator, so that double frees and writes after free are
+//! caught. A failed check panics with the address of the allocation.
+//!
+//! Guest-visible memory corrupted with these patterns points at a kernel heap
+//! bug rather than at the hypervisor.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::{align_of, size_of};
+use core::{ptr, slice};
+
+use spinning_top::Spinlock;
+
+/// Byte pattern of the redzones.
+pub const REDZONE_BYTE: u8 = 0xfd;
+/// Byte pattern of fresh allocations.
+pub const ALLOC_POISON: u8 = 0xcd;
+/// Byte pattern of freed allocations.
+pub const FREE_POISON: u8 = 0xdd;
+
+const REDZONE_SIZE: usize = 16;
+const LIVE_MAGIC: u64 = 0x4c49_5645_414c_4c43; // "LIVEALLC"
+const FREED_MAGIC: u64 = 0x4652_4545_414c_4c43; // "FREEALLC"
+
+/// Number of freed allocations held back from the inner allocator.
+const QUARANTINE_LEN: usize = 64;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+    align: usize,
+}
+
+/// Offset of the data from the start of the inner allocation.
+fn data_offset(layout: Layout) -> usize {
+    (size_of::<Header>() + REDZONE_SIZE).next_multiple_of(layout.align())
+}
+
+fn inner_layout(layout: Layout) -> Option<Layout> {
+    let size = data_offset(layout)
+        .checked_add(layout.size())?
+        .checked_add(REDZONE_SIZE)?;
+    Layout::from_size_align(size, layout.align().max(align_of::<Header>())).ok()
+}
+
+/// # Safety
+///
+/// Caller should ensure that data was returned by [`HeapDebug::alloc`].
+unsafe fn header(data: *mut u8) -> *mut Header {
+    data.sub(REDZONE_SIZE + size_of::<Header>()).cast()
+}
+
+/// Returns the offset of the first byte of bytes that isn't value.
+fn find_mismatch(bytes: &[u8], value: u8) -> Option<usize> {
+    bytes.iter().position(|&b| b != value)
+}
+
+#[derive(Debug)]
+struct Quarantine {
+    /// Data pointers and layouts of the freed allocations.
+    entries: [Option<(usize, Layout)>; QUARANTINE_LEN],
+    next: usize,
+}
+
+impl Quarantine {
+    /// Adds an allocation, returning the oldest one if the quarantine is full.
+    fn push(&mut self, entry: (usize, Layout)) -> Option<(usize, Layout)> {
+        let evicted = self.entries[self.next].replace(entry);
+        self.next = (self.next + 1) % QUARANTINE_LEN;
+        evicted
+    }
+}
+
+/// Allocator wrapping inner with redzones, poisoning and free checks.
+#[derive(Debug)]
+pub struct HeapDebug<A> {
+    inner: A,
+    quarantine: Spinlock<Quarantine>,
+}
+
+impl<A: GlobalAlloc> HeapDebug<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            quarantine: Spinlock::new(Quarantine {
+                entries: [None; QUARANTINE_LEN],
+                next: 0,
+            }),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Returns the quarantined allocations to the inner allocator, checking
+    /// that they weren't written after being freed.
+    pub fn flush_quarantine(&self) {
+        for i in 0..QUARANTINE_LEN {
+            let entry = self.quarantine.lock().entries[i].take();
+            if let Some((data, layout)) = entry {
+                // SAFETY: quarantined allocations are freed, and only owned by
+                // the quarantine.
+                unsafe { self.release(data as *mut u8, layout) };
+            }
+        }
+    }
+
+    /// Checks that a freed allocation is still poisoned, and returns it to the
+    /// inner allocator.
+    ///
+    /// # Safety
+    ///
+    /// Caller should ensure that data is a freed allocation of layout, taken
+    /// out of the quarantine.
+    unsafe fn release(&self, data: *mut u8, layout: Layout) {
+        let bytes = slice::from_raw_parts(data, layout.size());
+        if let Some(offset) = find_mismatch(bytes, FREE_POISON) {
+            panic!(
+                "heap-debug: use after free of {:p} ({} bytes): written at offset {}",
+                data,
+                layout.size(),
+                offset
+            );
+        }
+
+        let inner_layout = inner_layout(layout).expect("quarantined an invalid layout");
+        self.inner
+            .dealloc(data.sub(data_offset(layout)), inner_layout);
+    }
+
+    /// Panics if data isn't a live allocation of layout, or if its redzones
+    /// were written.
+    ///
+    /// # Safety
+    ///
+    /// Caller should ensure that data points to readable heap memory.
+    unsafe fn check_live(&self, data: *mut u8, layout: Layout) {
+        let header = &*header(data);
+        match header.magic {
+            LIVE_MAGIC => {}
+            FREED_MAGIC => panic!(
+                "heap-debug: double free of {:p} ({} bytes)",
+                data, header.size
+            ),
+            _ => panic!(
+                "heap-debug: free of {:p}, which isn't a live allocation (corrupted header?)",
+                data
+            ),
+        }
+
+        if header.size != layout.size() || header.align != layout.align() {
+            panic!(
+                "heap-debug: free of {:p} with {:?}, allocated with size {} and align {}",
+                data, layout, header.size, header.align
+            );
+        }
+
+        let front = slice::from_raw_parts(data.sub(REDZONE_SIZE), REDZONE_SIZE);
+        if let Some(offset) = find_mismatch(front, REDZONE_BYTE) {
+            panic!(
+                "heap-debug: underflow of {:p} ({} bytes): front redzone written at offset -{}",
+                data,
+                layout.size(),
+                REDZONE_SIZE - offset
+            );
+        }
+        let back = slice::from_raw_parts(data.add(layout.size()), REDZONE_SIZE);
+        if let Some(offset) = find_mismatch(back, REDZONE_BYTE) {
+            panic!(
+                "heap-debug: overflow of {:p} ({} bytes): back redzone written at offset {}",
+                data,
+                layout.size(),
+                layout.size() + offset
+            );
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for HeapDebug<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(inner_layout) = inner_layout(layout) else {
+            return ptr::null_mut();
+        };
+        let start = self.inner.alloc(inner_layout);
+        if start.is_null() {
+            return start;
+        }
+
+        let data = start.add(data_offset(layout));
+        header(data).write(Header {
+            magic: LIVE_MAGIC,
+            size: layout.size(),
+            align: layout.align(),
+        });
+        data.sub(REDZONE_SIZE)
+            .write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+        data.write_bytes(ALLOC_POISON, layout.size());
+        data.add(layout.size())
+            .write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+        data
+    }
+
+    unsafe fn dealloc(&self, data: *mut u8, layout: Layout) {
+        self.check_live(data, layout);
+
+        (*header(data)).magic = FREED_MAGIC;
+        data.write_bytes(FREE_POISON, layout.size());
+
+        let evicted = self.quarantine.lock().push((data as usize, layout));
+        if let Some((data, layout)) = evicted {
+            self.release(data as *mut u8, layout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::alloc::System;
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn poison_and_alignment() {
+        let heap = HeapDebug::new(System);
+        for align in [1, 8, 64, 4096] {
+            let layout = layout(24, align);
+            // SAFETY: the allocation is only accessed within layout, and
+            // freed with it.
+            unsafe {
+                let data = heap.alloc(layout);
+                assert_eq!(data as usize % align, 0);
+                let bytes = slice::from_raw_parts_mut(data, layout.size());
+                assert!(bytes.iter().all(|&b| b == ALLOC_POISON));
+
+                bytes.fill(0);
+                heap.dealloc(data, layout);
+                let bytes = slice::from_raw_parts(data, layout.size());
+                assert!(bytes.iter().all(|&b| b == FREE_POISON));
+            }
+        }
+        heap.flush_quarantine();
+    }
+
+    #[test]
+    fn quarantine_releases_the_oldest() {
+        let heap = HeapDebug::new(System);
+        let layout = layout(8, 8);
+        // SAFETY: the allocations are freed once.
+        unsafe {
+            for _ in 0..QUARANTINE_LEN * 2 {
+                heap.dealloc(heap.alloc(layout), layout);
+            }
+        }
+        assert!(heap.quarantine.lock().entries.iter().all(Option::is_some));
+        heap.flush_quarantine();
+        assert!(heap.quarantine.lock().entries.iter().all(Option::is_none));
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free() {
+        let heap = HeapDebug::new(System);
+        let layout = layout(16, 8);
+        // SAFETY: the second free is caught before touching the allocation.
+        unsafe {
+            let data = heap.alloc(layout);
+            heap.dealloc(data, layout);
+            heap.dealloc(data, layout);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "allocated with size 16 and align 8")]
+    fn layout_mismatch() {
+        let heap = HeapDebug::new(System);
+        // SAFETY: the free is caught before touching the allocation.
+        unsafe {
+            let data = heap.alloc(layout(16, 8));
+            heap.dealloc(data, layout(32, 8));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "back redzone written at offset 16")]
+    fn overflow() {
+        let heap = HeapDebug::new(System);
+        let layout = layout(16, 8);
+        // SAFETY: the write lands in the back redzone, inside the inner
+        // allocation.
+        unsafe {
+            let data = heap.alloc(layout);
+            data.add(16).write(0);
+            heap.dealloc(data, layout);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "front redzone written at offset -1")]
+    fn underflow() {
+        let heap = HeapDebug::new(System);
+        let layout = layout(16, 8);
+        // SAFETY: the write lands in the front redzone, inside the inner
+        // allocation.
+        unsafe {
+            let data = heap.alloc(layout);
+            data.sub(1).write(0);
+            heap.dealloc(data, layout);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "use after free")]
+    fn use_after_free() {
+        let heap = HeapDebug::new(System);
+        let layout = layout(16, 8);
+        // SAFETY: the allocation is still quarantined when written.
+        unsafe {
+            let data = heap.alloc(layout);
+            heap.dealloc(data, layout);
+            data.write(0);
+        }
+        heap.flush_quarantine();
+    }
+}
@@ -1,36 +1,390 @@
+//! Physical frame allocator.
+//!
+//! One bit per 4KiB frame tracks whether it is in use, up to the end of the
+//! last usable region. The bitmap is built once from the bootloader memory
+//! map, and lives in usable memory accessed through the direct map.
+
 use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use spinning_top::Spinlock;
 use x86_64::{
-    structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
-    PhysAddr,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
 };
 
-pub struct BootInfoFrameAllocator {
-    memory_regions: &'static MemoryRegions,
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const BITS: usize = u64::BITS as usize;
+
+/// End of the low memory, only handed out by [`BitmapFrameAllocator::reserve`]
+/// so that it stays available for real-mode guest trampolines.
+pub const LOW_MEMORY_END: PhysAddr = PhysAddr::new_truncate(0x10_0000);
+
+pub static FRAMES: Spinlock<BitmapFrameAllocator> = Spinlock::new(BitmapFrameAllocator::empty());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// No usable region is large enough to hold the bitmap.
+    NoBitmapMemory,
+    /// A frame of the range is in use, or isn't usable memory.
+    Unavailable(PhysAddr),
+}
+
+/// Frame allocator: allocations of 2^order contiguous frames aligned on their
+/// size, and reservation of fixed ranges.
+///
+/// Single frames are allocated in amortized constant time: the search
+/// resumes from the lowest bitmap word that may hold a free frame.
+pub struct BitmapFrameAllocator {
+    /// Set bits are used frames.
+    bitmap: &'static mut [u64],
+    /// Every word from floor to next is full.
     next: usize,
+    /// Words below floor are only handed out by reserve.
+    floor: usize,
+    total: u64,
+    free: u64,
 }
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_regions: &'static MemoryRegions) -> Self {
+impl BitmapFrameAllocator {
+    pub const fn empty() -> Self {
         Self {
-            memory_regions,
+            bitmap: &mut [],
             next: 0,
+            floor: 0,
+            total: 0,
+            free: 0,
+        }
+    }
+
+    /// Creates an allocator over bitmap, with every frame in use.
+    pub fn new(bitmap: &'static mut [u64]) -> Self {
+        bitmap.fill(u64::MAX);
+        Self {
+            bitmap,
+            next: 0,
+            floor: 0,
+            total: 0,
+            free: 0,
+        }
+    }
+
+    /// Builds the bitmap over the usable regions of the memory map, storing it
+    /// in the first usable region large enough.
+    ///
+    /// # Safety
+    ///
+    /// Caller should ensure that the usable regions are unused, and mapped in
+    /// the direct map at phys_offset.
+    pub unsafe fn init(
+        memory_regions: &MemoryRegions,
+        phys_offset: VirtAddr,
+    ) -> Result<Self, FrameError> {
+        let usable = || {
+            memory_regions
+                .iter()
+                .filter(|r| r.kind == MemoryRegionKind::Usable)
+        };
+
+        let end = usable().map(|r| r.end).max().unwrap_or(0);
+        let words = (end / FRAME_SIZE).div_ceil(BITS as u64) as usize;
+        let bitmap_size = (words * core::mem::size_of::<u64>()) as u64;
+
+        let bitmap_start = usable()
+            .map(|r| {
+                let start = PhysAddr::new(r.start).max(LOW_MEMORY_END);
+                (start.align_up(FRAME_SIZE), PhysAddr::new(r.end))
+            })
+            .find(|&(start, end)| start + bitmap_size <= end)
+            .ok_or(FrameError::NoBitmapMemory)?
+            .0;
+
+        let ptr = (phys_offset + bitmap_start.as_u64()).as_mut_ptr::<u64>();
+        let mut frames = Self::new(core::slice::from_raw_parts_mut(ptr, words));
+        for r in usable() {
+            frames.add_range(PhysAddr::new(r.start), PhysAddr::new(r.end));
+        }
+        frames.reserve(bitmap_start, bitmap_start + bitmap_size)?;
+        frames.set_floor(LOW_MEMORY_END);
+
+        Ok(frames)
+    }
+
+    /// Marks the frames fully inside [start, end) as free.
+    pub fn add_range(&mut self, start: PhysAddr, end: PhysAddr) {
+        let start = Self::index(start.align_up(FRAME_SIZE));
+        let end = Self::index(end.align_down(FRAME_SIZE)).min(self.bitmap.len() * BITS);
+
+        for index in start..end {
+            if self.is_used(index) {
+                self.clear(index);
+                self.total += 1;
+                self.free += 1;
+            }
+        }
+        self.next = self.next.min(start / BITS).max(self.floor);
+    }
+
+    /// Only hands out the frames below addr (rounded down to 256KiB) through
+    /// reserve.
+    pub fn set_floor(&mut self, addr: PhysAddr) {
+        self.floor = Self::index(addr) / BITS;
+        self.next = self.next.max(self.floor);
+    }
+
+    /// Reserves the frames covering [start, end), which must all be free.
+    pub fn reserve(&mut self, start: PhysAddr, end: PhysAddr) -> Result<(), FrameError> {
+        let first = Self::index(start.align_down(FRAME_SIZE));
+        let last = Self::index(end.align_up(FRAME_SIZE));
+
+        if let Some(index) = (first..last).find(|&index| self.is_used(index)) {
+            return Err(FrameError::Unavailable(Self::addr(index)));
+        }
+
+        (first..last).for_each(|index| self.set(index));
+        self.free -= (last - first) as u64;
+        Ok(())
+    }
+
+    /// Allocates 2^order contiguous frames aligned on their size.
+    pub fn allocate(&mut self, order: u32) -> Option<PhysAddr> {
+        let count = 1usize << order;
+        let first = if count < BITS {
+            self.find_in_words(count)?
+        } else {
+            self.find_words(count / BITS)?
+        };
+
+        (first..first + count).for_each(|index| self.set(index));
+        self.free -= count as u64;
+        Some(Self::addr(first))
+    }
+
+    /// # Safety
+    ///
+    /// Caller should ensure that the frames were allocated with the same
+    /// order, and are no longer used.
+    pub unsafe fn deallocate(&mut self, addr: PhysAddr, order: u32) {
+        let first = Self::index(addr);
+        let count = 1usize << order;
+
+        for index in first..first + count {
+            debug_assert!(self.is_used(index), "double free of frame {:?}", addr);
+            self.clear(index);
         }
+        self.free += count as u64;
+        self.next = self.next.min(first / BITS).max(self.floor);
+    }
+
+    pub fn total_frames(&self) -> u64 {
+        self.total
+    }
+
+    pub fn free_frames(&self) -> u64 {
+        self.free
+    }
+
+    /// Finds count (< 64) free frames aligned on count inside a word.
+    fn find_in_words(&mut self, count: usize) -> Option<usize> {
+        let mask = (1u64 << count) - 1;
+        let mut full = true;
+
+        for word in self.next..self.bitmap.len() {
+            let bits = self.bitmap[word];
+            if full && bits == u64::MAX {
+                // Keep the hint on the first word with a free frame.
+                self.next = word + 1;
+                continue;
+            }
+            full = false;
+
+            if let Some(shift) = (0..BITS)
+                .step_by(count)
+                .find(|&shift| bits & (mask << shift) == 0)
+            {
+                return Some(word * BITS + shift);
+            }
+        }
+
+        None
+    }
+
+    /// Finds words free words aligned on words.
+    fn find_words(&self, words: usize) -> Option<usize> {
+        let start = self.next.max(self.floor).next_multiple_of(words);
+        (start..self.bitmap.len())
+            .step_by(words)
+            .find(|&word| {
+                word + words <= self.bitmap.len()
+                    && self.bitmap[word..word + words]
+                        .iter()
+                        .all(|&bits| bits == 0)
+            })
+            .map(|word| word * BITS)
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_regions.iter();
-        let usable_regions = regions.filter(|r| r.kind == MemoryRegionKind::Usable);
-        let addr_ranges = usable_regions.map(|r| r.start..r.end);
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap
+            .get(index / BITS)
+            .is_none_or(|bits| bits & (1 << (index % BITS)) != 0)
+    }
+
+    fn set(&mut self, index: usize) {
+        self.bitmap[index / BITS] |= 1 << (index % BITS);
+    }
+
+    fn clear(&mut self, index: usize) {
+        self.bitmap[index / BITS] &= !(1 << (index % BITS));
+    }
+
+    fn index(addr: PhysAddr) -> usize {
+        (addr.as_u64() / FRAME_SIZE) as usize
+    }
+
+    fn addr(index: usize) -> PhysAddr {
+        PhysAddr::new(index as u64 * FRAME_SIZE)
+    }
+}
 
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+impl Default for BitmapFrameAllocator {
+    fn default() -> Self {
+        BitmapFrameAllocator::empty()
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+/// Order of the frames of size S.
+const fn order<S: PageSize>() -> u32 {
+    (S::SIZE / FRAME_SIZE).trailing_zeros()
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        self.allocate(order::<Size4KiB>())
+            .map(PhysFrame::containing_address)
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate(order::<Size2MiB>())
+            .map(PhysFrame::containing_address)
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        self.allocate(order::<Size1GiB>())
+            .map(PhysFrame::containing_address)
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        self.deallocate(frame.start_address(), order::<S>());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 0x10_0000;
+
+    /// Allocator covering size bytes of physical memory, all free.
+    fn frames(size: u64) -> BitmapFrameAllocator {
+        let words = (size / FRAME_SIZE) as usize / BITS;
+        let bitmap = Box::leak(vec![0u64; words].into_boxed_slice());
+        let mut frames = BitmapFrameAllocator::new(bitmap);
+        frames.add_range(PhysAddr::new(0), PhysAddr::new(size));
+        frames
+    }
+
+    #[test]
+    fn allocates_in_order() {
+        let mut frames = frames(MIB);
+        assert_eq!(frames.total_frames(), 256);
+
+        let a: PhysFrame<Size4KiB> = frames.allocate_frame().unwrap();
+        let b: PhysFrame<Size4KiB> = frames.allocate_frame().unwrap();
+        assert_eq!(a.start_address().as_u64(), 0);
+        assert_eq!(b.start_address().as_u64(), 0x1000);
+        assert_eq!(frames.free_frames(), 254);
+
+        // SAFETY: a is unused.
+        unsafe { frames.deallocate_frame(a) };
+        let c: PhysFrame<Size4KiB> = frames.allocate_frame().unwrap();
+        assert_eq!(c, a);
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut frames = frames(64 * FRAME_SIZE);
+        for _ in 0..64 {
+            assert!(FrameAllocator::<Size4KiB>::allocate_frame(&mut frames).is_some());
+        }
+        assert!(FrameAllocator::<Size4KiB>::allocate_frame(&mut frames).is_none());
+        assert_eq!(frames.free_frames(), 0);
+    }
+
+    #[test]
+    fn large_frames_are_aligned() {
+        let mut frames = frames(8 * MIB);
+        frames.allocate(0).unwrap();
+
+        let large: PhysFrame<Size2MiB> = frames.allocate_frame().unwrap();
+        assert_eq!(large.start_address().as_u64(), 2 * MIB);
+
+        let order3 = frames.allocate(3).unwrap();
+        assert_eq!(order3.as_u64(), 8 * FRAME_SIZE);
+
+        // SAFETY: large is unused.
+        unsafe { frames.deallocate_frame(large) };
+        assert_eq!(frames.free_frames(), 2048 - 1 - 8);
+
+        let none: Option<PhysFrame<Size1GiB>> = frames.allocate_frame();
+        assert!(none.is_none());
+    }
+
+    #[test]
+    fn reserve() {
+        let mut frames = frames(2 * MIB);
+        frames.set_floor(LOW_MEMORY_END);
+
+        let first = frames.allocate(0).unwrap();
+        assert_eq!(first, LOW_MEMORY_END);
+
+        // SAFETY: first is unused.
+        unsafe { frames.deallocate(first, 0) };
+        assert_eq!(frames.allocate(0), Some(first));
+
+        frames
+            .reserve(PhysAddr::new(0x8000), PhysAddr::new(0x9000))
+            .unwrap();
+        frames
+            .reserve(PhysAddr::new(0), LOW_MEMORY_END - 0x8000u64)
+            .unwrap_err();
+        assert_eq!(frames.free_frames(), 512 - 2);
+
+        assert_eq!(
+            frames.reserve(first, first + 1u64),
+            Err(FrameError::Unavailable(first))
+        );
+        assert_eq!(
+            frames.reserve(PhysAddr::new(2 * MIB), PhysAddr::new(3 * MIB)),
+            Err(FrameError::Unavailable(PhysAddr::new(2 * MIB)))
+        );
+    }
+
+    #[test]
+    fn holes_are_never_allocated() {
+        let bitmap = Box::leak(vec![0u64; 2].into_boxed_slice());
+        let mut frames = BitmapFrameAllocator::new(bitmap);
+        frames.add_range(PhysAddr::new(0x1800), PhysAddr::new(0x3000));
+        frames.add_range(PhysAddr::new(0x10000), PhysAddr::new(0x11000));
+
+        assert_eq!(frames.total_frames(), 2);
+        assert_eq!(frames.allocate(0), Some(PhysAddr::new(0x2000)));
+        assert_eq!(frames.allocate(0), Some(PhysAddr::new(0x10000)));
+        assert_eq!(frames.allocate(0), None);
     }
 }
@@ -1,38 +1,59 @@
 use core::{alloc::Layout, ptr::NonNull};
 
 use spinning_top::Spinlock;
-use x86_64::{
-    registers::control::Cr3,
-    structures::paging::{OffsetPageTable, PageTable, Translate},
-    PhysAddr, VirtAddr,
-};
+use x86_64::{PhysAddr, VirtAddr};
 
-use super::alloc::{AllocError, PHYS_MEM_OFFSET};
+use super::alloc::AllocError;
+use super::frame::FRAMES;
+use super::page::{PageAllocator, PageStats, Pages, PAGE_SIZE};
+use super::paging::{MapAttributes, KERNEL_SPACE};
 
 // Safety: ROOT_MEM.page_range.{start, end} are note accessed before being initialized.
 pub static ROOT_MEM: Spinlock<MemoryRegion> = Spinlock::new(MemoryRegion::empty());
 
 pub const PAGE_SHIFT: usize = 12;
 
-unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
-    let (level_4_table_frame, _) = Cr3::read();
-    let phys = level_4_table_frame.start_address();
-    let virt = physical_memory_offset + phys.as_u64();
-    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
-
-    &mut *page_table_ptr
-}
-
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
-    let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
+/// Order of the blocks the page allocator takes from the frame allocator when
+/// it runs out of pages.
+const PAGE_REFILL_ORDER: u32 = 9;
+
+/// Minimum size by which the heap grows, to amortize the mappings.
+pub const HEAP_GROW_SIZE: usize = 256 * 1024;
+
+/// Heap statistics, in bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Currently mapped heap size.
+    pub size: usize,
+    /// Size the heap may grow to.
+    pub max_size: usize,
+    pub used: usize,
+    pub free: usize,
+    /// Highest value reached by used.
+    pub high_watermark: usize,
+    /// Number of live allocations.
+    pub live: usize,
+    pub failed: usize,
+    /// Number of times the heap was extended.
+    pub grows: usize,
 }
 
 /// Represents a memory region.
+///
+/// The heap starts with the pages mapped by the caller of init, and maps more
+/// frames at its end when an allocation fails, up to max_size.
 pub struct MemoryRegion {
     start_virt: VirtAddr,
     end_virt: VirtAddr,
+    max_size: usize,
+    high_watermark: usize,
+    live: usize,
+    failed: usize,
+    grows: usize,
     global_alloc: linked_list_allocator::Heap,
+    // Separately locked, so that pages can be allocated through a shared
+    // reference.
+    pages: Spinlock<PageAllocator>,
 }
 
 impl MemoryRegion {
@@ -40,42 +61,167 @@ impl MemoryRegion {
         Self {
             start_virt: VirtAddr::zero(),
             end_virt: VirtAddr::zero(),
+            max_size: 0,
+            high_watermark: 0,
+            live: 0,
+            failed: 0,
+            grows: 0,
             global_alloc: linked_list_allocator::Heap::empty(),
+            pages: Spinlock::new(PageAllocator::empty()),
         }
     }
 
-    pub fn init(&mut self, heap_start: usize, heap_size: usize) {
+    /// Initializes the heap over the heap_size bytes already mapped at
+    /// heap_start. The heap may then grow up to max_size bytes.
+    pub fn init(&mut self, heap_start: usize, heap_size: usize, max_size: usize) {
         self.start_virt = VirtAddr::new(heap_start as u64);
         self.end_virt = self.start_virt + heap_size as u64;
+        self.max_size = max_size.max(heap_size);
 
         unsafe {
             self.global_alloc.init(heap_start as *mut u8, heap_size);
         }
     }
 
+    /// Sets the size the heap may grow to. The heap never shrinks, so a cap
+    /// below the current size only prevents further growth.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
     pub fn alloc(&mut self, layout: Layout) -> Result<VirtAddr, AllocError> {
+        let result = self.alloc_first_fit(layout);
+        match result {
+            Ok(_) => {
+                self.live += 1;
+                self.high_watermark = self.high_watermark.max(self.global_alloc.used());
+            }
+            Err(_) => self.failed += 1,
+        }
+        result
+    }
+
+    fn alloc_first_fit(&mut self, layout: Layout) -> Result<VirtAddr, AllocError> {
+        if let Ok(ptr) = self.global_alloc.allocate_first_fit(layout) {
+            return Ok(VirtAddr::from_ptr(ptr.as_ptr()));
+        }
+
+        // The hole at the end of the heap may be too small, or misaligned.
+        self.grow(layout.size() + layout.align())?;
         self.global_alloc
             .allocate_first_fit(layout)
             .map(|ptr| VirtAddr::from_ptr(ptr.as_ptr()))
             .map_err(|_| AllocError::OutOfMemory)
     }
 
+    /// Maps at least min bytes of new frames at the end of the heap, and adds
+    /// them to it.
+    fn grow(&mut self, min: usize) -> Result<(), AllocError> {
+        let size = self.end_virt - self.start_virt;
+        let available = (self.max_size as u64).saturating_sub(size);
+        let min = (min as u64).next_multiple_of(PAGE_SIZE);
+        if min > available {
+            return Err(AllocError::OutOfMemory);
+        }
+        let by = (HEAP_GROW_SIZE as u64).max(min).min(available);
+
+        let mut space = KERNEL_SPACE.lock();
+        let mut frames = FRAMES.lock();
+        let mut mapped = 0;
+        let result = (0..by).step_by(PAGE_SIZE as usize).try_for_each(|offset| {
+            space
+                .map_anonymous(
+                    self.end_virt + offset,
+                    PAGE_SIZE,
+                    MapAttributes::data(),
+                    &mut *frames,
+                )
+                .map_err(|_| AllocError::OutOfMemory)?;
+            mapped += PAGE_SIZE;
+            Ok(())
+        });
+        drop(frames);
+        drop(space);
+
+        // Keep the pages mapped before a failure.
+        if mapped > 0 {
+            // SAFETY: the pages right after the top of the heap were just
+            // mapped.
+            unsafe { self.global_alloc.extend(mapped as usize) };
+            self.end_virt += mapped;
+            self.grows += 1;
+        }
+        result
+    }
+
     /// # Safety
     ///
     /// Caller should ensure linked_list_allocator::Heap::deallocate() safety
     /// requirements.
     pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.live -= 1;
         self.global_alloc
             .deallocate(NonNull::<u8>::new_unchecked(ptr), layout);
     }
 
-    pub fn alloc_pages(&self, _order: u32) -> Result<VirtAddr, AllocError> {
-        unimplemented!()
+    /// Sets up the page allocator, accessing the pages through the direct map
+    /// at phys_offset. Pages are taken from [`FRAMES`] on demand.
+    pub fn init_pages(&self, phys_offset: VirtAddr) {
+        self.pages.lock().init(phys_offset);
     }
 
-    pub fn alloc_page(&self) -> Result<VirtAddr, AllocError> {
+    /// Allocates 2^order physically contiguous pages, aligned on their size.
+    ///
+    /// When the page allocator is empty, it is refilled from the frame
+    /// allocator with a block of at least 2MiB, or of the requested order.
+    pub fn alloc_pages(&self, order: u32) -> Result<Pages, AllocError> {
+        let mut pages = self.pages.lock();
+        match pages.alloc_pages(order) {
+            Err(AllocError::OutOfMemory) => {}
+            result => return result,
+        }
+
+        let mut frames = FRAMES.lock();
+        let (start, refill) = [order.max(PAGE_REFILL_ORDER), order]
+            .into_iter()
+            .find_map(|refill| frames.allocate(refill).map(|start| (start, refill)))
+            .ok_or(AllocError::OutOfMemory)?;
+        drop(frames);
+
+        // SAFETY: the frames were just allocated, and the bootloader maps all
+        // the physical memory.
+        unsafe { pages.add_range(start, start + (PAGE_SIZE << refill)) };
+        pages.alloc_pages(order)
+    }
+
+    pub fn alloc_page(&self) -> Result<Pages, AllocError> {
         self.alloc_pages(0)
     }
+
+    /// # Safety
+    ///
+    /// Caller should ensure that pages were returned by alloc_pages, and are
+    /// no longer used.
+    pub unsafe fn free_pages(&self, pages: Pages) {
+        self.pages.lock().free_pages(pages)
+    }
+
+    pub fn page_stats(&self) -> PageStats {
+        self.pages.lock().stats()
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        HeapStats {
+            size: self.global_alloc.size(),
+            max_size: self.max_size,
+            used: self.global_alloc.used(),
+            free: self.global_alloc.free(),
+            high_watermark: self.high_watermark,
+            live: self.live,
+            failed: self.failed,
+            grows: self.grows,
+        }
+    }
 }
 
 impl Default for MemoryRegion {
@@ -85,10 +231,8 @@ impl Default for MemoryRegion {
 }
 
 pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
-    let mapper = unsafe {
-        let pt = active_level_4_table(PHYS_MEM_OFFSET);
-        OffsetPageTable::new(pt, PHYS_MEM_OFFSET)
-    };
-
-    mapper.translate_addr(addr)
+    KERNEL_SPACE
+        .lock()
+        .translate(addr)
+        .map(|mapping| mapping.paddr)
 }
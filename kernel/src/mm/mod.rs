@@ -1,4 +1,10 @@
 pub mod alloc;
 pub mod block;
+#[cfg(any(test, feature = "heap-debug"))]
+pub mod debug;
 pub mod frame;
 pub mod memory;
+pub mod page;
+pub mod paging;
+pub mod stack;
+pub mod stats;
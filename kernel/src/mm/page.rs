@@ -0,0 +1,253 @@
+//! Buddy physical page allocator.
+//!
+//! Free blocks are kept in the intrusive free lists of a
+//! [`buddy_system_allocator::Heap`] managing the direct map of the physical
+//! memory, so the allocator never allocates from the kernel heap itself.
+
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::alloc::AllocError;
+use super::memory::PAGE_SHIFT;
+
+pub const PAGE_SIZE: u64 = 1 << PAGE_SHIFT;
+
+/// Largest order, i.e. blocks of 2^18 pages (1 GiB).
+pub const MAX_ORDER: u32 = 18;
+
+/// Number of free lists of the buddy heap: one per power of two up to the
+/// largest block size in bytes.
+const HEAP_ORDER: usize = PAGE_SHIFT + MAX_ORDER as usize + 1;
+
+/// 2^order physically contiguous pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pages {
+    pub paddr: PhysAddr,
+    /// Address of the pages in the direct map.
+    pub vaddr: VirtAddr,
+    pub order: u32,
+}
+
+impl Pages {
+    pub fn count(&self) -> u64 {
+        1 << self.order
+    }
+
+    pub fn size(&self) -> u64 {
+        PAGE_SIZE << self.order
+    }
+}
+
+/// Page allocator statistics, in pages.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PageStats {
+    pub total: u64,
+    pub allocated: u64,
+    pub free: u64,
+}
+
+pub struct PageAllocator {
+    heap: buddy_system_allocator::Heap<HEAP_ORDER>,
+    phys_offset: VirtAddr,
+    /// Largest order whose blocks are aligned the same way in the direct map
+    /// and in physical memory.
+    max_order: u32,
+}
+
+impl PageAllocator {
+    pub const fn empty() -> Self {
+        Self {
+            heap: buddy_system_allocator::Heap::new(),
+            phys_offset: VirtAddr::zero(),
+            max_order: 0,
+        }
+    }
+
+    /// Sets the direct map offset. Must be called before adding memory.
+    pub fn init(&mut self, phys_offset: VirtAddr) {
+        self.phys_offset = phys_offset;
+        self.max_order = match phys_offset.as_u64() {
+            0 => MAX_ORDER,
+            offset => (offset.trailing_zeros() - PAGE_SHIFT as u32).min(MAX_ORDER),
+        };
+    }
+
+    /// Adds the physical range [start, end) to the allocator, shrunk to page
+    /// boundaries.
+    ///
+    /// # Safety
+    ///
+    /// Caller should ensure that the range is unused, is mapped in the direct
+    /// map, and isn't added twice.
+    pub unsafe fn add_range(&mut self, start: PhysAddr, end: PhysAddr) {
+        let start = start.align_up(PAGE_SIZE);
+        let end = end.align_down(PAGE_SIZE);
+        if start >= end {
+            return;
+        }
+
+        let vstart = self.phys_offset + start.as_u64();
+        self.heap.add_to_heap(
+            vstart.as_u64() as usize,
+            (vstart + (end - start)).as_u64() as usize,
+        );
+    }
+
+    /// Allocates 2^order contiguous pages, aligned on their size.
+    pub fn alloc_pages(&mut self, order: u32) -> Result<Pages, AllocError> {
+        let layout = self.layout(order)?;
+        let ptr = self
+            .heap
+            .alloc(layout)
+            .map_err(|_| AllocError::OutOfMemory)?;
+
+        let vaddr = VirtAddr::from_ptr(ptr.as_ptr());
+        Ok(Pages {
+            paddr: PhysAddr::new(vaddr - self.phys_offset),
+            vaddr,
+            order,
+        })
+    }
+
+    /// # Safety
+    ///
+    /// Caller should ensure that pages were returned by alloc_pages, and are
+    /// no longer used.
+    pub unsafe fn free_pages(&mut self, pages: Pages) {
+        let layout = self
+            .layout(pages.order)
+            .expect("pages allocated with an invalid order");
+        let ptr = NonNull::new(pages.vaddr.as_mut_ptr::<u8>()).expect("null pages");
+        self.heap.dealloc(ptr, layout);
+    }
+
+    pub fn stats(&self) -> PageStats {
+        let total = self.heap.stats_total_bytes() as u64 >> PAGE_SHIFT;
+        let allocated = self.heap.stats_alloc_actual() as u64 >> PAGE_SHIFT;
+        PageStats {
+            total,
+            allocated,
+            free: total - allocated,
+        }
+    }
+
+    fn layout(&self, order: u32) -> Result<Layout, AllocError> {
+        if order > self.max_order {
+            return Err(AllocError::TooBig);
+        }
+
+        let size = (PAGE_SIZE as usize) << order;
+        Layout::from_size_align(size, size).map_err(|_| AllocError::LayoutError)
+    }
+}
+
+impl Default for PageAllocator {
+    fn default() -> Self {
+        PageAllocator::empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::alloc::{alloc, dealloc};
+
+    /// Page allocator over a host buffer, with a zero phys_offset.
+    struct Fixture {
+        pages: PageAllocator,
+        buffer: *mut u8,
+        layout: Layout,
+    }
+
+    impl Fixture {
+        fn new(count: usize) -> Self {
+            let size = count * PAGE_SIZE as usize;
+            let layout = Layout::from_size_align(size, size.next_power_of_two()).unwrap();
+            // SAFETY: layout has a non-zero size.
+            let buffer = unsafe { alloc(layout) };
+            assert!(!buffer.is_null());
+
+            let mut pages = PageAllocator::empty();
+            pages.init(VirtAddr::zero());
+            let start = PhysAddr::new(buffer as u64);
+            // SAFETY: the buffer is unused and identity mapped.
+            unsafe { pages.add_range(start, start + size as u64) };
+
+            Self {
+                pages,
+                buffer,
+                layout,
+            }
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            // SAFETY: buffer was allocated with layout.
+            unsafe { dealloc(self.buffer, self.layout) };
+        }
+    }
+
+    #[test]
+    fn alloc_free() {
+        let mut f = Fixture::new(16);
+        assert_eq!(
+            f.pages.stats(),
+            PageStats {
+                total: 16,
+                allocated: 0,
+                free: 16
+            }
+        );
+
+        let block = f.pages.alloc_pages(2).unwrap();
+        assert_eq!(block.count(), 4);
+        assert!(block.paddr.is_aligned(block.size()));
+        assert_eq!(block.vaddr.as_u64(), block.paddr.as_u64());
+
+        let page = f.pages.alloc_pages(0).unwrap();
+        assert!(page.paddr < block.paddr || page.paddr >= block.paddr + block.size());
+        assert_eq!(f.pages.stats().allocated, 5);
+
+        // SAFETY: both were allocated above and are unused.
+        unsafe {
+            f.pages.free_pages(page);
+            f.pages.free_pages(block);
+        }
+        assert_eq!(f.pages.stats().free, 16);
+
+        // Freed buddies are merged back into the whole range.
+        assert!(f.pages.alloc_pages(4).is_ok());
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut f = Fixture::new(4);
+        assert!(matches!(
+            f.pages.alloc_pages(3),
+            Err(AllocError::OutOfMemory)
+        ));
+        for _ in 0..4 {
+            f.pages.alloc_pages(0).unwrap();
+        }
+        assert!(matches!(
+            f.pages.alloc_pages(0),
+            Err(AllocError::OutOfMemory)
+        ));
+        assert!(matches!(
+            f.pages.alloc_pages(MAX_ORDER + 1),
+            Err(AllocError::TooBig)
+        ));
+    }
+
+    #[test]
+    fn max_order_follows_phys_offset_alignment() {
+        let mut pages = PageAllocator::empty();
+        pages.init(VirtAddr::new(0x20_0000));
+        assert!(matches!(pages.alloc_pages(10), Err(AllocError::TooBig)));
+        assert!(matches!(pages.alloc_pages(9), Err(AllocError::OutOfMemory)));
+    }
+}
@@ -0,0 +1,909 @@
+//! Kernel page tables management.
+//!
+//! [`KERNEL_SPACE`] wraps the page tables set up by the bootloader. Tables are
+//! allocated from a [`FrameAllocator`], like [`crate::virt::vmx::ept::Ept`]
+//! tables, and accessed through the linear mapping of the physical memory.
+//!
+//! The PAT is programmed so that the PWT and PCD bits of an entry select its
+//! [`CacheMode`]: write-back, write-combining (PWT), uncacheable minus (PCD)
+//! and uncacheable (PCD | PWT).
+
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+
+use spinning_top::Spinlock;
+use x86_64::{
+    instructions::interrupts,
+    registers::{
+        control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags},
+        model_specific::Msr,
+    },
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::cpu::msr::IA32_PAT;
+
+pub static KERNEL_SPACE: Spinlock<KernelAddressSpace> = Spinlock::new(KernelAddressSpace::empty());
+
+/// PAT entries: WB, WC, UC-, UC, repeated for the entries selected with the
+/// PAT bit.
+const PAT_VALUE: u64 = 0x0007_0106_0007_0106;
+
+/// Virtual range where MMIO regions are mapped by [`KernelAddressSpace::map_mmio`].
+pub const MMIO_START: u64 = 0x_5555_0000_0000;
+pub const MMIO_SIZE: u64 = 1 << 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    /// The frame allocator ran out of frames for a new table.
+    FrameAllocationFailed,
+    /// An address or size isn't aligned to 4KiB.
+    Misaligned,
+    /// Part of the range is already mapped.
+    AlreadyMapped,
+    /// Part of the range isn't mapped.
+    NotMapped,
+    /// The range covers part of a huge page.
+    HugePage,
+    /// The MMIO virtual range is exhausted.
+    OutOfVirtualSpace,
+}
+
+impl<S: PageSize> From<MapToError<S>> for PagingError {
+    fn from(e: MapToError<S>) -> Self {
+        match e {
+            MapToError::FrameAllocationFailed => Self::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => {
+                Self::AlreadyMapped
+            }
+        }
+    }
+}
+
+impl From<UnmapError> for PagingError {
+    fn from(e: UnmapError) -> Self {
+        match e {
+            UnmapError::ParentEntryHugePage => Self::HugePage,
+            UnmapError::PageNotMapped | UnmapError::InvalidFrameAddress(_) => Self::NotMapped,
+        }
+    }
+}
+
+impl From<FlagUpdateError> for PagingError {
+    fn from(e: FlagUpdateError) -> Self {
+        match e {
+            FlagUpdateError::ParentEntryHugePage => Self::HugePage,
+            FlagUpdateError::PageNotMapped => Self::NotMapped,
+        }
+    }
+}
+
+/// Memory type of a mapping, selected through the PAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteCombining,
+    /// Uncacheable, unless overridden by the MTRRs to write-combining.
+    UncacheableMinus,
+    Uncacheable,
+}
+
+impl CacheMode {
+    const MASK: PageTableFlags = PageTableFlags::WRITE_THROUGH.union(PageTableFlags::NO_CACHE);
+
+    const fn flags(self) -> PageTableFlags {
+        match self {
+            Self::WriteBack => PageTableFlags::empty(),
+            Self::WriteCombining => PageTableFlags::WRITE_THROUGH,
+            Self::UncacheableMinus => PageTableFlags::NO_CACHE,
+            Self::Uncacheable => Self::MASK,
+        }
+    }
+
+    fn from_flags(flags: PageTableFlags) -> Self {
+        let pwt = flags.contains(PageTableFlags::WRITE_THROUGH);
+        let pcd = flags.contains(PageTableFlags::NO_CACHE);
+        match (pcd, pwt) {
+            (false, false) => Self::WriteBack,
+            (false, true) => Self::WriteCombining,
+            (true, false) => Self::UncacheableMinus,
+            (true, true) => Self::Uncacheable,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MappingSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl MappingSize {
+    pub const fn size(self) -> u64 {
+        match self {
+            Self::Size4KiB => Size4KiB::SIZE,
+            Self::Size2MiB => Size2MiB::SIZE,
+            Self::Size1GiB => Size1GiB::SIZE,
+        }
+    }
+}
+
+/// Attributes of the leaf entries created by [`KernelAddressSpace::map`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapAttributes {
+    /// Entry flags, e.g. WRITABLE or NO_EXECUTE. PRESENT is always set, and
+    /// the cache bits are set from cache.
+    pub flags: PageTableFlags,
+    pub cache: CacheMode,
+    /// Largest page size the mapping may use. 1GiB pages are only used if the
+    /// CPU supports them.
+    pub max_size: MappingSize,
+}
+
+impl MapAttributes {
+    pub const fn new(flags: PageTableFlags, cache: CacheMode, max_size: MappingSize) -> Self {
+        Self {
+            flags,
+            cache,
+            max_size,
+        }
+    }
+
+    /// Writable, non-executable, write-back memory mapped with 4KiB pages.
+    pub const fn data() -> Self {
+        Self::new(
+            PageTableFlags::WRITABLE.union(PageTableFlags::NO_EXECUTE),
+            CacheMode::WriteBack,
+            MappingSize::Size4KiB,
+        )
+    }
+
+    fn entry_flags(&self) -> PageTableFlags {
+        (self.flags - CacheMode::MASK) | PageTableFlags::PRESENT | self.cache.flags()
+    }
+}
+
+/// Leaf translation of a virtual address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub paddr: PhysAddr,
+    pub size: MappingSize,
+    pub flags: PageTableFlags,
+    pub cache: CacheMode,
+}
+
+/// Page table entries visited to translate an address, from the PML4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageWalk {
+    pub vaddr: VirtAddr,
+    /// Raw entries, up to the leaf or to the first non-present one.
+    pub entries: [Option<u64>; 4],
+}
+
+impl fmt::Display for PageWalk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NAMES: [&str; 4] = ["PML4", "PDPT", "PD", "PT"];
+        let indexes = [
+            self.vaddr.p4_index(),
+            self.vaddr.p3_index(),
+            self.vaddr.p2_index(),
+            self.vaddr.p1_index(),
+        ];
+
+        write!(f, "page walk of {:#x}:", self.vaddr.as_u64())?;
+        for ((name, index), entry) in NAMES.iter().zip(indexes).zip(self.entries) {
+            let Some(entry) = entry else {
+                break;
+            };
+            write!(
+                f,
+                "\n  {:<4}[{:3}] = {:#018x} {:?}",
+                name,
+                u16::from(index),
+                entry,
+                PageTableFlags::from_bits_truncate(entry)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The kernel page tables.
+#[derive(Debug)]
+pub struct KernelAddressSpace {
+    mapper: Option<OffsetPageTable<'static>>,
+    phys_offset: VirtAddr,
+    /// Next free address of the MMIO range.
+    mmio_next: u64,
+}
+
+impl KernelAddressSpace {
+    pub const fn empty() -> Self {
+        Self {
+            mapper: None,
+            phys_offset: VirtAddr::zero(),
+            mmio_next: MMIO_START,
+        }
+    }
+
+    /// Wraps the page tables rooted at level_4_table.
+    ///
+    /// # Safety
+    ///
+    /// Caller should ensure that the whole physical memory is mapped at
+    /// phys_offset, and that level_4_table isn't referenced elsewhere.
+    pub unsafe fn new(level_4_table: &'static mut PageTable, phys_offset: VirtAddr) -> Self {
+        Self {
+            mapper: Some(OffsetPageTable::new(level_4_table, phys_offset)),
+            phys_offset,
+            mmio_next: MMIO_START,
+        }
+    }
+
+    /// Takes over the active page tables and programs the PAT.
+    ///
+    /// # Safety
+    ///
+    /// Caller should ensure that the whole physical memory is mapped at
+    /// phys_offset, and that it is called once.
+    pub unsafe fn init(&mut self, phys_offset: VirtAddr) {
+        let (level_4_table_frame, _) = Cr3::read();
+        let virt = phys_offset + level_4_table_frame.start_address().as_u64();
+        *self = Self::new(&mut *virt.as_mut_ptr::<PageTable>(), phys_offset);
+
+        // The bootloader mappings only use the write-back entry, which is
+        // left unchanged.
+        write_pat(PAT_VALUE);
+    }
+
+    fn mapper(&mut self) -> &mut OffsetPageTable<'static> {
+        self.mapper
+            .as_mut()
+            .expect("kernel address space not initialized")
+    }
+
+    /// Maps [vaddr, vaddr + size) to [paddr, paddr + size), using the largest
+    /// pages up to attrs.max_size allowed by the alignment of both ranges.
+    pub fn map<A: FrameAllocator<Size4KiB>>(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        size: u64,
+        attrs: MapAttributes,
+        frames: &mut A,
+    ) -> Result<(), PagingError> {
+        check_range(vaddr, size)?;
+        if !paddr.is_aligned(Size4KiB::SIZE) {
+            return Err(PagingError::Misaligned);
+        }
+
+        let max_size = match attrs.max_size {
+            MappingSize::Size1GiB if !huge_pages_supported() => MappingSize::Size2MiB,
+            max_size => max_size,
+        };
+        let flags = attrs.entry_flags();
+
+        let mut offset = 0;
+        while offset < size {
+            let (va, pa) = (vaddr + offset, paddr + offset);
+            let page_size = [MappingSize::Size1GiB, MappingSize::Size2MiB]
+                .into_iter()
+                .find(|&s| {
+                    s <= max_size
+                        && va.is_aligned(s.size())
+                        && pa.is_aligned(s.size())
+                        && size - offset >= s.size()
+                })
+                .unwrap_or(MappingSize::Size4KiB);
+
+            // SAFETY: the caller owns the physical range it maps.
+            let result = unsafe {
+                match page_size {
+                    MappingSize::Size4KiB => self.map_page::<Size4KiB, A>(va, pa, flags, frames),
+                    MappingSize::Size2MiB => self.map_page::<Size2MiB, A>(va, pa, flags, frames),
+                    MappingSize::Size1GiB => self.map_page::<Size1GiB, A>(va, pa, flags, frames),
+                }
+            };
+            if let Err(e) = result {
+                // Exactly the pages mapped so far are unmapped.
+                self.unmap(vaddr, offset)
+                    .expect("failed to roll back a partial mapping");
+                return Err(e);
+            }
+            offset += page_size.size();
+        }
+
+        Ok(())
+    }
+
+    /// Maps [vaddr, vaddr + size) to newly allocated 4KiB frames. On failure,
+    /// nothing stays mapped, and the frames are freed.
+    pub fn map_anonymous<A>(
+        &mut self,
+        vaddr: VirtAddr,
+        size: u64,
+        attrs: MapAttributes,
+        frames: &mut A,
+    ) -> Result<(), PagingError>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+    {
+        check_range(vaddr, size)?;
+        let flags = attrs.entry_flags();
+
+        for offset in (0..size).step_by(Size4KiB::SIZE as usize) {
+            let result = match frames.allocate_frame() {
+                // SAFETY: the frame was just allocated, and is freed if it
+                // isn't mapped.
+                Some(frame) => unsafe {
+                    self.map_page::<Size4KiB, A>(
+                        vaddr + offset,
+                        frame.start_address(),
+                        flags,
+                        frames,
+                    )
+                    .inspect_err(|_| frames.deallocate_frame(frame))
+                },
+                None => Err(PagingError::FrameAllocationFailed),
+            };
+            if let Err(e) = result {
+                // Exactly the pages mapped so far are unmapped.
+                self.unmap_anonymous(vaddr, offset, frames)
+                    .expect("failed to roll back a partial mapping");
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unmaps [vaddr, vaddr + size). The mapped frames aren't freed, and huge
+    /// pages must be fully covered by the range.
+    pub fn unmap(&mut self, vaddr: VirtAddr, size: u64) -> Result<(), PagingError> {
+        self.for_each_leaf(vaddr, size, |mapper, va, mapping| {
+            match mapping {
+                MappingSize::Size4KiB => flush(
+                    mapper
+                        .unmap(Page::<Size4KiB>::from_start_address(va).unwrap())?
+                        .1,
+                ),
+                MappingSize::Size2MiB => flush(
+                    mapper
+                        .unmap(Page::<Size2MiB>::from_start_address(va).unwrap())?
+                        .1,
+                ),
+                MappingSize::Size1GiB => flush(
+                    mapper
+                        .unmap(Page::<Size1GiB>::from_start_address(va).unwrap())?
+                        .1,
+                ),
+            }
+            Ok(())
+        })
+    }
+
+    /// Unmaps [vaddr, vaddr + size), mapped with 4KiB pages, and frees the
+    /// mapped frames.
+    pub fn unmap_anonymous<D: FrameDeallocator<Size4KiB>>(
+        &mut self,
+        vaddr: VirtAddr,
+        size: u64,
+        frames: &mut D,
+    ) -> Result<(), PagingError> {
+        self.for_each_leaf(vaddr, size, |mapper, va, mapping| {
+            if mapping != MappingSize::Size4KiB {
+                return Err(PagingError::HugePage);
+            }
+            let (frame, flush_tlb) =
+                mapper.unmap(Page::<Size4KiB>::from_start_address(va).unwrap())?;
+            flush(flush_tlb);
+            // SAFETY: the frame is no longer mapped.
+            unsafe { frames.deallocate_frame(frame) };
+            Ok(())
+        })
+    }
+
+    /// Changes the flags and cache mode of [vaddr, vaddr + size). Huge pages
+    /// must be fully covered by the range.
+    pub fn protect(
+        &mut self,
+        vaddr: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+        cache: CacheMode,
+    ) -> Result<(), PagingError> {
+        let flags = MapAttributes::new(flags, cache, MappingSize::Size4KiB).entry_flags();
+        self.for_each_leaf(vaddr, size, |mapper, va, mapping| {
+            // SAFETY: the caller is responsible for the new flags.
+            unsafe {
+                match mapping {
+                    MappingSize::Size4KiB => {
+                        flush(mapper.update_flags(
+                            Page::<Size4KiB>::from_start_address(va).unwrap(),
+                            flags,
+                        )?)
+                    }
+                    MappingSize::Size2MiB => {
+                        flush(mapper.update_flags(
+                            Page::<Size2MiB>::from_start_address(va).unwrap(),
+                            flags,
+                        )?)
+                    }
+                    MappingSize::Size1GiB => {
+                        flush(mapper.update_flags(
+                            Page::<Size1GiB>::from_start_address(va).unwrap(),
+                            flags,
+                        )?)
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Maps the MMIO range [paddr, paddr + size) in the MMIO virtual range,
+    /// writable and non-executable, and returns the address of paddr.
+    ///
+    /// Virtual addresses aren't reused after [`KernelAddressSpace::unmap_mmio`].
+    pub fn map_mmio<A: FrameAllocator<Size4KiB>>(
+        &mut self,
+        paddr: PhysAddr,
+        size: u64,
+        cache: CacheMode,
+        frames: &mut A,
+    ) -> Result<VirtAddr, PagingError> {
+        let start = paddr.align_down(Size4KiB::SIZE);
+        let size = (paddr + size).align_up(Size4KiB::SIZE) - start;
+
+        // Keep the 2MiB alignment of the physical range, so that large ranges
+        // can use huge pages.
+        let align = Size2MiB::SIZE;
+        let vstart = self.mmio_next.next_multiple_of(align) + start.as_u64() % align;
+        if vstart + size > MMIO_START + MMIO_SIZE {
+            return Err(PagingError::OutOfVirtualSpace);
+        }
+
+        let attrs = MapAttributes::new(
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            cache,
+            MappingSize::Size2MiB,
+        );
+        self.map(VirtAddr::new(vstart), start, size, attrs, frames)?;
+        self.mmio_next = vstart + size;
+
+        Ok(VirtAddr::new(vstart + (paddr - start)))
+    }
+
+    /// Unmaps a range mapped by [`KernelAddressSpace::map_mmio`].
+    pub fn unmap_mmio(&mut self, vaddr: VirtAddr, size: u64) -> Result<(), PagingError> {
+        let start = vaddr.align_down(Size4KiB::SIZE);
+        let end = (vaddr + size).align_up(Size4KiB::SIZE);
+        self.unmap(start, end - start)
+    }
+
+    pub fn translate(&mut self, vaddr: VirtAddr) -> Option<Mapping> {
+        let TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } = self.mapper().translate(vaddr)
+        else {
+            return None;
+        };
+
+        let size = match frame {
+            MappedFrame::Size4KiB(_) => MappingSize::Size4KiB,
+            MappedFrame::Size2MiB(_) => MappingSize::Size2MiB,
+            MappedFrame::Size1GiB(_) => MappingSize::Size1GiB,
+        };
+        Some(Mapping {
+            paddr: frame.start_address() + offset,
+            size,
+            flags,
+            cache: CacheMode::from_flags(flags),
+        })
+    }
+
+    /// Returns the entries of every level translating vaddr.
+    pub fn walk(&mut self, vaddr: VirtAddr) -> PageWalk {
+        let mut walk = PageWalk {
+            vaddr,
+            entries: [None; 4],
+        };
+        let indexes = [
+            vaddr.p4_index(),
+            vaddr.p3_index(),
+            vaddr.p2_index(),
+            vaddr.p1_index(),
+        ];
+
+        let phys_offset = self.phys_offset;
+        let mut table: &PageTable = self.mapper().level_4_table();
+        for (level, index) in indexes.into_iter().enumerate() {
+            let entry = &table[index];
+            let flags = entry.flags();
+            walk.entries[level] = Some(entry.addr().as_u64() | flags.bits());
+
+            if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                break;
+            }
+            let next = phys_offset + entry.addr().as_u64();
+            // SAFETY: the entry points to the next level table, reached
+            // through the linear mapping.
+            table = unsafe { &*next.as_ptr::<PageTable>() };
+        }
+
+        walk
+    }
+
+    /// Logs the entries of every level translating vaddr.
+    pub fn dump(&mut self, vaddr: VirtAddr) {
+        log::info!("{}", self.walk(vaddr));
+    }
+
+    /// # Safety
+    ///
+    /// Caller should ensure that mapping paddr at vaddr doesn't break memory
+    /// safety.
+    unsafe fn map_page<S, A>(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        flags: PageTableFlags,
+        frames: &mut A,
+    ) -> Result<(), PagingError>
+    where
+        S: PageSize,
+        A: FrameAllocator<Size4KiB>,
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        let page = Page::<S>::from_start_address(vaddr).map_err(|_| PagingError::Misaligned)?;
+        let frame =
+            PhysFrame::<S>::from_start_address(paddr).map_err(|_| PagingError::Misaligned)?;
+        flush(self.mapper().map_to(page, frame, flags, frames)?);
+        Ok(())
+    }
+
+    /// Calls f on the address and size of every leaf mapping covering
+    /// [vaddr, vaddr + size), which must fully cover huge pages.
+    fn for_each_leaf<F>(&mut self, vaddr: VirtAddr, size: u64, mut f: F) -> Result<(), PagingError>
+    where
+        F: FnMut(&mut OffsetPageTable<'static>, VirtAddr, MappingSize) -> Result<(), PagingError>,
+    {
+        check_range(vaddr, size)?;
+
+        let end = vaddr + size;
+        let mut va = vaddr;
+        while va < end {
+            let mapping = self.translate(va).ok_or(PagingError::NotMapped)?;
+            let page_size = mapping.size.size();
+            if !va.is_aligned(page_size) || end - va < page_size {
+                return Err(PagingError::HugePage);
+            }
+
+            f(self.mapper(), va, mapping.size)?;
+            va += page_size;
+        }
+
+        Ok(())
+    }
+}
+
+/// Flushes the TLB entry of a changed mapping. Host tests can't run invlpg,
+/// and their tables are never active.
+fn flush<S: PageSize>(flush: x86_64::structures::paging::mapper::MapperFlush<S>) {
+    #[cfg(not(test))]
+    flush.flush();
+    #[cfg(test)]
+    flush.ignore();
+}
+
+/// Programs the PAT while the caches may hold lines of the old memory types,
+/// following Intel SDM Vol. 3A, Section 11.12.4: caching is disabled and the
+/// caches and TLBs flushed around the write.
+///
+/// # Safety
+///
+/// Caller should ensure that the memory types of the live mappings stay
+/// consistent with the new PAT.
+unsafe fn write_pat(value: u64) {
+    interrupts::without_interrupts(|| unsafe {
+        let cr0 = Cr0::read();
+        Cr0::write((cr0 | Cr0Flags::CACHE_DISABLE) - Cr0Flags::NOT_WRITE_THROUGH);
+        asm!("wbinvd", options(nostack, preserves_flags));
+        flush_all_global();
+
+        Msr::new(IA32_PAT).write(value);
+
+        asm!("wbinvd", options(nostack, preserves_flags));
+        flush_all_global();
+        Cr0::write(cr0);
+    });
+}
+
+/// Flushes the TLBs, global entries included, by toggling CR4.PGE.
+unsafe fn flush_all_global() {
+    let cr4 = Cr4::read();
+    if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+        unsafe {
+            Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+            Cr4::write(cr4);
+        }
+    } else {
+        x86_64::instructions::tlb::flush_all();
+    }
+}
+
+/// Returns true if the CPU supports 1GiB pages.
+fn huge_pages_supported() -> bool {
+    // CPUID.80000001H:EDX.Page1GB[bit 26]
+    __cpuid(0x8000_0001).edx & (1 << 26) != 0
+}
+
+fn check_range(vaddr: VirtAddr, size: u64) -> Result<(), PagingError> {
+    if !vaddr.is_aligned(Size4KiB::SIZE) || !size.is_multiple_of(Size4KiB::SIZE) {
+        return Err(PagingError::Misaligned);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{boxed::Box, vec::Vec};
+
+    /// Hands out heap pages, whose "physical" address is their host virtual
+    /// address, so the tables are used with a zero phys_offset.
+    #[derive(Default)]
+    struct FakeFrames {
+        frames: Vec<Box<PageTable>>,
+        freed: Vec<PhysAddr>,
+    }
+
+    unsafe impl FrameAllocator<Size4KiB> for FakeFrames {
+        fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+            let mut table = Box::new(PageTable::new());
+            let addr = PhysAddr::new(&mut *table as *mut PageTable as u64);
+            self.frames.push(table);
+            PhysFrame::from_start_address(addr).ok()
+        }
+    }
+
+    impl FrameDeallocator<Size4KiB> for FakeFrames {
+        unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+            self.freed.push(frame.start_address());
+        }
+    }
+
+    fn space() -> KernelAddressSpace {
+        let pml4 = Box::leak(Box::new(PageTable::new()));
+        // SAFETY: the tables are host memory identity "mapped".
+        unsafe { KernelAddressSpace::new(pml4, VirtAddr::zero()) }
+    }
+
+    const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+    const MIB2: u64 = Size2MiB::SIZE;
+    const VA: VirtAddr = VirtAddr::new_truncate(0x4000_0000_0000);
+    const PA: PhysAddr = PhysAddr::new_truncate(0x1_0000_0000);
+
+    #[test]
+    fn map_translate() {
+        let mut frames = FakeFrames::default();
+        let mut space = space();
+        let attrs = MapAttributes::new(
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            CacheMode::Uncacheable,
+            MappingSize::Size4KiB,
+        );
+        space.map(VA, PA, 0x3000, attrs, &mut frames).unwrap();
+
+        let mapping = space.translate(VA + 0x2010u64).unwrap();
+        assert_eq!(mapping.paddr, PA + 0x2010u64);
+        assert_eq!(mapping.size, MappingSize::Size4KiB);
+        assert_eq!(mapping.cache, CacheMode::Uncacheable);
+        assert!(mapping.flags.contains(PageTableFlags::NO_EXECUTE));
+        assert!(space.translate(VA + 0x3000u64).is_none());
+
+        assert_eq!(
+            space.map(VA + 0x2000u64, PA, 0x1000, attrs, &mut frames),
+            Err(PagingError::AlreadyMapped)
+        );
+        assert_eq!(
+            space.map(VA + 0x10u64, PA, 0x1000, attrs, &mut frames),
+            Err(PagingError::Misaligned)
+        );
+    }
+
+    #[test]
+    fn huge_pages() {
+        let mut frames = FakeFrames::default();
+        let mut space = space();
+        let attrs = MapAttributes::new(
+            PageTableFlags::WRITABLE,
+            CacheMode::WriteBack,
+            MappingSize::Size2MiB,
+        );
+        space
+            .map(VA, PA, 2 * MIB2 + 0x1000, attrs, &mut frames)
+            .unwrap();
+
+        let mapping = space.translate(VA + MIB2 + 0x1234u64).unwrap();
+        assert_eq!(mapping.size, MappingSize::Size2MiB);
+        assert_eq!(mapping.paddr, PA + MIB2 + 0x1234u64);
+        assert_eq!(
+            space.translate(VA + 2 * MIB2).unwrap().size,
+            MappingSize::Size4KiB
+        );
+
+        // Partially covered huge pages aren't split.
+        assert_eq!(space.unmap(VA, 0x1000), Err(PagingError::HugePage));
+        space.unmap(VA, MIB2).unwrap();
+        assert!(space.translate(VA).is_none());
+        assert!(space.translate(VA + MIB2).is_some());
+        assert_eq!(space.unmap(VA, MIB2), Err(PagingError::NotMapped));
+    }
+
+    #[test]
+    fn anonymous() {
+        let mut frames = FakeFrames::default();
+        let mut space = space();
+        space
+            .map_anonymous(VA, 0x2000, MapAttributes::data(), &mut frames)
+            .unwrap();
+        let data = [VA, VA + 0x1000u64].map(|va| space.translate(va).unwrap().paddr);
+        assert_ne!(data[0], data[1]);
+
+        space.unmap_anonymous(VA, 0x2000, &mut frames).unwrap();
+        assert_eq!(frames.freed, data);
+        assert!(space.translate(VA).is_none());
+
+        // Page tables aren't freed, so huge pages go to the next 2MiB range.
+        let huge = MapAttributes::new(
+            PageTableFlags::empty(),
+            CacheMode::WriteBack,
+            MappingSize::Size2MiB,
+        );
+        space.map(VA + MIB2, PA, MIB2, huge, &mut frames).unwrap();
+        assert_eq!(
+            space.unmap_anonymous(VA + MIB2, MIB2, &mut frames),
+            Err(PagingError::HugePage)
+        );
+    }
+
+    #[test]
+    fn failed_map_rolls_back() {
+        let mut frames = FakeFrames::default();
+        let mut space = space();
+        let attrs = MapAttributes::data();
+        space
+            .map(VA + 0x2000u64, PA, 0x1000, attrs, &mut frames)
+            .unwrap();
+        let unmapped = |space: &mut KernelAddressSpace| {
+            [0x0u64, 0x1000, 0x3000]
+                .iter()
+                .all(|&offset| space.translate(VA + offset).is_none())
+        };
+
+        // The third page of the ranges is already mapped.
+        assert_eq!(
+            space.map(VA, PA + 0x10000u64, 0x4000, attrs, &mut frames),
+            Err(PagingError::AlreadyMapped)
+        );
+        assert!(unmapped(&mut space));
+        assert_eq!(space.translate(VA + 0x2000u64).unwrap().paddr, PA);
+
+        assert_eq!(
+            space.map_anonymous(VA, 0x4000, attrs, &mut frames),
+            Err(PagingError::AlreadyMapped)
+        );
+        assert!(unmapped(&mut space));
+        // The frame of the third page, then the ones of the mapped pages.
+        let data: Vec<_> = frames.frames[frames.frames.len() - 3..]
+            .iter()
+            .map(|table| PhysAddr::new(&**table as *const PageTable as u64))
+            .collect();
+        assert_eq!(frames.freed, [data[2], data[0], data[1]]);
+
+        // The MMIO range isn't left half mapped, and is used again.
+        let blocker = VirtAddr::new(MMIO_START + 0x1000);
+        space.map(blocker, PA, 0x1000, attrs, &mut frames).unwrap();
+        let bar = PhysAddr::new(0xc000_0000);
+        assert_eq!(
+            space.map_mmio(bar, 0x2000, CacheMode::Uncacheable, &mut frames),
+            Err(PagingError::AlreadyMapped)
+        );
+        assert!(space.translate(VirtAddr::new(MMIO_START)).is_none());
+        space.unmap(blocker, 0x1000).unwrap();
+        assert_eq!(
+            space.map_mmio(bar, 0x2000, CacheMode::Uncacheable, &mut frames),
+            Ok(VirtAddr::new(MMIO_START))
+        );
+    }
+
+    #[test]
+    fn protect() {
+        let mut frames = FakeFrames::default();
+        let mut space = space();
+        space
+            .map(VA, PA, 0x2000, MapAttributes::data(), &mut frames)
+            .unwrap();
+
+        space
+            .protect(
+                VA,
+                0x1000,
+                PageTableFlags::empty(),
+                CacheMode::WriteCombining,
+            )
+            .unwrap();
+        let mapping = space.translate(VA).unwrap();
+        assert!(!mapping.flags.contains(PageTableFlags::WRITABLE));
+        assert_eq!(mapping.cache, CacheMode::WriteCombining);
+        assert_eq!(
+            space.translate(VA + 0x1000u64).unwrap().cache,
+            CacheMode::WriteBack
+        );
+    }
+
+    #[test]
+    fn walk() {
+        let mut frames = FakeFrames::default();
+        let mut space = space();
+        space
+            .map(VA, PA, 0x1000, MapAttributes::data(), &mut frames)
+            .unwrap();
+
+        let walk = space.walk(VA);
+        assert!(walk.entries.iter().all(Option::is_some));
+        assert_eq!(walk.entries[3].unwrap() & ADDR_MASK, PA.as_u64());
+        let dump = alloc::format!("{}", walk);
+        assert!(dump.starts_with("page walk of 0x400000000000:\n  PML4[128] = "));
+        assert!(dump.contains("\n  PT  [  0] = 0x8000000100000003"));
+
+        let walk = space.walk(VA + MIB2);
+        assert!(walk.entries[2].is_some());
+        assert!(walk.entries[3].is_none());
+    }
+
+    #[test]
+    fn mmio() {
+        let mut frames = FakeFrames::default();
+        let mut space = space();
+
+        let apic = space
+            .map_mmio(
+                PhysAddr::new(0xfee0_0020),
+                4,
+                CacheMode::Uncacheable,
+                &mut frames,
+            )
+            .unwrap();
+        assert_eq!(apic.as_u64() & 0xfff, 0x20);
+        let mapping = space.translate(apic).unwrap();
+        assert_eq!(mapping.paddr.as_u64(), 0xfee0_0020);
+        assert_eq!(mapping.cache, CacheMode::Uncacheable);
+        assert!(mapping.flags.contains(PageTableFlags::NO_EXECUTE));
+
+        let bar = space
+            .map_mmio(
+                PhysAddr::new(0xc000_0000),
+                2 * MIB2,
+                CacheMode::WriteCombining,
+                &mut frames,
+            )
+            .unwrap();
+        assert!(bar.is_aligned(MIB2));
+        assert_eq!(space.translate(bar).unwrap().size, MappingSize::Size2MiB);
+
+        space.unmap_mmio(apic, 4).unwrap();
+        assert!(space.translate(apic).is_none());
+    }
+}
@@ -0,0 +1,126 @@
+//! Kernel stacks with guard pages.
+//!
+//! Stacks live in a dedicated virtual range, split in [`STACK_SLOT_SIZE`]
+//! slots. Each stack is mapped at the top of its slot, and the rest of the
+//! slot is left unmapped, so that an overflow faults on a guard page instead
+//! of corrupting the memory below the stack. The first slot holds the boot
+//! stack, mapped there by the bootloader.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::VirtAddr;
+
+use super::frame::FRAMES;
+use super::page::PAGE_SIZE;
+use super::paging::{KernelAddressSpace, MapAttributes, PagingError, KERNEL_SPACE};
+
+pub const STACKS_START: u64 = 0x_6666_0000_0000;
+pub const STACKS_SIZE: u64 = 1 << 39;
+/// Virtual space of a stack and its guard pages.
+pub const STACK_SLOT_SIZE: u64 = 1 << 20;
+
+/// Address of the boot stack guard page. The bootloader maps the boot stack
+/// right above it.
+pub const BOOT_STACK_GUARD: u64 = STACKS_START;
+
+/// Next free slot, the first one being used by the boot stack.
+static NEXT_SLOT: AtomicU64 = AtomicU64::new(1);
+
+/// Returns true if addr is in the stacks range. Faults there are stack
+/// overflows, since the only unmapped pages are guard pages and freed stacks.
+pub fn is_stack_guard(addr: VirtAddr) -> bool {
+    (STACKS_START..STACKS_START + STACKS_SIZE).contains(&addr.as_u64())
+}
+
+/// Makes sure that the page below the boot stack isn't mapped.
+pub fn init_boot_stack_guard(space: &mut KernelAddressSpace) -> Result<(), PagingError> {
+    let guard = VirtAddr::new(BOOT_STACK_GUARD);
+    if space.translate(guard).is_some() {
+        log::error!("Boot stack guard page {:#x} is mapped", guard.as_u64());
+        space.unmap(guard, PAGE_SIZE)?;
+    }
+    Ok(())
+}
+
+/// A stack mapped in the stacks range, with a guard page below it.
+#[derive(Debug)]
+pub struct KernelStack {
+    bottom: VirtAddr,
+    size: u64,
+}
+
+impl KernelStack {
+    /// Maps a stack of size bytes, rounded up to pages.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack and its guard page don't fit in a slot.
+    pub fn new(size: usize) -> Result<Self, PagingError> {
+        let size = (size as u64).next_multiple_of(PAGE_SIZE);
+        assert!(
+            size > 0 && size <= STACK_SLOT_SIZE - PAGE_SIZE,
+            "invalid stack size {:#x}",
+            size
+        );
+
+        let slot = NEXT_SLOT.fetch_add(1, Ordering::Relaxed);
+        if slot >= STACKS_SIZE / STACK_SLOT_SIZE {
+            return Err(PagingError::OutOfVirtualSpace);
+        }
+        let bottom = VirtAddr::new(STACKS_START + (slot + 1) * STACK_SLOT_SIZE - size);
+
+        let mut space = KERNEL_SPACE.lock();
+        let mut frames = FRAMES.lock();
+        let mut mapped = 0;
+        let result = (0..size)
+            .step_by(PAGE_SIZE as usize)
+            .try_for_each(|offset| {
+                space.map_anonymous(
+                    bottom + offset,
+                    PAGE_SIZE,
+                    MapAttributes::data(),
+                    &mut *frames,
+                )?;
+                mapped += PAGE_SIZE;
+                Ok(())
+            });
+        if let Err(e) = result {
+            space
+                .unmap_anonymous(bottom, mapped, &mut *frames)
+                .expect("failed to unmap a partial stack");
+            return Err(e);
+        }
+
+        Ok(Self { bottom, size })
+    }
+
+    /// Returns the initial stack pointer.
+    pub fn top(&self) -> VirtAddr {
+        self.bottom + self.size
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    /// Returns the address of the guard page right below the stack.
+    pub fn guard(&self) -> VirtAddr {
+        self.bottom - PAGE_SIZE
+    }
+
+    /// Keeps the stack mapped forever, e.g. for the TSS stacks.
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top();
+        core::mem::forget(self);
+        top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut space = KERNEL_SPACE.lock();
+        space
+            .unmap_anonymous(self.bottom, self.size, &mut *FRAMES.lock())
+            .expect("failed to unmap a kernel stack");
+    }
+}
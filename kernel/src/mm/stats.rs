@@ -0,0 +1,168 @@
+//! Allocator statistics snapshots, to dump the allocators state and to check
+//! that some code leaks nothing.
+//!
+//! ```ignore
+//! let before = stats::snapshot();
+//! create_and_destroy_vm()?;
+//! let leaks = stats::snapshot().diff(&before);
+//! assert!(leaks.is_empty(), "leaked {}", leaks);
+//! ```
+
+use core::fmt;
+
+use super::alloc::block_stats;
+use super::block::{BlockStats, BLOCK_SIZES};
+use super::memory::{HeapStats, ROOT_MEM};
+use super::page::PageStats;
+
+/// Statistics of every kernel allocator at a point in time.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllocSnapshot {
+    pub blocks: BlockStats,
+    pub heap: HeapStats,
+    pub pages: PageStats,
+}
+
+/// Takes a snapshot of the global block allocator and of ROOT_MEM.
+///
+/// With heap-debug, the quarantine is flushed first, so that freed
+/// allocations aren't counted as live.
+pub fn snapshot() -> AllocSnapshot {
+    #[cfg(feature = "heap-debug")]
+    super::alloc::flush_quarantine();
+
+    let blocks = block_stats();
+    let root_mem = ROOT_MEM.lock();
+    AllocSnapshot {
+        blocks,
+        heap: root_mem.heap_stats(),
+        pages: root_mem.page_stats(),
+    }
+}
+
+impl AllocSnapshot {
+    /// Returns the live allocations made since before, and not freed.
+    ///
+    /// Memory cached by the allocators (free blocks, heap growth, page
+    /// refills) isn't counted as a leak.
+    pub fn diff(&self, before: &AllocSnapshot) -> AllocDiff {
+        let delta = |now: usize, before: usize| now as isize - before as isize;
+
+        let mut live_blocks = [0; BLOCK_SIZES.len()];
+        for (i, live) in live_blocks.iter_mut().enumerate() {
+            *live = delta(self.blocks.live_blocks[i], before.blocks.live_blocks[i]);
+        }
+
+        AllocDiff {
+            allocated: delta(self.blocks.allocated, before.blocks.allocated),
+            live_blocks,
+            live_large: delta(self.blocks.live_large, before.blocks.live_large),
+            pages: self.pages.allocated as i64 - before.pages.allocated as i64,
+        }
+    }
+
+    /// Logs the statistics.
+    pub fn dump(&self) {
+        let blocks = &self.blocks;
+        log::info!(
+            "blocks: allocated={} high_watermark={} large={} failed={} fallbacks={}",
+            blocks.allocated,
+            blocks.high_watermark,
+            blocks.live_large,
+            blocks.failed,
+            blocks.fallbacks
+        );
+        for (size, live) in BLOCK_SIZES.iter().zip(blocks.live_blocks) {
+            if live > 0 {
+                log::info!("blocks: {:>4}B x {}", size, live);
+            }
+        }
+
+        let heap = &self.heap;
+        log::info!(
+            "heap: size={} max_size={} used={} free={} high_watermark={} live={} failed={} grows={}",
+            heap.size,
+            heap.max_size,
+            heap.used,
+            heap.free,
+            heap.high_watermark,
+            heap.live,
+            heap.failed,
+            heap.grows
+        );
+
+        let pages = &self.pages;
+        log::info!(
+            "pages: total={} allocated={} free={}",
+            pages.total,
+            pages.allocated,
+            pages.free
+        );
+    }
+}
+
+/// Difference of the live allocations between two snapshots.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllocDiff {
+    /// Bytes requested by the live allocations.
+    pub allocated: isize,
+    /// Live blocks per size class, in BLOCK_SIZES order.
+    pub live_blocks: [isize; BLOCK_SIZES.len()],
+    pub live_large: isize,
+    /// Allocated pages.
+    pub pages: i64,
+}
+
+impl AllocDiff {
+    /// Returns true if no allocation was leaked, nor freed.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl fmt::Display for AllocDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:+} bytes", self.allocated)?;
+        for (size, live) in BLOCK_SIZES.iter().zip(self.live_blocks) {
+            if live != 0 {
+                write!(f, ", {:+} x {}B blocks", live, size)?;
+            }
+        }
+        if self.live_large != 0 {
+            write!(f, ", {:+} large allocations", self.live_large)?;
+        }
+        if self.pages != 0 {
+            write!(f, ", {:+} pages", self.pages)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_ignores_cached_memory() {
+        let mut before = AllocSnapshot::default();
+        before.blocks.live_large = 1;
+        let mut after = before;
+        after.heap.size += 0x4_0000;
+        after.heap.grows += 1;
+        after.pages.total += 512;
+        after.blocks.high_watermark = 64;
+        assert!(after.diff(&before).is_empty());
+
+        after.blocks.allocated = 40;
+        after.blocks.live_blocks[2] = 1;
+        after.blocks.live_large = 0;
+        after.pages.allocated = 2;
+        let diff = after.diff(&before);
+        assert!(!diff.is_empty());
+        assert_eq!(diff.live_large, -1);
+        assert_eq!(
+            diff.to_string(),
+            "+40 bytes, +1 x 64B blocks, -1 large allocations, +2 pages"
+        );
+    }
+}
@@ -0,0 +1,303 @@
+//! In-kernel test harness.
+//!
+//! Tests are registered with [`kernel_test!`](crate::kernel_test), which
+//! places a [`KernelTest`] descriptor in the `kernel_tests` linker section,
+//! and are run by [`run_tests`] from the `ktest` kernel binary.
+//!
+//! Results are printed over serial, one line per event:
+//!
+//! ```text
+//! [TEST] running <count> tests
+//! [TEST] start <name>
+//! [TEST] ok <name>
+//! [TEST] fail <name>: <message>
+//! [TEST] skip <name>: <reason>
+//! [TEST] result: passed=<n> failed=<n> skipped=<n>
+//! ```
+//!
+//! Messages and reasons are kept on their line: line breaks are escaped as
+//! `\n` and `\r`, and backslashes as `\\`.
+//!
+//! A panic fails the running test and ends the run, since the kernel can't
+//! unwind. QEMU is then shut down through the isa-debug-exit device with
+//! [`QemuExitCode::Success`] only if no test failed.
+
+use alloc::{format, string::String};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+use spin::Mutex;
+
+use crate::io::outl;
+use crate::logger::_log;
+
+/// I/O port of the isa-debug-exit device (`-device isa-debug-exit,iobase=0xf4,iosize=0x04`).
+pub const QEMU_EXIT_PORT: u16 = 0xf4;
+
+/// Values written to the isa-debug-exit device. QEMU exits with
+/// `(value << 1) | 1`, i.e. 33 for success and 35 for failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failure = 0x11,
+}
+
+/// Shuts QEMU down with code, if the isa-debug-exit device is present.
+pub fn exit_qemu(code: QemuExitCode) {
+    // SAFETY: writing to the isa-debug-exit port has no other side effect.
+    unsafe { outl(QEMU_EXIT_PORT, code as u32) };
+}
+
+/// Outcome of a single test.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    /// The test doesn't apply to this CPU (e.g. missing VMX capability).
+    Skipped(&'static str),
+}
+
+/// Types a test function may return, like `std::process::Termination`.
+pub trait Termination {
+    fn report(self) -> Outcome;
+}
+
+impl Termination for () {
+    fn report(self) -> Outcome {
+        Outcome::Passed
+    }
+}
+
+impl Termination for Outcome {
+    fn report(self) -> Outcome {
+        self
+    }
+}
+
+impl<T: Termination, E: fmt::Debug> Termination for Result<T, E> {
+    fn report(self) -> Outcome {
+        match self {
+            Ok(value) => value.report(),
+            Err(e) => Outcome::Failed(format!("{:?}", e)),
+        }
+    }
+}
+
+/// Test descriptor, as stored in the `kernel_tests` section.
+#[derive(Debug)]
+#[repr(C)]
+pub struct KernelTest {
+    pub name: &'static str,
+    pub func: fn() -> Outcome,
+}
+
+/// Registers test functions with the in-kernel harness.
+///
+/// ```ignore
+/// kernel_test! {
+///     fn vmxon_succeeds() -> Result<(), VirtError> {
+///         ...
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! kernel_test {
+    ($($(#[$meta:meta])* fn $name:ident() $(-> $ret:ty)? $body:block)*) => {
+        $(
+            $(#[$meta])*
+            fn $name() $(-> $ret)? $body
+
+            const _: () = {
+                fn run() -> $crate::testing::Outcome {
+                    $crate::testing::Termination::report($name())
+                }
+
+                #[used]
+                #[link_section = "kernel_tests"]
+                static TEST: $crate::testing::KernelTest = $crate::testing::KernelTest {
+                    name: concat!(module_path!(), "::", stringify!($name)),
+                    func: run,
+                };
+            };
+        )*
+    };
+}
+
+/// Displays a message on a single line, with its line breaks and backslashes
+/// escaped.
+struct OneLine<T>(T);
+
+/// Escapes what is written to the inner formatter.
+struct EscapeWriter<'a, 'b>(&'a mut fmt::Formatter<'b>);
+
+impl Write for EscapeWriter<'_, '_> {
+    fn write_str(&mut self, mut s: &str) -> fmt::Result {
+        while let Some(i) = s.find(['\\', '\n', '\r']) {
+            self.0.write_str(&s[..i])?;
+            self.0.write_str(match s.as_bytes()[i] {
+                b'\n' => "\\n",
+                b'\r' => "\\r",
+                _ => "\\\\",
+            })?;
+            s = &s[i + 1..];
+        }
+        self.0.write_str(s)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for OneLine<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(EscapeWriter(f), "{}", self.0)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Summary {
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+#[derive(Debug)]
+struct State {
+    current: Option<&'static str>,
+    summary: Summary,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    current: None,
+    summary: Summary {
+        passed: 0,
+        failed: 0,
+        skipped: 0,
+    },
+});
+
+/// Returns the tests registered in the `kernel_tests` section.
+#[cfg(not(test))]
+pub fn tests() -> &'static [KernelTest] {
+    extern "C" {
+        static __start_kernel_tests: u8;
+        static __stop_kernel_tests: u8;
+    }
+
+    // SAFETY: the linker defines both symbols around the kernel_tests
+    // section, which only holds KernelTest descriptors.
+    unsafe {
+        let start = core::ptr::addr_of!(__start_kernel_tests).cast::<KernelTest>();
+        let stop = core::ptr::addr_of!(__stop_kernel_tests).cast::<KernelTest>();
+        core::slice::from_raw_parts(start, stop.offset_from(start) as usize)
+    }
+}
+
+/// Runs tests, reports the results and shuts QEMU down.
+pub fn run_tests(tests: &[KernelTest]) -> ! {
+    _log(format_args!("[TEST] running {} tests", tests.len()));
+
+    for test in tests {
+        STATE.lock().current = Some(test.name);
+        _log(format_args!("[TEST] start {}", test.name));
+
+        let outcome = (test.func)();
+
+        let mut state = STATE.lock();
+        state.current = None;
+        match outcome {
+            Outcome::Passed => {
+                state.summary.passed += 1;
+                _log(format_args!("[TEST] ok {}", test.name));
+            }
+            Outcome::Failed(message) => {
+                state.summary.failed += 1;
+                _log(format_args!(
+                    "[TEST] fail {}: {}",
+                    test.name,
+                    OneLine(message)
+                ));
+            }
+            Outcome::Skipped(reason) => {
+                state.summary.skipped += 1;
+                _log(format_args!(
+                    "[TEST] skip {}: {}",
+                    test.name,
+                    OneLine(reason)
+                ));
+            }
+        }
+    }
+
+    let summary = STATE.lock().summary;
+    finish(summary)
+}
+
+/// Fails the running test, if any, and ends the run. Called by the panic
+/// handler, returns if no test is running.
+pub fn on_panic(info: &PanicInfo<'_>) {
+    // The panic may come from code holding the lock, don't deadlock on it.
+    let Some(mut state) = STATE.try_lock() else {
+        return;
+    };
+    let Some(name) = state.current.take() else {
+        return;
+    };
+
+    state.summary.failed += 1;
+    let summary = state.summary;
+    drop(state);
+
+    _log(format_args!(
+        "[TEST] fail {}: {}",
+        name,
+        OneLine(info.message())
+    ));
+    finish(summary)
+}
+
+fn finish(summary: Summary) -> ! {
+    _log(format_args!(
+        "[TEST] result: passed={} failed={} skipped={}",
+        summary.passed, summary.failed, summary.skipped
+    ));
+
+    exit_qemu(if summary.failed == 0 {
+        QemuExitCode::Success
+    } else {
+        QemuExitCode::Failure
+    });
+
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn termination() {
+        assert_eq!(().report(), Outcome::Passed);
+        assert_eq!(Ok::<(), u32>(()).report(), Outcome::Passed);
+        assert_eq!(
+            Err::<(), u32>(42).report(),
+            Outcome::Failed(String::from("42"))
+        );
+        assert_eq!(
+            Outcome::Skipped("no VMX").report(),
+            Outcome::Skipped("no VMX")
+        );
+    }
+
+    #[test]
+    fn one_line_messages() {
+        use alloc::string::ToString;
+
+        let message = format_args!("failed\n  left: \"a\\b\"\r\n right: 2");
+        assert_eq!(
+            OneLine(message).to_string(),
+            "failed\\n  left: \"a\\\\b\"\\r\\n right: 2"
+        );
+        assert_eq!(OneLine("no VMX").to_string(), "no VMX");
+    }
+}
@@ -0,0 +1,193 @@
+//! 16550 UART driver.
+
+use core::fmt;
+
+use crate::io::{inb, outb};
+
+/// Frequency of the UART clock divided by 16, the baud rate of divisor 1.
+pub const MAX_BAUD_RATE: u32 = 115_200;
+
+// Register offsets. The divisor latch replaces DATA and IER while LCR.DLAB
+// is set.
+const DATA: u16 = 0;
+const IER: u16 = 1;
+const FCR: u16 = 2;
+const LCR: u16 = 3;
+const MCR: u16 = 4;
+const LSR: u16 = 5;
+const DIVISOR_LOW: u16 = 0;
+const DIVISOR_HIGH: u16 = 1;
+
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 1 << 7;
+/// Enables and clears the FIFOs, with a 14 bytes receive threshold.
+const FCR_ENABLE_CLEAR_14: u8 = 0xc7;
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// Byte sent to itself by the loopback test.
+const LOOPBACK_BYTE: u8 = 0xae;
+/// LSR polls before giving up on the loopback test.
+const LOOPBACK_POLLS: usize = 10_000;
+
+/// Legacy serial port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    /// Returns the base I/O port of the UART.
+    pub const fn base(self) -> u16 {
+        match self {
+            Self::Com1 => 0x3f8,
+            Self::Com2 => 0x2f8,
+            Self::Com3 => 0x3e8,
+            Self::Com4 => 0x2e8,
+        }
+    }
+
+    /// Parses "com1" to "com4", in any case.
+    pub const fn parse(name: &str) -> Option<Self> {
+        let name = name.as_bytes();
+        if name.len() != 4
+            || !name[0].eq_ignore_ascii_case(&b'c')
+            || !name[1].eq_ignore_ascii_case(&b'o')
+            || !name[2].eq_ignore_ascii_case(&b'm')
+        {
+            return None;
+        }
+        match name[3] {
+            b'1' => Some(Self::Com1),
+            b'2' => Some(Self::Com2),
+            b'3' => Some(Self::Com3),
+            b'4' => Some(Self::Com4),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartError {
+    /// The baud rate isn't MAX_BAUD_RATE divided by a 16-bit divisor.
+    InvalidBaudRate(u32),
+    /// The UART didn't receive what it sent in loopback mode.
+    LoopbackFailed,
+}
+
+impl fmt::Display for UartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidBaudRate(baud) => write!(f, "invalid baud rate {}", baud),
+            Self::LoopbackFailed => write!(f, "loopback test failed"),
+        }
+    }
+}
+
+/// Returns the divisor of baud.
+fn divisor(baud: u32) -> Result<u16, UartError> {
+    if baud == 0 || !MAX_BAUD_RATE.is_multiple_of(baud) {
+        return Err(UartError::InvalidBaudRate(baud));
+    }
+    u16::try_from(MAX_BAUD_RATE / baud).map_err(|_| UartError::InvalidBaudRate(baud))
+}
+
+/// 16550 UART, polled. It only holds the base port, copies access the same
+/// device.
+#[derive(Debug, Clone, Copy)]
+pub struct Uart {
+    base: u16,
+}
+
+impl Uart {
+    pub const fn new(port: ComPort) -> Self {
+        Self { base: port.base() }
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        // SAFETY: the UART registers have no memory side effect.
+        unsafe { inb(self.base + reg) }
+    }
+
+    fn write(&self, reg: u16, value: u8) {
+        // SAFETY: the UART registers have no memory side effect.
+        unsafe { outb(self.base + reg, value) }
+    }
+
+    /// Sets the UART to 8N1 at baud, with interrupts disabled and FIFOs
+    /// enabled, and checks that it works in loopback mode.
+    pub fn init(&self, baud: u32) -> Result<(), UartError> {
+        let [low, high] = divisor(baud)?.to_le_bytes();
+
+        self.write(IER, 0);
+        self.write(LCR, LCR_DLAB);
+        self.write(DIVISOR_LOW, low);
+        self.write(DIVISOR_HIGH, high);
+        self.write(LCR, LCR_8N1);
+        self.write(FCR, FCR_ENABLE_CLEAR_14);
+
+        self.write(MCR, MCR_RTS | MCR_OUT1 | MCR_OUT2 | MCR_LOOPBACK);
+        self.write(DATA, LOOPBACK_BYTE);
+        let received = (0..LOOPBACK_POLLS).find_map(|_| self.try_read_byte());
+        self.write(MCR, MCR_DTR | MCR_RTS | MCR_OUT1 | MCR_OUT2);
+
+        if received != Some(LOOPBACK_BYTE) {
+            return Err(UartError::LoopbackFailed);
+        }
+        Ok(())
+    }
+
+    /// Sends byte, once the transmit holding register is empty.
+    pub fn write_byte(&self, byte: u8) {
+        while self.read(LSR) & LSR_THR_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(DATA, byte);
+    }
+
+    /// Returns the next received byte, if any.
+    pub fn try_read_byte(&self) -> Option<u8> {
+        (self.read(LSR) & LSR_DATA_READY != 0).then(|| self.read(DATA))
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            self.write_byte(b);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn com_ports() {
+        assert_eq!(ComPort::parse("com1"), Some(ComPort::Com1));
+        assert_eq!(ComPort::parse("COM4"), Some(ComPort::Com4));
+        assert_eq!(ComPort::parse("com5"), None);
+        assert_eq!(ComPort::parse("ttyS0"), None);
+        assert_eq!(ComPort::Com2.base(), 0x2f8);
+    }
+
+    #[test]
+    fn baud_divisors() {
+        assert_eq!(divisor(115_200), Ok(1));
+        assert_eq!(divisor(9600), Ok(12));
+        assert_eq!(divisor(50), Ok(2304));
+        assert_eq!(divisor(0), Err(UartError::InvalidBaudRate(0)));
+        assert_eq!(divisor(1000), Err(UartError::InvalidBaudRate(1000)));
+        assert_eq!(divisor(1), Err(UartError::InvalidBaudRate(1)));
+    }
+}
@@ -1,16 +1,59 @@
+use core::fmt;
+
+use crate::cpu::extable::Fault;
+use vmx::errors::VmInstructionError;
+
 pub mod vmx;
 
 #[derive(Debug)]
 pub enum VMXResult {
     Succeed,
-    FailValid(u32),
+    FailValid(VmInstructionError),
     FailInvalid,
 }
 
+impl fmt::Display for VMXResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Succeed => write!(f, "VMsucceed"),
+            Self::FailValid(error) => write!(f, "VMfailValid: {}", error),
+            Self::FailInvalid => write!(f, "VMfailInvalid"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum VirtError {
     /// Bad address requested.
     BadAddress(u64),
     /// Error while executing a VMX instruction.
     VMInstruction(VMXResult),
+    /// VM exit with an exit reason unknown to the SDM.
+    UnknownExitReason(u32),
+    /// The hypervisor failed a hypercall with VMfailValid.
+    HypercallFailed,
+    /// No TSS is loaded, which VM entry requires for the host and the guest.
+    NoTss,
+    /// An instruction raised an exception, e.g. #UD for vmxon with CR4.VMXE
+    /// clear.
+    Fault(Fault),
+}
+
+impl From<Fault> for VirtError {
+    fn from(fault: Fault) -> Self {
+        Self::Fault(fault)
+    }
+}
+
+impl fmt::Display for VirtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadAddress(addr) => write!(f, "bad address {:#x}", addr),
+            Self::VMInstruction(result) => write!(f, "VMX instruction failed, {}", result),
+            Self::UnknownExitReason(reason) => write!(f, "unknown exit reason {:#x}", reason),
+            Self::HypercallFailed => write!(f, "hypercall failed, VMfailValid"),
+            Self::NoTss => write!(f, "no TSS loaded, TR is null"),
+            Self::Fault(fault) => write!(f, "instruction fault, {}", fault),
+        }
+    }
 }
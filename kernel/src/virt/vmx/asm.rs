@@ -1,30 +1,76 @@
+use crate::cpu::extable::catch;
+use crate::extable;
 use crate::virt::{VMXResult, VirtError};
-use core::arch::asm;
-use x86_64::PhysAddr;
+use core::arch::{asm, global_asm};
+use x86_64::{PhysAddr, VirtAddr};
 
-use super::errors::VM_INSTRUCTION_ERROR;
+use super::errors::{VmInstructionError, VM_INSTRUCTION_ERROR};
+use super::fields::VmcsFieldNatural;
+use super::vcpu::GuestRegisters;
 
+/// Decodes the outcome of a VMX instruction from the CF and ZF flags it left,
+/// in VMX root operation.
+///
+/// CF set means VMfailInvalid, ZF set means VMfailValid, in which case the
+/// error number is read from the current VMCS.
+#[inline]
+fn vm_result(cf: u8, zf: u8) -> Result<(), VirtError> {
+    if cf != 0 {
+        return Err(VirtError::VMInstruction(VMXResult::FailInvalid));
+    }
+
+    if zf != 0 {
+        // SAFETY: VMfailValid is only reported when a VMCS is current.
+        let error = unsafe { asm_vmread(VM_INSTRUCTION_ERROR as u32)? } as u32;
+        return Err(VirtError::VMInstruction(VMXResult::FailValid(
+            VmInstructionError::from_raw(error),
+        )));
+    }
+
+    Ok(())
+}
+
+/// Enters VMX operation. The #UD or #GP raised, e.g. with CR4.VMXE clear, is
+/// returned as [`VirtError::Fault`].
 ///
 /// # Safety
 ///
 /// Caller should ensure that the VMXON region is still allocated.
 #[inline]
 pub unsafe fn asm_vmxon(addr: PhysAddr) -> Result<(), VirtError> {
-    let mut ret: u8;
+    let (cf, zf) = catch(|| {
+        let (mut cf, mut zf) = (0u8, 0u8);
+        asm!(
+            "2: vmxon [{addr}]",
+            "setc {cf}",
+            "setz {zf}",
+            "3:",
+            extable!("2b", "3b"),
+            addr = in(reg) &addr.as_u64(), cf = inout(reg_byte) cf, zf = inout(reg_byte) zf,
+            options(nostack)
+        );
+        (cf, zf)
+    })?;
+
+    vm_result(cf, zf)
+}
+
+/// Leaves VMX operation.
+///
+/// # Safety
+///
+/// Caller should ensure that the CPU is in VMX root operation, and that no
+/// guest state still relies on it.
+#[inline]
+pub unsafe fn asm_vmxoff() -> Result<(), VirtError> {
+    let (mut cf, mut zf): (u8, u8);
     asm!(
-        "vmxon [{addr}]; setna {ret}",
-        addr = in(reg) &addr.as_u64(), ret = out(reg_byte) ret,
-        options(readonly, nostack, preserves_flags)
+        "vmxoff; setc {cf}; setz {zf}",
+        cf = out(reg_byte) cf, zf = out(reg_byte) zf,
+        options(nomem, nostack)
     );
 
-    match ret {
-        0 => Ok(()),
-        1 => Err(VirtError::VMInstruction(VMXResult::FailValid(asm_vmread(
-            VM_INSTRUCTION_ERROR as u32,
-        )?))),
-        2 => Err(VirtError::VMInstruction(VMXResult::FailInvalid)),
-        _ => unreachable!(),
-    }
+    vm_result(cf, zf)
 }
 
 ///
@@ -33,21 +79,34 @@ pub unsafe fn asm_vmxon(addr: PhysAddr) -> Result<(), VirtError> {
 /// Caller should ensure that the VMCS region is still allocated.
 #[inline]
 pub unsafe fn asm_vmptrld(addr: PhysAddr) -> Result<(), VirtError> {
-    let mut ret: u8;
+    let (mut cf, mut zf): (u8, u8);
     asm!(
-        "vmptrld [{addr}]; setna {ret}",
-        addr = in(reg) &addr.as_u64(), ret = out(reg_byte) ret,
-        options(readonly, nostack, preserves_flags)
+        "vmptrld [{addr}]; setc {cf}; setz {zf}",
+        addr = in(reg) &addr.as_u64(), cf = out(reg_byte) cf, zf = out(reg_byte) zf,
+        options(readonly, nostack)
     );
 
-    match ret {
-        0 => Ok(()),
-        1 => Err(VirtError::VMInstruction(VMXResult::FailValid(asm_vmread(
-            VM_INSTRUCTION_ERROR as u32,
-        )?))),
-        2 => Err(VirtError::VMInstruction(VMXResult::FailInvalid)),
-        _ => unreachable!(),
-    }
+    vm_result(cf, zf)
+}
+
+/// Returns the physical address of the current VMCS, or None if there is no
+/// current VMCS.
+///
+/// # Safety
+///
+/// Caller should ensure that the CPU is in VMX root operation.
+#[inline]
+pub unsafe fn asm_vmptrst() -> Result<Option<PhysAddr>, VirtError> {
+    let mut addr: u64 = 0;
+    let (mut cf, mut zf): (u8, u8);
+    asm!(
+        "vmptrst [{addr}]; setc {cf}; setz {zf}",
+        addr = in(reg) &mut addr, cf = out(reg_byte) cf, zf = out(reg_byte) zf,
+        options(nostack)
+    );
+
+    vm_result(cf, zf)?;
+    Ok((addr != u64::MAX).then(|| PhysAddr::new_truncate(addr)))
 }
 
 ///
@@ -55,21 +114,318 @@ pub unsafe fn asm_vmptrld(addr: PhysAddr) -> Result<(), VirtError> {
 ///
 /// Caller should ensure that the VMCS region is still allocated.
 #[inline]
-pub unsafe fn asm_vmread(field: u32) -> Result<u32, VirtError> {
-    let mut ret: u8;
-    let mut result: u32;
+pub unsafe fn asm_vmclear(addr: PhysAddr) -> Result<(), VirtError> {
+    let (mut cf, mut zf): (u8, u8);
+    asm!(
+        "vmclear [{addr}]; setc {cf}; setz {zf}",
+        addr = in(reg) &addr.as_u64(), cf = out(reg_byte) cf, zf = out(reg_byte) zf,
+        options(nostack)
+    );
+
+    vm_result(cf, zf)
+}
+
+///
+/// # Safety
+///
+/// Caller should ensure that a VMCS is currently loaded.
+#[inline]
+pub unsafe fn asm_vmread(field: u32) -> Result<u64, VirtError> {
+    let mut result: u64;
+    let (mut cf, mut zf): (u8, u8);
+    asm!(
+        "vmread {result}, {field}; setc {cf}; setz {zf}",
+        result = out(reg) result, field = in(reg) u64::from(field),
+        cf = out(reg_byte) cf, zf = out(reg_byte) zf,
+        options(nomem, nostack)
+    );
+
+    vm_result(cf, zf).map(|_| result)
+}
+
+///
+/// # Safety
+///
+/// Caller should ensure that a VMCS is currently loaded, and that writing
+/// value to field doesn't break the host state.
+#[inline]
+pub unsafe fn asm_vmwrite(field: u32, value: u64) -> Result<(), VirtError> {
+    let (mut cf, mut zf): (u8, u8);
+    asm!(
+        "vmwrite {field}, {value}; setc {cf}; setz {zf}",
+        field = in(reg) u64::from(field), value = in(reg) value,
+        cf = out(reg_byte) cf, zf = out(reg_byte) zf,
+        options(nomem, nostack)
+    );
+
+    vm_result(cf, zf)
+}
+
+/// INVEPT invalidation types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum InveptType {
+    /// Invalidates the mappings derived from a single EPTP.
+    SingleContext = 1,
+    /// Invalidates the mappings derived from all EPTPs.
+    Global = 2,
+}
+
+/// Invalidates the cached EPT mappings.
+///
+/// eptp is ignored for global invalidations.
+///
+/// # Safety
+///
+/// Caller should ensure that the CPU is in VMX root operation.
+#[inline]
+pub unsafe fn asm_invept(kind: InveptType, eptp: u64) -> Result<(), VirtError> {
+    let desc: [u64; 2] = [eptp, 0];
+    let (mut cf, mut zf): (u8, u8);
+    asm!(
+        "invept {kind}, [{desc}]; setc {cf}; setz {zf}",
+        kind = in(reg) kind as u64, desc = in(reg) &desc,
+        cf = out(reg_byte) cf, zf = out(reg_byte) zf,
+        options(readonly, nostack)
+    );
+
+    vm_result(cf, zf)
+}
+
+/// INVVPID invalidation types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum InvvpidType {
+    /// Invalidates the mappings of a single linear address tagged with a VPID.
+    IndividualAddress = 0,
+    /// Invalidates all the mappings tagged with a VPID.
+    SingleContext = 1,
+    /// Invalidates all the mappings tagged with any VPID but 0.
+    AllContext = 2,
+    /// Invalidates all the mappings tagged with a VPID, except global ones.
+    SingleContextRetainingGlobals = 3,
+}
+
+/// Invalidates the cached linear mappings tagged with a VPID.
+///
+/// vpid is ignored for all-context invalidations, and address is only used
+/// for individual-address invalidations.
+///
+/// # Safety
+///
+/// Caller should ensure that the CPU is in VMX root operation.
+#[inline]
+pub unsafe fn asm_invvpid(kind: InvvpidType, vpid: u16, address: u64) -> Result<(), VirtError> {
+    let desc: [u64; 2] = [u64::from(vpid), address];
+    let (mut cf, mut zf): (u8, u8);
+    asm!(
+        "invvpid {kind}, [{desc}]; setc {cf}; setz {zf}",
+        kind = in(reg) kind as u64, desc = in(reg) &desc,
+        cf = out(reg_byte) cf, zf = out(reg_byte) zf,
+        options(readonly, nostack)
+    );
+
+    vm_result(cf, zf)
+}
+
+/// Decodes the CF and ZF flags left by the hypervisor after a hypercall.
+///
+/// The guest can't read the VMCS, so VMfailValid carries no error number.
+#[inline]
+fn hypercall_result(cf: u8, zf: u8) -> Result<(), VirtError> {
+    if cf != 0 {
+        return Err(VirtError::VMInstruction(VMXResult::FailInvalid));
+    }
+    if zf != 0 {
+        return Err(VirtError::HypercallFailed);
+    }
+    Ok(())
+}
+
+/// Executes vmcall, with nr in rax and args in rbx, rcx, rdx and rsi.
+/// Returns rax and the CF and ZF flags.
+#[inline]
+unsafe fn vmcall(nr: u64, args: [u64; 4]) -> (u64, u8, u8) {
+    let mut ret: u64;
+    let (mut cf, mut zf): (u8, u8);
+    // rbx is reserved by LLVM, so swap it with a scratch register around
+    // vmcall. The test clears CF and ZF, so that a hypervisor leaving
+    // RFLAGS untouched reports a success.
     asm!(
-        "vmread [{result:r}], {field:r}; setna {ret}",
-        result = out(reg) result, field = in(reg) field, ret = out(reg_byte) ret,
-        options(readonly, nostack, preserves_flags)
+        "xchg {arg0}, rbx",
+        "test rsp, rsp",
+        "vmcall",
+        "setc {cf}",
+        "setz {zf}",
+        "xchg {arg0}, rbx",
+        arg0 = inout(reg) args[0] => _,
+        inout("rax") nr => ret,
+        inout("rcx") args[1] => _,
+        inout("rdx") args[2] => _,
+        inout("rsi") args[3] => _,
+        cf = out(reg_byte) cf, zf = out(reg_byte) zf,
     );
+    (ret, cf, zf)
+}
 
-    match ret {
-        0 => Ok(result),
-        1 => Err(VirtError::VMInstruction(VMXResult::FailValid(asm_vmread(
-            VM_INSTRUCTION_ERROR as u32,
-        )?))),
-        2 => Err(VirtError::VMInstruction(VMXResult::FailInvalid)),
+/// Issues a hypercall from a guest, with nr in rax and args in rbx, rcx, rdx
+/// and rsi. Returns the value left in rax.
+///
+/// The hypervisor reports failures by setting CF or ZF like a VMX
+/// instruction.
+///
+/// # Safety
+///
+/// Caller should ensure that the hypervisor handling the call doesn't break
+/// the current execution state.
+#[inline]
+pub unsafe fn asm_vmcall(nr: u64, args: [u64; 4]) -> Result<u64, VirtError> {
+    let (ret, cf, zf) = vmcall(nr, args);
+    hypercall_result(cf, zf).map(|_| ret)
+}
+
+/// Executes vmcall in VMX root operation, where it fails with VMfailValid
+/// unless the dual-monitor treatment is active. The error number is read
+/// from the current VMCS.
+///
+/// # Safety
+///
+/// Caller should ensure that the CPU is in VMX root operation, with a
+/// current VMCS.
+#[inline]
+pub unsafe fn asm_vmcall_root(nr: u64, args: [u64; 4]) -> Result<u64, VirtError> {
+    let (ret, cf, zf) = vmcall(nr, args);
+    vm_result(cf, zf).map(|_| ret)
+}
+
+/// Invokes the VM function function, with index in ecx (e.g. the EPTP list
+/// index for EPTP switching).
+///
+/// VMFUNC doesn't report errors through RFLAGS: it causes a VM exit if the
+/// function isn't enabled, and #UD outside of VMX non-root operation.
+///
+/// # Safety
+///
+/// Caller should ensure that it runs in a guest with the VM function enabled,
+/// and that switching to the requested state doesn't break it.
+#[inline]
+pub unsafe fn asm_vmfunc(function: u32, index: u32) {
+    asm!(
+        "vmfunc",
+        in("eax") function, in("ecx") index,
+        options(nostack)
+    );
+}
+
+// Enters the guest with vmlaunch, or vmresume if launched is non-zero.
+//
+// The guest general purpose registers are loaded from, and saved back to, the
+// GuestRegisters pointed by rdi. HOST_RSP is set to the stack pointer right
+// before entering the guest, so that vmx_vmexit (HOST_RIP) finds the
+// GuestRegisters pointer on top of its stack.
+//
+// Returns 0 after a VM exit, 1 on VMfailValid and 2 on VMfailInvalid.
+global_asm!(
+    ".global vmx_run",
+    "vmx_run:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "push rdi",
+    "mov rax, {host_rsp}",
+    "vmwrite rax, rsp",
+    "jna 3f",
+    "test sil, sil",
+    "mov rax, [rdi + 0x00]",
+    "mov rbx, [rdi + 0x08]",
+    "mov rcx, [rdi + 0x10]",
+    "mov rdx, [rdi + 0x18]",
+    "mov rsi, [rdi + 0x20]",
+    "mov rbp, [rdi + 0x30]",
+    "mov r8, [rdi + 0x38]",
+    "mov r9, [rdi + 0x40]",
+    "mov r10, [rdi + 0x48]",
+    "mov r11, [rdi + 0x50]",
+    "mov r12, [rdi + 0x58]",
+    "mov r13, [rdi + 0x60]",
+    "mov r14, [rdi + 0x68]",
+    "mov r15, [rdi + 0x70]",
+    "mov rdi, [rdi + 0x28]",
+    "jnz 2f",
+    "vmlaunch",
+    "jmp 3f",
+    "2:",
+    "vmresume",
+    "3:",
+    "mov eax, 1",
+    "mov ecx, 2",
+    "cmovc eax, ecx",
+    "pop rdi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    ".global vmx_vmexit",
+    "vmx_vmexit:",
+    "push rdi",
+    "mov rdi, [rsp + 8]",
+    "mov [rdi + 0x00], rax",
+    "mov [rdi + 0x08], rbx",
+    "mov [rdi + 0x10], rcx",
+    "mov [rdi + 0x18], rdx",
+    "mov [rdi + 0x20], rsi",
+    "mov [rdi + 0x30], rbp",
+    "mov [rdi + 0x38], r8",
+    "mov [rdi + 0x40], r9",
+    "mov [rdi + 0x48], r10",
+    "mov [rdi + 0x50], r11",
+    "mov [rdi + 0x58], r12",
+    "mov [rdi + 0x60], r13",
+    "mov [rdi + 0x68], r14",
+    "mov [rdi + 0x70], r15",
+    "pop qword ptr [rdi + 0x28]",
+    "add rsp, 8",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "xor eax, eax",
+    "ret",
+    host_rsp = const VmcsFieldNatural::HostRsp as u32,
+);
+
+extern "C" {
+    fn vmx_run(regs: *mut GuestRegisters, launched: u8) -> u8;
+    fn vmx_vmexit();
+}
+
+/// Address the CPU jumps to on VM exit, to be written in HOST_RIP.
+#[inline]
+pub fn vmexit_rip() -> VirtAddr {
+    VirtAddr::from_ptr(vmx_vmexit as *const ())
+}
+
+/// Enters the guest until the next VM exit.
+///
+/// # Safety
+///
+/// Caller should ensure that the current VMCS is fully set up, with HOST_RIP
+/// pointing to [`vmexit_rip`].
+#[inline]
+pub unsafe fn asm_vmentry(regs: &mut GuestRegisters, launched: bool) -> Result<(), VirtError> {
+    match vmx_run(regs, launched.into()) {
+        0 => Ok(()),
+        1 => vm_result(0, 1),
+        2 => vm_result(1, 0),
         _ => unreachable!(),
     }
 }
@@ -0,0 +1,446 @@
+//! VMX capability MSRs, see Intel SDM Vol. 3D, Appendix A.
+
+use core::arch::x86_64::__cpuid;
+
+use x86_64::registers::model_specific::Msr;
+
+use crate::cpu::extable::Fault;
+use crate::cpu::msr::{
+    try_rdmsr, IA32_VMX_BASIC, IA32_VMX_ENTRY_CTLS, IA32_VMX_EPT_VPID_CAP, IA32_VMX_EXIT_CTLS,
+    IA32_VMX_MISC, IA32_VMX_PINBASED_CTLS, IA32_VMX_PROCBASED_CTLS, IA32_VMX_PROCBASED_CTLS2,
+    IA32_VMX_TRUE_ENTRY_CTLS, IA32_VMX_TRUE_EXIT_CTLS, IA32_VMX_TRUE_PINBASED_CTLS,
+    IA32_VMX_TRUE_PROCBASED_CTLS, IA32_VMX_VMFUNC,
+};
+
+/// Primary processor-based control activating the secondary controls.
+pub const PROCBASED_ACTIVATE_SECONDARY_CONTROLS: u32 = 1 << 31;
+pub const PROCBASED2_ENABLE_EPT: u32 = 1 << 1;
+pub const PROCBASED2_ENABLE_VPID: u32 = 1 << 5;
+pub const PROCBASED2_ENABLE_VMFUNC: u32 = 1 << 13;
+
+#[inline]
+fn bit(raw: u64, n: u32) -> bool {
+    raw & (1 << n) != 0
+}
+
+/// Returns true if the CPU supports VMX (CPUID.1:ECX.VMX). The capability
+/// MSRs can only be read if it does.
+pub fn vmx_supported() -> bool {
+    bit(u64::from(__cpuid(1).ecx), 5)
+}
+
+/// IA32_VMX_BASIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmxBasic {
+    pub revision_id: u32,
+    pub vmcs_size: u16,
+    pub phys_addr_width_32: bool,
+    pub dual_monitor: bool,
+    pub memory_type: u8,
+    pub ins_outs_info: bool,
+    /// The IA32_VMX_TRUE_*_CTLS MSRs are supported.
+    pub true_ctls: bool,
+    pub no_error_code_requirement: bool,
+}
+
+impl VmxBasic {
+    pub fn from_raw(raw: u64) -> Self {
+        Self {
+            revision_id: raw as u32 & !(1 << 31),
+            vmcs_size: ((raw >> 32) & 0x1fff) as u16,
+            phys_addr_width_32: bit(raw, 48),
+            dual_monitor: bit(raw, 49),
+            memory_type: ((raw >> 50) & 0xf) as u8,
+            ins_outs_info: bit(raw, 54),
+            true_ctls: bit(raw, 55),
+            no_error_code_requirement: bit(raw, 56),
+        }
+    }
+
+    pub fn read() -> Self {
+        // SAFETY: Reading IA32_VMX_BASIC is safe
+        Self::from_raw(unsafe { Msr::new(IA32_VMX_BASIC).read() })
+    }
+
+    /// Reads IA32_VMX_BASIC, returning the #GP raised if VMX is unsupported.
+    pub fn try_read() -> Result<Self, Fault> {
+        try_rdmsr(IA32_VMX_BASIC).map(Self::from_raw)
+    }
+}
+
+/// Allowed settings of a VM-execution, VM-exit or VM-entry control field,
+/// as reported by the corresponding capability MSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlCaps {
+    /// Bits that must be set (allowed 0-settings, low 32 bits of the MSR).
+    pub allowed0: u32,
+    /// Bits that may be set (allowed 1-settings, high 32 bits of the MSR).
+    pub allowed1: u32,
+}
+
+impl ControlCaps {
+    pub const fn from_raw(raw: u64) -> Self {
+        Self {
+            allowed0: raw as u32,
+            allowed1: (raw >> 32) as u32,
+        }
+    }
+
+    pub const fn supports(&self, bits: u32) -> bool {
+        self.allowed1 & bits == bits
+    }
+}
+
+/// Result of [`adjust_controls`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdjustedControls {
+    /// Value to write to the VMCS control field.
+    pub value: u32,
+    /// Requested bits the CPU doesn't allow to be set, and were dropped.
+    pub unsupported: u32,
+    /// Bits the CPU requires to be set, that weren't requested.
+    pub forced: u32,
+}
+
+impl AdjustedControls {
+    /// True if every requested bit could be set.
+    pub const fn is_complete(&self) -> bool {
+        self.unsupported == 0
+    }
+}
+
+/// Applies the allowed-0 and allowed-1 settings of msr to the desired
+/// controls.
+pub const fn adjust_controls(desired: u32, msr: ControlCaps) -> AdjustedControls {
+    AdjustedControls {
+        value: (desired | msr.allowed0) & msr.allowed1,
+        unsupported: desired & !msr.allowed1,
+        forced: msr.allowed0 & !desired,
+    }
+}
+
+/// IA32_VMX_MISC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmxMisc {
+    /// The VMX-preemption timer counts down every 2^rate TSC ticks.
+    pub preemption_timer_rate: u8,
+    pub store_efer_lma: bool,
+    pub activity_hlt: bool,
+    pub activity_shutdown: bool,
+    pub activity_wait_for_sipi: bool,
+    pub intel_pt: bool,
+    pub rdmsr_smbase: bool,
+    pub cr3_targets: u16,
+    /// Recommended maximum number of entries in the MSR load/store lists.
+    pub max_msr_list_len: u32,
+    pub smm_monitor_ctl_bit2: bool,
+    pub vmwrite_any_field: bool,
+    pub zero_len_injection: bool,
+    pub mseg_revision_id: u32,
+}
+
+impl VmxMisc {
+    pub fn from_raw(raw: u64) -> Self {
+        Self {
+            preemption_timer_rate: (raw & 0x1f) as u8,
+            store_efer_lma: bit(raw, 5),
+            activity_hlt: bit(raw, 6),
+            activity_shutdown: bit(raw, 7),
+            activity_wait_for_sipi: bit(raw, 8),
+            intel_pt: bit(raw, 14),
+            rdmsr_smbase: bit(raw, 15),
+            cr3_targets: ((raw >> 16) & 0x1ff) as u16,
+            max_msr_list_len: 512 * (((raw >> 25) & 0x7) as u32 + 1),
+            smm_monitor_ctl_bit2: bit(raw, 28),
+            vmwrite_any_field: bit(raw, 29),
+            zero_len_injection: bit(raw, 30),
+            mseg_revision_id: (raw >> 32) as u32,
+        }
+    }
+}
+
+/// IA32_VMX_EPT_VPID_CAP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptVpidCap {
+    pub execute_only: bool,
+    pub page_walk_4: bool,
+    pub page_walk_5: bool,
+    pub memory_type_uc: bool,
+    pub memory_type_wb: bool,
+    pub pages_2m: bool,
+    pub pages_1g: bool,
+    pub invept: bool,
+    pub accessed_dirty: bool,
+    pub advanced_exit_info: bool,
+    pub supervisor_shadow_stack: bool,
+    pub invept_single_context: bool,
+    pub invept_all_context: bool,
+    pub invvpid: bool,
+    pub invvpid_individual_address: bool,
+    pub invvpid_single_context: bool,
+    pub invvpid_all_context: bool,
+    pub invvpid_single_context_retaining_globals: bool,
+    pub max_hlat_prefix_size: u8,
+}
+
+impl EptVpidCap {
+    pub fn from_raw(raw: u64) -> Self {
+        Self {
+            execute_only: bit(raw, 0),
+            page_walk_4: bit(raw, 6),
+            page_walk_5: bit(raw, 7),
+            memory_type_uc: bit(raw, 8),
+            memory_type_wb: bit(raw, 14),
+            pages_2m: bit(raw, 16),
+            pages_1g: bit(raw, 17),
+            invept: bit(raw, 20),
+            accessed_dirty: bit(raw, 21),
+            advanced_exit_info: bit(raw, 22),
+            supervisor_shadow_stack: bit(raw, 23),
+            invept_single_context: bit(raw, 25),
+            invept_all_context: bit(raw, 26),
+            invvpid: bit(raw, 32),
+            invvpid_individual_address: bit(raw, 40),
+            invvpid_single_context: bit(raw, 41),
+            invvpid_all_context: bit(raw, 42),
+            invvpid_single_context_retaining_globals: bit(raw, 43),
+            max_hlat_prefix_size: ((raw >> 48) & 0x3f) as u8,
+        }
+    }
+}
+
+/// IA32_VMX_VMFUNC: bit n is set if VM function n may be enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmFuncCap(pub u64);
+
+impl VmFuncCap {
+    pub const fn eptp_switching(&self) -> bool {
+        self.0 & 1 != 0
+    }
+}
+
+/// Raw values of every VMX capability MSR. MSRs the CPU doesn't enumerate
+/// are left to 0.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RawVmxCaps {
+    pub basic: u64,
+    pub pinbased: u64,
+    pub procbased: u64,
+    pub exit: u64,
+    pub entry: u64,
+    pub misc: u64,
+    pub procbased2: u64,
+    pub ept_vpid: u64,
+    pub true_pinbased: u64,
+    pub true_procbased: u64,
+    pub true_exit: u64,
+    pub true_entry: u64,
+    pub vmfunc: u64,
+}
+
+impl RawVmxCaps {
+    /// Reads the capability MSRs, skipping the ones the CPU doesn't enumerate
+    /// as reading them would #GP.
+    pub fn read() -> Self {
+        // SAFETY: each MSR is only read if its existence is enumerated by a
+        // previously read capability MSR.
+        unsafe {
+            let read = |msr| Msr::new(msr).read();
+            let mut raw = Self {
+                basic: read(IA32_VMX_BASIC),
+                pinbased: read(IA32_VMX_PINBASED_CTLS),
+                procbased: read(IA32_VMX_PROCBASED_CTLS),
+                exit: read(IA32_VMX_EXIT_CTLS),
+                entry: read(IA32_VMX_ENTRY_CTLS),
+                misc: read(IA32_VMX_MISC),
+                ..Default::default()
+            };
+
+            if VmxBasic::from_raw(raw.basic).true_ctls {
+                raw.true_pinbased = read(IA32_VMX_TRUE_PINBASED_CTLS);
+                raw.true_procbased = read(IA32_VMX_TRUE_PROCBASED_CTLS);
+                raw.true_exit = read(IA32_VMX_TRUE_EXIT_CTLS);
+                raw.true_entry = read(IA32_VMX_TRUE_ENTRY_CTLS);
+            }
+
+            if !ControlCaps::from_raw(raw.procbased).supports(PROCBASED_ACTIVATE_SECONDARY_CONTROLS)
+            {
+                return raw;
+            }
+
+            raw.procbased2 = read(IA32_VMX_PROCBASED_CTLS2);
+            let procbased2 = ControlCaps::from_raw(raw.procbased2);
+            if procbased2.allowed1 & (PROCBASED2_ENABLE_EPT | PROCBASED2_ENABLE_VPID) != 0 {
+                raw.ept_vpid = read(IA32_VMX_EPT_VPID_CAP);
+            }
+            if procbased2.supports(PROCBASED2_ENABLE_VMFUNC) {
+                raw.vmfunc = read(IA32_VMX_VMFUNC);
+            }
+
+            raw
+        }
+    }
+}
+
+/// Parsed VMX capabilities. The control capabilities come from the TRUE_*
+/// MSRs when the CPU supports them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmxCaps {
+    pub basic: VmxBasic,
+    pub pinbased: ControlCaps,
+    pub procbased: ControlCaps,
+    pub procbased2: ControlCaps,
+    pub exit: ControlCaps,
+    pub entry: ControlCaps,
+    pub misc: VmxMisc,
+    pub ept_vpid: EptVpidCap,
+    pub vmfunc: VmFuncCap,
+}
+
+impl VmxCaps {
+    pub fn from_raw(raw: &RawVmxCaps) -> Self {
+        let basic = VmxBasic::from_raw(raw.basic);
+        let pick = |default, true_ctls| {
+            ControlCaps::from_raw(if basic.true_ctls { true_ctls } else { default })
+        };
+
+        Self {
+            basic,
+            pinbased: pick(raw.pinbased, raw.true_pinbased),
+            procbased: pick(raw.procbased, raw.true_procbased),
+            procbased2: ControlCaps::from_raw(raw.procbased2),
+            exit: pick(raw.exit, raw.true_exit),
+            entry: pick(raw.entry, raw.true_entry),
+            misc: VmxMisc::from_raw(raw.misc),
+            ept_vpid: EptVpidCap::from_raw(raw.ept_vpid),
+            vmfunc: VmFuncCap(raw.vmfunc),
+        }
+    }
+
+    pub fn read() -> Self {
+        Self::from_raw(&RawVmxCaps::read())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Recorded in an L1 guest running on KVM.
+    const KVM: RawVmxCaps = RawVmxCaps {
+        basic: 0x00da_0400_0000_0004,
+        pinbased: 0x0000_00ff_0000_0016,
+        procbased: 0xfff9_fffe_0401_e172,
+        exit: 0x01ff_ffff_0003_6dff,
+        entry: 0x0003_ffff_0000_11ff,
+        misc: 0x0000_0000_2000_0065,
+        procbased2: 0x0057_efff_0000_0000,
+        ept_vpid: 0x0000_0f01_0673_4141,
+        true_pinbased: 0x0000_00ff_0000_0016,
+        true_procbased: 0xfff9_fffe_0400_6172,
+        true_exit: 0x01ff_ffff_0003_6dfb,
+        true_entry: 0x0003_ffff_0000_11fb,
+        vmfunc: 0x1,
+    };
+
+    #[test]
+    fn basic() {
+        let basic = VmxBasic::from_raw(KVM.basic);
+        assert_eq!(basic.revision_id, 4);
+        assert_eq!(basic.vmcs_size, 0x400);
+        assert_eq!(basic.memory_type, 6);
+        assert!(!basic.phys_addr_width_32);
+        assert!(basic.ins_outs_info);
+        assert!(basic.true_ctls);
+        assert!(!basic.no_error_code_requirement);
+
+        // Bit 31 is always 0 in the revision identifier.
+        assert_eq!(VmxBasic::from_raw(0x8000_0001).revision_id, 1);
+    }
+
+    #[test]
+    fn true_controls_preferred() {
+        let caps = VmxCaps::from_raw(&KVM);
+        assert_eq!(caps.procbased, ControlCaps::from_raw(KVM.true_procbased));
+        assert_eq!(caps.exit, ControlCaps::from_raw(KVM.true_exit));
+
+        let raw = RawVmxCaps {
+            basic: KVM.basic & !(1 << 55),
+            ..KVM
+        };
+        let caps = VmxCaps::from_raw(&raw);
+        assert_eq!(caps.procbased, ControlCaps::from_raw(KVM.procbased));
+        assert_eq!(caps.entry, ControlCaps::from_raw(KVM.entry));
+    }
+
+    #[test]
+    fn adjust() {
+        let procbased = ControlCaps::from_raw(KVM.true_procbased);
+
+        // HLT exiting is supported, bit 17 is reserved.
+        let adjusted = adjust_controls((1 << 7) | (1 << 17), procbased);
+        assert_eq!(adjusted.value, 0x0400_61f2);
+        assert_eq!(adjusted.unsupported, 1 << 17);
+        assert_eq!(adjusted.forced, 0x0400_6172);
+        assert!(!adjusted.is_complete());
+
+        let adjusted = adjust_controls(1 << 7, procbased);
+        assert!(adjusted.is_complete());
+        assert_eq!(adjusted.value & procbased.allowed0, procbased.allowed0);
+        assert_eq!(adjusted.value & !procbased.allowed1, 0);
+    }
+
+    #[test]
+    fn adjust_default_vs_true() {
+        // CR3-load/store exiting (bits 15 and 16) are default1 controls: they
+        // are forced by IA32_VMX_PROCBASED_CTLS but may be cleared with the
+        // TRUE MSR.
+        let default = adjust_controls(0, ControlCaps::from_raw(KVM.procbased));
+        let true_ctls = adjust_controls(0, ControlCaps::from_raw(KVM.true_procbased));
+        assert_eq!(default.value & (3 << 15), 3 << 15);
+        assert_eq!(true_ctls.value & (3 << 15), 0);
+    }
+
+    #[test]
+    fn misc() {
+        let misc = VmxMisc::from_raw(KVM.misc);
+        assert_eq!(misc.preemption_timer_rate, 5);
+        assert!(misc.store_efer_lma);
+        assert!(misc.activity_hlt);
+        assert!(!misc.activity_shutdown);
+        assert!(misc.vmwrite_any_field);
+        assert_eq!(misc.cr3_targets, 0);
+        assert_eq!(misc.max_msr_list_len, 512);
+    }
+
+    #[test]
+    fn ept_vpid() {
+        let cap = EptVpidCap::from_raw(KVM.ept_vpid);
+        assert!(cap.execute_only);
+        assert!(cap.page_walk_4);
+        assert!(!cap.page_walk_5);
+        assert!(cap.memory_type_uc);
+        assert!(cap.memory_type_wb);
+        assert!(cap.pages_2m);
+        assert!(cap.pages_1g);
+        assert!(cap.invept);
+        assert!(cap.invept_single_context);
+        assert!(cap.invept_all_context);
+        assert!(cap.invvpid);
+        assert!(cap.invvpid_individual_address);
+        assert!(cap.invvpid_all_context);
+        assert!(cap.invvpid_single_context_retaining_globals);
+    }
+
+    #[test]
+    fn secondary() {
+        let caps = VmxCaps::from_raw(&KVM);
+        assert!(caps
+            .procbased
+            .supports(PROCBASED_ACTIVATE_SECONDARY_CONTROLS));
+        assert!(caps
+            .procbased2
+            .supports(PROCBASED2_ENABLE_EPT | PROCBASED2_ENABLE_VPID));
+        assert!(caps.vmfunc.eptp_switching());
+    }
+}
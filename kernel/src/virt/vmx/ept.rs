@@ -0,0 +1,909 @@
+//! Extended page tables, see Intel SDM Vol. 3C, Section 29.3.
+//!
+//! Tables are allocated from a [`FrameAllocator`] and accessed through a
+//! linear mapping of the physical memory at `phys_offset`, like the kernel
+//! page tables in [`crate::mm::memory`].
+
+use core::ops::BitOr;
+
+use crate::virt::VirtError;
+
+use super::asm::{asm_invept, InveptType};
+
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+const ENTRY_COUNT: usize = 512;
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// End of the guest-physical addresses translated by a 4-level page walk.
+const MAX_GPA: u64 = 1 << 48;
+/// End of the host-physical addresses an entry can hold.
+const MAX_HPA: u64 = ADDR_MASK + 0x1000;
+const MEMORY_TYPE_SHIFT: u64 = 3;
+const MEMORY_TYPE_MASK: u64 = 0x7 << MEMORY_TYPE_SHIFT;
+const IGNORE_PAT: u64 = 1 << 6;
+const LARGE_PAGE: u64 = 1 << 7;
+const ACCESSED: u64 = 1 << 8;
+const DIRTY: u64 = 1 << 9;
+/// Ignored by the CPU: set in the leaf entries of mapped pages, which may
+/// have no access rights, e.g. after `protect(.., EptFlags::NONE)`.
+const MAPPED: u64 = 1 << 11;
+
+const EPTP_PAGE_WALK_4: u64 = 3 << 3;
+const EPTP_ACCESSED_DIRTY: u64 = 1 << 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EptError {
+    /// The frame allocator ran out of frames for a new table.
+    FrameAllocationFailed,
+    /// An address or size isn't aligned to 4KiB.
+    Misaligned,
+    /// Part of the range is already mapped.
+    AlreadyMapped,
+    /// Part of the range isn't mapped.
+    NotMapped,
+    /// The range goes past the guest-physical addresses translated by the
+    /// EPT, or the host-physical addresses an entry can hold.
+    OutOfRange,
+}
+
+/// Access rights of an EPT entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptFlags(u64);
+
+impl EptFlags {
+    pub const NONE: Self = Self(0);
+    pub const READ: Self = Self(1 << 0);
+    pub const WRITE: Self = Self(1 << 1);
+    /// Supervisor-mode execute if mode-based execute control is enabled.
+    pub const EXECUTE: Self = Self(1 << 2);
+    /// Only used if mode-based execute control is enabled.
+    pub const USER_EXECUTE: Self = Self(1 << 10);
+    pub const RWX: Self = Self(Self::READ.0 | Self::WRITE.0 | Self::EXECUTE.0);
+    pub const ALL: Self = Self(Self::RWX.0 | Self::USER_EXECUTE.0);
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for EptFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// EPT memory types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+}
+
+impl MemoryType {
+    fn from_bits(bits: u64) -> Option<Self> {
+        match bits {
+            0 => Some(Self::Uncacheable),
+            1 => Some(Self::WriteCombining),
+            4 => Some(Self::WriteThrough),
+            5 => Some(Self::WriteProtected),
+            6 => Some(Self::WriteBack),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EptPageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl EptPageSize {
+    pub const fn size(self) -> u64 {
+        match self {
+            Self::Size4KiB => 0x1000,
+            Self::Size2MiB => 0x20_0000,
+            Self::Size1GiB => 0x4000_0000,
+        }
+    }
+
+    /// Level of the table holding leaf entries of this size, the PML4 being
+    /// level 4.
+    const fn level(self) -> usize {
+        match self {
+            Self::Size4KiB => 1,
+            Self::Size2MiB => 2,
+            Self::Size1GiB => 3,
+        }
+    }
+
+    const fn from_level(level: usize) -> Self {
+        match level {
+            1 => Self::Size4KiB,
+            2 => Self::Size2MiB,
+            _ => Self::Size1GiB,
+        }
+    }
+}
+
+/// Attributes of the leaf entries created by [`Ept::map`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptAttributes {
+    pub flags: EptFlags,
+    pub memory_type: MemoryType,
+    /// Largest page size the mapping may use.
+    pub max_page_size: EptPageSize,
+}
+
+impl EptAttributes {
+    pub const fn new(flags: EptFlags, memory_type: MemoryType, max_page_size: EptPageSize) -> Self {
+        Self {
+            flags,
+            memory_type,
+            max_page_size,
+        }
+    }
+}
+
+/// Leaf translation of a guest-physical address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptMapping {
+    pub hpa: PhysAddr,
+    pub page_size: EptPageSize,
+    pub flags: EptFlags,
+    pub memory_type: Option<MemoryType>,
+    pub ignore_pat: bool,
+    pub accessed: bool,
+    pub dirty: bool,
+}
+
+#[repr(C, align(4096))]
+struct EptTable([u64; ENTRY_COUNT]);
+
+#[inline]
+const fn index(gpa: u64, level: usize) -> usize {
+    ((gpa >> (12 + 9 * (level - 1))) & 0x1ff) as usize
+}
+
+#[inline]
+const fn level_size(level: usize) -> u64 {
+    1 << (12 + 9 * (level - 1))
+}
+
+#[inline]
+const fn is_leaf(entry: u64, level: usize) -> bool {
+    level == 1 || (level < 4 && entry & LARGE_PAGE != 0)
+}
+
+/// Returns true if the entry maps a page or references a table. Tables are
+/// always referenced with some access rights.
+#[inline]
+const fn is_present(entry: u64, level: usize) -> bool {
+    if is_leaf(entry, level) {
+        entry & MAPPED != 0
+    } else {
+        entry & EptFlags::RWX.bits() != 0
+    }
+}
+
+/// A 4-level EPT hierarchy. The tables are leaked unless the EPT is
+/// destroyed with [`Ept::destroy`].
+#[derive(Debug)]
+pub struct Ept {
+    pml4: PhysAddr,
+    phys_offset: VirtAddr,
+    accessed_dirty: bool,
+}
+
+impl Ept {
+    /// Allocates an empty PML4.
+    ///
+    /// # Safety
+    ///
+    /// Caller should ensure that the whole physical memory is mapped at
+    /// phys_offset, and that allocator returns unused frames.
+    pub unsafe fn new<A: FrameAllocator<Size4KiB>>(
+        allocator: &mut A,
+        phys_offset: VirtAddr,
+    ) -> Result<Self, EptError> {
+        let mut ept = Self {
+            pml4: PhysAddr::zero(),
+            phys_offset,
+            accessed_dirty: false,
+        };
+        ept.pml4 = ept.alloc_table(allocator)?;
+        Ok(ept)
+    }
+
+    /// Enables the accessed and dirty flags in the EPTP. The CPU support is
+    /// reported by `EptVpidCap::accessed_dirty`.
+    pub fn set_accessed_dirty(&mut self, enabled: bool) {
+        self.accessed_dirty = enabled;
+    }
+
+    pub fn pml4(&self) -> PhysAddr {
+        self.pml4
+    }
+
+    /// Returns the EPTP value to write in the EPT_POINTER VMCS field, with
+    /// write-back paging structures and a 4-level page walk.
+    pub fn eptp(&self) -> u64 {
+        let mut eptp = self.pml4.as_u64() | MemoryType::WriteBack as u64 | EPTP_PAGE_WALK_4;
+        if self.accessed_dirty {
+            eptp |= EPTP_ACCESSED_DIRTY;
+        }
+        eptp
+    }
+
+    /// Invalidates the translations derived from this EPT (single-context
+    /// INVEPT), to be called after changing mappings of an EPT in use.
+    ///
+    /// # Safety
+    ///
+    /// Caller should ensure that the CPU is in VMX root operation, with
+    /// single-context INVEPT supported (`EptVpidCap`).
+    pub unsafe fn invalidate(&self) -> Result<(), VirtError> {
+        asm_invept(InveptType::SingleContext, self.eptp())
+    }
+
+    /// Maps [gpa, gpa + size) to [hpa, hpa + size), using the largest pages
+    /// up to attrs.max_page_size allowed by the alignment of both ranges.
+    ///
+    /// The caller is responsible for invalidating the EPT-derived
+    /// translations ([`Ept::invalidate`]) if the EPT is in use.
+    pub fn map<A: FrameAllocator<Size4KiB>>(
+        &mut self,
+        gpa: u64,
+        hpa: u64,
+        size: u64,
+        attrs: EptAttributes,
+        allocator: &mut A,
+    ) -> Result<(), EptError> {
+        check_range(gpa, size)?;
+        if hpa & 0xfff != 0 {
+            return Err(EptError::Misaligned);
+        }
+        if hpa.checked_add(size).is_none_or(|end| end > MAX_HPA) {
+            return Err(EptError::OutOfRange);
+        }
+
+        let mut offset = 0;
+        while offset < size {
+            let page_size = [
+                EptPageSize::Size1GiB,
+                EptPageSize::Size2MiB,
+                EptPageSize::Size4KiB,
+            ]
+            .into_iter()
+            .find(|&s| {
+                let len = s.size();
+                s <= attrs.max_page_size
+                    && (gpa + offset).is_multiple_of(len)
+                    && (hpa + offset).is_multiple_of(len)
+                    && size - offset >= len
+            })
+            .unwrap_or(EptPageSize::Size4KiB);
+
+            if let Err(e) = self.map_page(gpa + offset, hpa + offset, page_size, attrs, allocator) {
+                // Exactly the pages mapped so far are unmapped, none is split.
+                self.unmap(gpa, offset, allocator)
+                    .expect("failed to roll back a partial EPT mapping");
+                return Err(e);
+            }
+            offset += page_size.size();
+        }
+
+        Ok(())
+    }
+
+    /// Unmaps [gpa, gpa + size), splitting large pages partially covered by
+    /// the range.
+    pub fn unmap<A: FrameAllocator<Size4KiB>>(
+        &mut self,
+        gpa: u64,
+        size: u64,
+        allocator: &mut A,
+    ) -> Result<(), EptError> {
+        self.for_each_leaf(gpa, size, allocator, |entry| *entry = 0)
+    }
+
+    /// Changes the access rights of [gpa, gpa + size), splitting large pages
+    /// partially covered by the range.
+    pub fn protect<A: FrameAllocator<Size4KiB>>(
+        &mut self,
+        gpa: u64,
+        size: u64,
+        flags: EptFlags,
+        allocator: &mut A,
+    ) -> Result<(), EptError> {
+        self.for_each_leaf(gpa, size, allocator, |entry| {
+            *entry = (*entry & !EptFlags::ALL.bits()) | flags.bits()
+        })
+    }
+
+    /// Clears the accessed and dirty flags of the leaf entries covering
+    /// [gpa, gpa + size).
+    pub fn clear_accessed_dirty<A: FrameAllocator<Size4KiB>>(
+        &mut self,
+        gpa: u64,
+        size: u64,
+        allocator: &mut A,
+    ) -> Result<(), EptError> {
+        self.for_each_leaf(gpa, size, allocator, |entry| *entry &= !(ACCESSED | DIRTY))
+    }
+
+    /// Walks the tables to translate gpa.
+    pub fn translate(&self, gpa: u64) -> Option<EptMapping> {
+        let mut table = self.pml4;
+        for level in (1..=4).rev() {
+            // SAFETY: table was allocated by this Ept.
+            let entry = unsafe { self.table(table) }.0[index(gpa, level)];
+            if !is_present(entry, level) {
+                return None;
+            }
+
+            if is_leaf(entry, level) {
+                let offset = gpa & (level_size(level) - 1);
+                return Some(EptMapping {
+                    hpa: PhysAddr::new((entry & ADDR_MASK & !(level_size(level) - 1)) + offset),
+                    page_size: EptPageSize::from_level(level),
+                    flags: EptFlags(entry & EptFlags::ALL.bits()),
+                    memory_type: MemoryType::from_bits(
+                        (entry & MEMORY_TYPE_MASK) >> MEMORY_TYPE_SHIFT,
+                    ),
+                    ignore_pat: entry & IGNORE_PAT != 0,
+                    accessed: entry & ACCESSED != 0,
+                    dirty: entry & DIRTY != 0,
+                });
+            }
+
+            table = PhysAddr::new(entry & ADDR_MASK);
+        }
+
+        None
+    }
+
+    fn map_page<A: FrameAllocator<Size4KiB>>(
+        &mut self,
+        gpa: u64,
+        hpa: u64,
+        page_size: EptPageSize,
+        attrs: EptAttributes,
+        allocator: &mut A,
+    ) -> Result<(), EptError> {
+        let target = page_size.level();
+        let mut table = self.pml4;
+
+        for level in (target + 1..=4).rev() {
+            // SAFETY: table was allocated by this Ept.
+            let entry = unsafe { self.table(table) }.0[index(gpa, level)];
+            table = if !is_present(entry, level) {
+                let next = self.alloc_table(allocator)?;
+                // SAFETY: table was allocated by this Ept.
+                unsafe { self.table(table) }.0[index(gpa, level)] =
+                    next.as_u64() | EptFlags::ALL.bits();
+                next
+            } else if is_leaf(entry, level) {
+                return Err(EptError::AlreadyMapped);
+            } else {
+                PhysAddr::new(entry & ADDR_MASK)
+            };
+        }
+
+        // SAFETY: table was allocated by this Ept.
+        let leaf = &mut unsafe { self.table(table) }.0[index(gpa, target)];
+        if is_present(*leaf, target) {
+            return Err(EptError::AlreadyMapped);
+        }
+
+        *leaf =
+            hpa | attrs.flags.bits() | ((attrs.memory_type as u64) << MEMORY_TYPE_SHIFT) | MAPPED;
+        if target > 1 {
+            *leaf |= LARGE_PAGE;
+        }
+
+        Ok(())
+    }
+
+    /// Calls f on every leaf entry covering [gpa, gpa + size). Large pages
+    /// partially covered by the range are split first.
+    fn for_each_leaf<A, F>(
+        &mut self,
+        gpa: u64,
+        size: u64,
+        allocator: &mut A,
+        mut f: F,
+    ) -> Result<(), EptError>
+    where
+        A: FrameAllocator<Size4KiB>,
+        F: FnMut(&mut u64),
+    {
+        check_range(gpa, size)?;
+
+        let end = gpa + size;
+        let mut addr = gpa;
+        while addr < end {
+            let (entry, level) = self.leaf_entry(addr, end - addr, allocator)?;
+            f(entry);
+            addr += level_size(level);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the leaf entry mapping gpa, splitting it if it is a large page
+    /// that isn't aligned on gpa or bigger than len.
+    fn leaf_entry<A: FrameAllocator<Size4KiB>>(
+        &mut self,
+        gpa: u64,
+        len: u64,
+        allocator: &mut A,
+    ) -> Result<(&mut u64, usize), EptError> {
+        let mut table = self.pml4;
+        let mut level = 4;
+        loop {
+            // SAFETY: table was allocated by this Ept.
+            let entry = unsafe { self.table(table) }.0[index(gpa, level)];
+            if !is_present(entry, level) {
+                return Err(EptError::NotMapped);
+            }
+
+            if is_leaf(entry, level) {
+                let size = level_size(level);
+                if gpa.is_multiple_of(size) && len >= size {
+                    // SAFETY: table was allocated by this Ept.
+                    let entry = &mut unsafe { self.table(table) }.0[index(gpa, level)];
+                    return Ok((entry, level));
+                }
+
+                let split = self.split(entry, level, allocator)?;
+                // SAFETY: table was allocated by this Ept.
+                unsafe { self.table(table) }.0[index(gpa, level)] =
+                    split.as_u64() | EptFlags::ALL.bits();
+                table = split;
+            } else {
+                table = PhysAddr::new(entry & ADDR_MASK);
+            }
+
+            level -= 1;
+        }
+    }
+
+    /// Allocates a table mapping the same range as the large page entry,
+    /// with pages of the next smaller size.
+    fn split<A: FrameAllocator<Size4KiB>>(
+        &mut self,
+        entry: u64,
+        level: usize,
+        allocator: &mut A,
+    ) -> Result<PhysAddr, EptError> {
+        let table = self.alloc_table(allocator)?;
+        let child_size = level_size(level - 1);
+        let base = entry & ADDR_MASK & !(level_size(level) - 1);
+        let mut attrs = entry & !ADDR_MASK;
+        if level - 1 == 1 {
+            attrs &= !LARGE_PAGE;
+        }
+
+        // SAFETY: table was just allocated by this Ept.
+        let children = unsafe { self.table(table) };
+        for (i, child) in children.0.iter_mut().enumerate() {
+            *child = (base + i as u64 * child_size) | attrs;
+        }
+
+        Ok(table)
+    }
+
+    /// Returns the frames of all the tables to deallocator.
+    ///
+    /// # Safety
+    ///
+    /// Caller should ensure that no VMCS references the EPT anymore, and that
+    /// the translations derived from it were invalidated.
+    pub unsafe fn destroy<D: FrameDeallocator<Size4KiB>>(self, deallocator: &mut D) {
+        self.free_table(self.pml4, 4, deallocator);
+    }
+
+    /// Frees table, at level, and the tables it references.
+    fn free_table<D: FrameDeallocator<Size4KiB>>(
+        &self,
+        table: PhysAddr,
+        level: usize,
+        deallocator: &mut D,
+    ) {
+        if level > 1 {
+            for i in 0..ENTRY_COUNT {
+                // SAFETY: table was allocated by this Ept.
+                let entry = unsafe { self.table(table) }.0[i];
+                if is_present(entry, level) && !is_leaf(entry, level) {
+                    self.free_table(PhysAddr::new(entry & ADDR_MASK), level - 1, deallocator);
+                }
+            }
+        }
+
+        // SAFETY: table was allocated by this Ept, and is no longer referenced.
+        unsafe { deallocator.deallocate_frame(PhysFrame::containing_address(table)) };
+    }
+
+    fn alloc_table<A: FrameAllocator<Size4KiB>>(
+        &mut self,
+        allocator: &mut A,
+    ) -> Result<PhysAddr, EptError> {
+        let frame = allocator
+            .allocate_frame()
+            .ok_or(EptError::FrameAllocationFailed)?;
+        let paddr = frame.start_address();
+        // SAFETY: the frame is unused, so we can safely overwrite it.
+        unsafe { self.table(paddr) }.0.fill(0);
+        Ok(paddr)
+    }
+
+    /// # Safety
+    ///
+    /// Caller should ensure paddr is a table allocated by this Ept, and that
+    /// no other reference to it is alive.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn table(&self, paddr: PhysAddr) -> &mut EptTable {
+        &mut *(self.phys_offset + paddr.as_u64()).as_mut_ptr::<EptTable>()
+    }
+}
+
+/// Checks that [gpa, gpa + size) is aligned and translated by the EPT, so
+/// that the table indexes of its addresses don't wrap around.
+fn check_range(gpa: u64, size: u64) -> Result<(), EptError> {
+    if gpa & 0xfff != 0 || size & 0xfff != 0 {
+        return Err(EptError::Misaligned);
+    }
+    if gpa.checked_add(size).is_none_or(|end| end > MAX_GPA) {
+        return Err(EptError::OutOfRange);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{boxed::Box, vec::Vec};
+
+    /// Hands out heap pages, whose "physical" address is their host virtual
+    /// address, so the Ept is used with a zero phys_offset.
+    struct FakeFrames {
+        frames: Vec<Box<EptTable>>,
+        limit: usize,
+        freed: Vec<PhysAddr>,
+    }
+
+    impl FakeFrames {
+        fn new(limit: usize) -> Self {
+            Self {
+                frames: Vec::new(),
+                limit,
+                freed: Vec::new(),
+            }
+        }
+    }
+
+    impl FrameDeallocator<Size4KiB> for FakeFrames {
+        unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+            self.freed.push(frame.start_address());
+        }
+    }
+
+    unsafe impl FrameAllocator<Size4KiB> for FakeFrames {
+        fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+            if self.frames.len() == self.limit {
+                return None;
+            }
+            // Fill with garbage to check that tables are zeroed.
+            let mut frame = Box::new(EptTable([u64::MAX; ENTRY_COUNT]));
+            let addr = PhysAddr::new(frame.0.as_mut_ptr() as u64);
+            self.frames.push(frame);
+            PhysFrame::from_start_address(addr).ok()
+        }
+    }
+
+    fn ept(frames: &mut FakeFrames) -> Ept {
+        unsafe { Ept::new(frames, VirtAddr::zero()) }.unwrap()
+    }
+
+    const GIB: u64 = EptPageSize::Size1GiB.size();
+    const MIB2: u64 = EptPageSize::Size2MiB.size();
+
+    #[test]
+    fn eptp() {
+        let mut frames = FakeFrames::new(1);
+        let mut ept = ept(&mut frames);
+        assert_eq!(ept.eptp(), ept.pml4().as_u64() | 0x1e);
+        ept.set_accessed_dirty(true);
+        assert_eq!(ept.eptp(), ept.pml4().as_u64() | 0x5e);
+    }
+
+    #[test]
+    fn map_4k() {
+        let mut frames = FakeFrames::new(16);
+        let mut ept = ept(&mut frames);
+        ept.map(
+            0x1000,
+            0x20_3000,
+            0x2000,
+            EptAttributes::new(
+                EptFlags::READ | EptFlags::WRITE,
+                MemoryType::WriteBack,
+                EptPageSize::Size4KiB,
+            ),
+            &mut frames,
+        )
+        .unwrap();
+
+        // PML4 + PDPT + PD + PT
+        assert_eq!(frames.frames.len(), 4);
+        let m = ept.translate(0x1234).unwrap();
+        assert_eq!(m.hpa, PhysAddr::new(0x20_3234));
+        assert_eq!(m.page_size, EptPageSize::Size4KiB);
+        assert_eq!(m.flags, EptFlags::READ | EptFlags::WRITE);
+        assert_eq!(m.memory_type, Some(MemoryType::WriteBack));
+        assert!(!m.accessed && !m.dirty);
+        assert_eq!(ept.translate(0x2fff).unwrap().hpa, PhysAddr::new(0x20_4fff));
+        assert!(ept.translate(0x3000).is_none());
+        assert!(ept.translate(0).is_none());
+    }
+
+    #[test]
+    fn map_picks_largest_pages() {
+        let mut frames = FakeFrames::new(16);
+        let mut ept = ept(&mut frames);
+        // [1G - 2M, 2G + 4K) -> one 2M page, one 1G page, one 4K page.
+        ept.map(
+            GIB - MIB2,
+            GIB - MIB2,
+            GIB + MIB2 + 0x1000,
+            EptAttributes::new(EptFlags::RWX, MemoryType::WriteBack, EptPageSize::Size1GiB),
+            &mut frames,
+        )
+        .unwrap();
+
+        let sizes = [
+            (GIB - MIB2, EptPageSize::Size2MiB),
+            (GIB, EptPageSize::Size1GiB),
+            (2 * GIB - 1, EptPageSize::Size1GiB),
+            (2 * GIB, EptPageSize::Size4KiB),
+        ];
+        for (gpa, size) in sizes {
+            let m = ept.translate(gpa).unwrap();
+            assert_eq!(m.page_size, size, "{:#x}", gpa);
+            assert_eq!(m.hpa.as_u64(), gpa);
+        }
+        assert!(ept.translate(2 * GIB + 0x1000).is_none());
+    }
+
+    #[test]
+    fn map_respects_max_and_alignment() {
+        let mut frames = FakeFrames::new(16);
+        let mut ept = ept(&mut frames);
+        ept.map(
+            0,
+            0,
+            2 * MIB2,
+            EptAttributes::new(
+                EptFlags::RWX,
+                MemoryType::Uncacheable,
+                EptPageSize::Size4KiB,
+            ),
+            &mut frames,
+        )
+        .unwrap();
+        assert_eq!(
+            ept.translate(MIB2).unwrap().page_size,
+            EptPageSize::Size4KiB
+        );
+
+        // hpa isn't 2M aligned, so 4K pages must be used.
+        ept.map(
+            GIB,
+            MIB2 + 0x1000,
+            MIB2,
+            EptAttributes::new(EptFlags::RWX, MemoryType::WriteBack, EptPageSize::Size1GiB),
+            &mut frames,
+        )
+        .unwrap();
+        assert_eq!(ept.translate(GIB).unwrap().page_size, EptPageSize::Size4KiB);
+    }
+
+    #[test]
+    fn errors() {
+        let mut frames = FakeFrames::new(4);
+        let mut ept = ept(&mut frames);
+        let map = |ept: &mut Ept, frames: &mut FakeFrames, gpa, size| {
+            ept.map(
+                gpa,
+                gpa,
+                size,
+                EptAttributes::new(EptFlags::RWX, MemoryType::WriteBack, EptPageSize::Size4KiB),
+                frames,
+            )
+        };
+
+        assert_eq!(
+            map(&mut ept, &mut frames, 0x10, 0x1000),
+            Err(EptError::Misaligned)
+        );
+        assert_eq!(
+            map(&mut ept, &mut frames, 0, 0x10),
+            Err(EptError::Misaligned)
+        );
+        map(&mut ept, &mut frames, 0, 0x1000).unwrap();
+        assert_eq!(
+            map(&mut ept, &mut frames, 0, 0x1000),
+            Err(EptError::AlreadyMapped)
+        );
+        assert_eq!(
+            ept.unmap(0x1000, 0x1000, &mut frames),
+            Err(EptError::NotMapped)
+        );
+        assert_eq!(
+            map(&mut ept, &mut frames, MAX_GPA - 0x1000, 0x2000),
+            Err(EptError::OutOfRange)
+        );
+        assert_eq!(
+            map(&mut ept, &mut frames, 0xffff_ffff_ffff_f000, 0x1000),
+            Err(EptError::OutOfRange)
+        );
+        assert_eq!(
+            ept.map(
+                0x1000,
+                MAX_HPA,
+                0x1000,
+                EptAttributes::new(EptFlags::RWX, MemoryType::WriteBack, EptPageSize::Size4KiB),
+                &mut frames,
+            ),
+            Err(EptError::OutOfRange)
+        );
+        assert_eq!(
+            ept.unmap(MAX_GPA, 0x1000, &mut frames),
+            Err(EptError::OutOfRange)
+        );
+        // Needs a new PDPT, PD and PT but the allocator is exhausted.
+        assert_eq!(
+            map(&mut ept, &mut frames, 1 << 39, 0x1000),
+            Err(EptError::FrameAllocationFailed)
+        );
+    }
+
+    #[test]
+    fn unmap_splits_large_pages() {
+        let mut frames = FakeFrames::new(16);
+        let mut ept = ept(&mut frames);
+        ept.map(
+            0,
+            0x4000_0000,
+            GIB,
+            EptAttributes::new(EptFlags::RWX, MemoryType::WriteBack, EptPageSize::Size1GiB),
+            &mut frames,
+        )
+        .unwrap();
+        let before = frames.frames.len();
+
+        ept.unmap(MIB2 + 0x1000, 0x1000, &mut frames).unwrap();
+        // One PD for the 1G page, one PT for the 2M page.
+        assert_eq!(frames.frames.len(), before + 2);
+
+        assert!(ept.translate(MIB2 + 0x1000).is_none());
+        let m = ept.translate(MIB2).unwrap();
+        assert_eq!(m.page_size, EptPageSize::Size4KiB);
+        assert_eq!(m.hpa.as_u64(), 0x4000_0000 + MIB2);
+        let m = ept.translate(MIB2 + 0x2000).unwrap();
+        assert_eq!(m.hpa.as_u64(), 0x4000_0000 + MIB2 + 0x2000);
+        let m = ept.translate(0).unwrap();
+        assert_eq!(m.page_size, EptPageSize::Size2MiB);
+        assert_eq!(m.memory_type, Some(MemoryType::WriteBack));
+        let m = ept.translate(GIB - 1).unwrap();
+        assert_eq!(m.page_size, EptPageSize::Size2MiB);
+        assert_eq!(m.hpa.as_u64(), 0x4000_0000 + GIB - 1);
+    }
+
+    #[test]
+    fn protect() {
+        let mut frames = FakeFrames::new(16);
+        let mut ept = ept(&mut frames);
+        ept.map(
+            0,
+            0,
+            MIB2,
+            EptAttributes::new(EptFlags::RWX, MemoryType::WriteBack, EptPageSize::Size2MiB),
+            &mut frames,
+        )
+        .unwrap();
+
+        ept.protect(0, MIB2, EptFlags::READ, &mut frames).unwrap();
+        let m = ept.translate(0x1000).unwrap();
+        assert_eq!(m.flags, EptFlags::READ);
+        assert_eq!(m.page_size, EptPageSize::Size2MiB);
+
+        ept.protect(0x1000, 0x1000, EptFlags::NONE, &mut frames)
+            .unwrap();
+        assert_eq!(ept.translate(0x1000).unwrap().flags, EptFlags::NONE);
+        assert_eq!(ept.translate(0x2000).unwrap().flags, EptFlags::READ);
+
+        // A page protected to no access is still tracked and can be restored.
+        ept.protect(0x1000, 0x1000, EptFlags::RWX, &mut frames)
+            .unwrap();
+        assert_eq!(ept.translate(0x1000).unwrap().flags, EptFlags::RWX);
+        assert_eq!(ept.translate(0x1000).unwrap().hpa.as_u64(), 0x1000);
+    }
+
+    #[test]
+    fn unreadable_page_zero_is_mapped() {
+        let mut frames = FakeFrames::new(16);
+        let mut ept = ept(&mut frames);
+        let attrs = EptAttributes::new(
+            EptFlags::NONE,
+            MemoryType::Uncacheable,
+            EptPageSize::Size4KiB,
+        );
+
+        // The leaf entry holds no address, rights nor memory type.
+        ept.map(0, 0, 0x1000, attrs, &mut frames).unwrap();
+        assert_eq!(ept.translate(0).unwrap().flags, EptFlags::NONE);
+        assert_eq!(
+            ept.map(0, 0, 0x1000, attrs, &mut frames),
+            Err(EptError::AlreadyMapped)
+        );
+        ept.unmap(0, 0x1000, &mut frames).unwrap();
+        assert!(ept.translate(0).is_none());
+    }
+
+    #[test]
+    fn failed_map_rolls_back() {
+        let mut frames = FakeFrames::new(16);
+        let mut ept = ept(&mut frames);
+        let attrs = EptAttributes::new(EptFlags::RWX, MemoryType::WriteBack, EptPageSize::Size4KiB);
+        ept.map(0x3000, 0x3000, 0x1000, attrs, &mut frames).unwrap();
+
+        assert_eq!(
+            ept.map(0, 0, 0x4000, attrs, &mut frames),
+            Err(EptError::AlreadyMapped)
+        );
+        for gpa in [0, 0x1000, 0x2000] {
+            assert!(ept.translate(gpa).is_none(), "{:#x}", gpa);
+        }
+        assert!(ept.translate(0x3000).is_some());
+    }
+
+    #[test]
+    fn destroy_frees_all_tables() {
+        let mut frames = FakeFrames::new(16);
+        let mut ept = ept(&mut frames);
+        ept.map(
+            0,
+            0,
+            GIB + 0x1000,
+            EptAttributes::new(EptFlags::RWX, MemoryType::WriteBack, EptPageSize::Size1GiB),
+            &mut frames,
+        )
+        .unwrap();
+        ept.unmap(MIB2, 0x1000, &mut frames).unwrap();
+
+        unsafe { ept.destroy(&mut frames) };
+        let mut allocated: Vec<PhysAddr> = frames
+            .frames
+            .iter()
+            .map(|frame| PhysAddr::new(frame.0.as_ptr() as u64))
+            .collect();
+        allocated.sort();
+        frames.freed.sort();
+        assert_eq!(frames.freed, allocated);
+    }
+}
@@ -1 +1,111 @@
+//! VM-instruction error numbers, see Intel SDM Vol. 3C, Section 31.4.
+
+use core::fmt;
+
 pub const VM_INSTRUCTION_ERROR: u16 = 0x4400;
+
+macro_rules! vm_instruction_errors {
+    ($($variant:ident = $value:expr => $message:expr,)*) => {
+        /// Error numbers reported in the VM_INSTRUCTION_ERROR field on
+        /// VMfailValid.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum VmInstructionError {
+            $($variant,)*
+            /// Error number not defined by the SDM.
+            Unknown(u32),
+        }
+
+        impl VmInstructionError {
+            pub const ALL: &'static [Self] = &[$(Self::$variant,)*];
+
+            pub const fn from_raw(value: u32) -> Self {
+                match value {
+                    $($value => Self::$variant,)*
+                    _ => Self::Unknown(value),
+                }
+            }
+
+            pub const fn raw(self) -> u32 {
+                match self {
+                    $(Self::$variant => $value,)*
+                    Self::Unknown(value) => value,
+                }
+            }
+
+            /// Returns the SDM description of the error.
+            pub const fn message(self) -> &'static str {
+                match self {
+                    $(Self::$variant => $message,)*
+                    Self::Unknown(_) => "unknown VM-instruction error",
+                }
+            }
+        }
+    };
+}
+
+vm_instruction_errors! {
+    VmcallInVmxRoot = 1 => "VMCALL executed in VMX root operation",
+    VmclearInvalidAddress = 2 => "VMCLEAR with invalid physical address",
+    VmclearVmxonPointer = 3 => "VMCLEAR with VMXON pointer",
+    VmlaunchNonClearVmcs = 4 => "VMLAUNCH with non-clear VMCS",
+    VmresumeNonLaunchedVmcs = 5 => "VMRESUME with non-launched VMCS",
+    VmresumeAfterVmxoff = 6 => "VMRESUME after VMXOFF",
+    EntryInvalidControlFields = 7 => "VM entry with invalid control field(s)",
+    EntryInvalidHostStateFields = 8 => "VM entry with invalid host-state field(s)",
+    VmptrldInvalidAddress = 9 => "VMPTRLD with invalid physical address",
+    VmptrldVmxonPointer = 10 => "VMPTRLD with VMXON pointer",
+    VmptrldIncorrectRevision = 11 => "VMPTRLD with incorrect VMCS revision identifier",
+    UnsupportedVmcsComponent = 12 => "VMREAD/VMWRITE from/to unsupported VMCS component",
+    VmwriteReadOnlyComponent = 13 => "VMWRITE to read-only VMCS component",
+    VmxonInVmxRoot = 15 => "VMXON executed in VMX root operation",
+    EntryInvalidExecutiveVmcsPointer = 16 => "VM entry with invalid executive-VMCS pointer",
+    EntryNonLaunchedExecutiveVmcs = 17 => "VM entry with non-launched executive VMCS",
+    EntryExecutiveVmcsNotVmxonPointer = 18
+        => "VM entry with executive-VMCS pointer not VMXON pointer",
+    VmcallNonClearVmcs = 19 => "VMCALL with non-clear VMCS",
+    VmcallInvalidExitControls = 20 => "VMCALL with invalid VM-exit control fields",
+    VmcallIncorrectMsegRevision = 22 => "VMCALL with incorrect MSEG revision identifier",
+    VmxoffDualMonitor = 23 => "VMXOFF under dual-monitor treatment of SMIs and SMM",
+    VmcallInvalidSmmMonitorFeatures = 24 => "VMCALL with invalid SMM-monitor features",
+    EntryInvalidExecutiveControls = 25
+        => "VM entry with invalid VM-execution control fields in executive VMCS",
+    EntryBlockedByMovSs = 26 => "VM entry with events blocked by MOV SS",
+    InvalidInveptInvvpidOperand = 28 => "invalid operand to INVEPT/INVVPID",
+}
+
+impl fmt::Display for VmInstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (error {})", self.message(), self.raw())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        for &error in VmInstructionError::ALL {
+            assert_eq!(VmInstructionError::from_raw(error.raw()), error);
+            assert_ne!(error.message(), VmInstructionError::Unknown(0).message());
+        }
+    }
+
+    #[test]
+    fn unknown() {
+        for raw in [0, 14, 21, 27, 29, u32::MAX] {
+            assert_eq!(
+                VmInstructionError::from_raw(raw),
+                VmInstructionError::Unknown(raw)
+            );
+        }
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            VmInstructionError::VmlaunchNonClearVmcs.to_string(),
+            "VMLAUNCH with non-clear VMCS (error 4)"
+        );
+    }
+}
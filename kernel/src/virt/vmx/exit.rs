@@ -0,0 +1,761 @@
+//! VM-exit decoding, see Intel SDM Vol. 3C, Chapter 28 and Appendix C.
+
+use core::fmt;
+
+use super::fields::{VmcsField32, VmcsField64, VmcsFieldNatural};
+use super::vcpu::{GuestRegisters, Vcpu};
+use super::vmcs::VMCS;
+use crate::virt::VirtError;
+
+macro_rules! exit_reasons {
+    ($($variant:ident = $value:expr,)*) => {
+        /// Basic exit reasons, bits 15:0 of the exit reason field.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u16)]
+        pub enum BasicExitReason {
+            $($variant = $value,)*
+        }
+
+        impl BasicExitReason {
+            pub const ALL: &'static [Self] = &[$(Self::$variant,)*];
+        }
+
+        impl TryFrom<u16> for BasicExitReason {
+            type Error = u16;
+
+            fn try_from(value: u16) -> Result<Self, u16> {
+                match value {
+                    $($value => Ok(Self::$variant),)*
+                    _ => Err(value),
+                }
+            }
+        }
+    };
+}
+
+exit_reasons! {
+    ExceptionOrNmi = 0,
+    ExternalInterrupt = 1,
+    TripleFault = 2,
+    InitSignal = 3,
+    StartupIpi = 4,
+    IoSmi = 5,
+    OtherSmi = 6,
+    InterruptWindow = 7,
+    NmiWindow = 8,
+    TaskSwitch = 9,
+    Cpuid = 10,
+    Getsec = 11,
+    Hlt = 12,
+    Invd = 13,
+    Invlpg = 14,
+    Rdpmc = 15,
+    Rdtsc = 16,
+    Rsm = 17,
+    Vmcall = 18,
+    Vmclear = 19,
+    Vmlaunch = 20,
+    Vmptrld = 21,
+    Vmptrst = 22,
+    Vmread = 23,
+    Vmresume = 24,
+    Vmwrite = 25,
+    Vmxoff = 26,
+    Vmxon = 27,
+    CrAccess = 28,
+    DrAccess = 29,
+    IoInstruction = 30,
+    Rdmsr = 31,
+    Wrmsr = 32,
+    InvalidGuestState = 33,
+    MsrLoading = 34,
+    Mwait = 36,
+    MonitorTrapFlag = 37,
+    Monitor = 39,
+    Pause = 40,
+    MachineCheck = 41,
+    TprBelowThreshold = 43,
+    ApicAccess = 44,
+    VirtualizedEoi = 45,
+    GdtrIdtrAccess = 46,
+    LdtrTrAccess = 47,
+    EptViolation = 48,
+    EptMisconfig = 49,
+    Invept = 50,
+    Rdtscp = 51,
+    PreemptionTimer = 52,
+    Invvpid = 53,
+    Wbinvd = 54,
+    Xsetbv = 55,
+    ApicWrite = 56,
+    Rdrand = 57,
+    Invpcid = 58,
+    Vmfunc = 59,
+    Encls = 60,
+    Rdseed = 61,
+    PmlFull = 62,
+    Xsaves = 63,
+    Xrstors = 64,
+    Pconfig = 65,
+    SppEvent = 66,
+    Umwait = 67,
+    Tpause = 68,
+    Loadiwkey = 69,
+    Enclv = 70,
+    EnqcmdPasidTranslation = 72,
+    EnqcmdsPasidTranslation = 73,
+    BusLock = 74,
+    InstructionTimeout = 75,
+    Seamcall = 76,
+    Tdcall = 77,
+    Rdmsrlist = 78,
+    Wrmsrlist = 79,
+}
+
+/// Number of slots needed to index a table by basic exit reason.
+pub const BASIC_EXIT_REASON_COUNT: usize = 80;
+
+/// Exit reason as stored in the VM_EXIT_REASON field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitReason {
+    pub basic: u16,
+    pub enclave_mode: bool,
+    pub pending_mtf: bool,
+    pub from_vmx_root: bool,
+    pub entry_failure: bool,
+}
+
+impl From<u32> for ExitReason {
+    fn from(raw: u32) -> Self {
+        Self {
+            basic: raw as u16,
+            enclave_mode: raw & (1 << 27) != 0,
+            pending_mtf: raw & (1 << 28) != 0,
+            from_vmx_root: raw & (1 << 29) != 0,
+            entry_failure: raw & (1 << 31) != 0,
+        }
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match BasicExitReason::try_from(self.basic) {
+            Ok(reason) => write!(f, "{:?}", reason)?,
+            Err(basic) => write!(f, "unknown reason {}", basic)?,
+        }
+        if self.entry_failure {
+            write!(f, " (VM-entry failure)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Raw VMCS exit-information fields, plus the guest registers needed to
+/// decode some exits.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ExitInfo {
+    pub reason: u32,
+    pub qualification: u64,
+    pub instruction_len: u32,
+    pub instruction_info: u32,
+    pub interruption_info: u32,
+    pub interruption_error_code: u32,
+    pub guest_physical_address: u64,
+    pub guest_linear_address: u64,
+    pub regs: GuestRegisters,
+}
+
+impl ExitInfo {
+    /// Reads the exit-information fields of the current VMCS.
+    ///
+    /// The guest-physical and guest-linear addresses are only read for EPT
+    /// exits, the guest-physical address field only existing with EPT
+    /// support.
+    pub fn read(vmcs: &VMCS, regs: &GuestRegisters) -> Result<Self, VirtError> {
+        let mut info = Self {
+            reason: vmcs.vmread(VmcsField32::VmExitReason)?,
+            qualification: vmcs.vmread(VmcsFieldNatural::ExitQualification)?,
+            instruction_len: vmcs.vmread(VmcsField32::VmExitInstructionLength)?,
+            instruction_info: vmcs.vmread(VmcsField32::VmExitInstructionInfo)?,
+            interruption_info: vmcs.vmread(VmcsField32::VmExitInterruptionInfo)?,
+            interruption_error_code: vmcs.vmread(VmcsField32::VmExitInterruptionErrorCode)?,
+            regs: *regs,
+            ..Default::default()
+        };
+
+        let basic = BasicExitReason::try_from(ExitReason::from(info.reason).basic);
+        if let Ok(BasicExitReason::EptViolation | BasicExitReason::EptMisconfig) = basic {
+            info.guest_physical_address = vmcs.vmread(VmcsField64::GuestPhysicalAddress)?;
+        }
+        if basic == Ok(BasicExitReason::EptViolation) {
+            info.guest_linear_address = vmcs.vmread(VmcsFieldNatural::GuestLinearAddress)?;
+        }
+        Ok(info)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptionType {
+    External,
+    Nmi,
+    HardwareException,
+    SoftwareInterrupt,
+    PrivilegedSoftwareException,
+    SoftwareException,
+    Other,
+}
+
+/// VM-exit interruption-information field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptionInfo {
+    pub vector: u8,
+    pub kind: InterruptionType,
+    pub error_code: Option<u32>,
+    pub nmi_unblocking: bool,
+}
+
+impl InterruptionInfo {
+    pub fn decode(info: u32, error_code: u32) -> Self {
+        let kind = match (info >> 8) & 0x7 {
+            0 => InterruptionType::External,
+            2 => InterruptionType::Nmi,
+            3 => InterruptionType::HardwareException,
+            4 => InterruptionType::SoftwareInterrupt,
+            5 => InterruptionType::PrivilegedSoftwareException,
+            6 => InterruptionType::SoftwareException,
+            _ => InterruptionType::Other,
+        };
+
+        Self {
+            vector: info as u8,
+            kind,
+            error_code: (info & (1 << 11) != 0).then_some(error_code),
+            nmi_unblocking: info & (1 << 12) != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrAccessType {
+    MovToCr,
+    MovFromCr,
+    Clts,
+    Lmsw,
+}
+
+/// Exit qualification for control-register accesses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrAccessQualification {
+    pub cr: u8,
+    pub access: CrAccessType,
+    pub lmsw_memory_operand: bool,
+    pub gpr: u8,
+    pub lmsw_source: u16,
+}
+
+impl CrAccessQualification {
+    pub fn decode(qualification: u64) -> Self {
+        let access = match (qualification >> 4) & 0x3 {
+            0 => CrAccessType::MovToCr,
+            1 => CrAccessType::MovFromCr,
+            2 => CrAccessType::Clts,
+            _ => CrAccessType::Lmsw,
+        };
+
+        Self {
+            cr: (qualification & 0xf) as u8,
+            access,
+            lmsw_memory_operand: qualification & (1 << 6) != 0,
+            gpr: ((qualification >> 8) & 0xf) as u8,
+            lmsw_source: (qualification >> 16) as u16,
+        }
+    }
+}
+
+/// Exit qualification for I/O instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoQualification {
+    pub size: u8,
+    pub input: bool,
+    pub string: bool,
+    pub rep: bool,
+    pub immediate: bool,
+    pub port: u16,
+}
+
+impl IoQualification {
+    pub fn decode(qualification: u64) -> Self {
+        Self {
+            size: (qualification & 0x7) as u8 + 1,
+            input: qualification & (1 << 3) != 0,
+            string: qualification & (1 << 4) != 0,
+            rep: qualification & (1 << 5) != 0,
+            immediate: qualification & (1 << 6) != 0,
+            port: (qualification >> 16) as u16,
+        }
+    }
+}
+
+/// Exit qualification for EPT violations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptViolationQualification {
+    pub read: bool,
+    pub write: bool,
+    pub fetch: bool,
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+    pub user_executable: bool,
+    pub linear_address_valid: bool,
+    /// Only meaningful if `linear_address_valid` is set: the access was to
+    /// the translated linear address rather than a paging-structure entry.
+    pub translated_access: bool,
+    pub nmi_unblocking: bool,
+}
+
+impl EptViolationQualification {
+    pub fn decode(qualification: u64) -> Self {
+        let bit = |n: u32| qualification & (1 << n) != 0;
+        Self {
+            read: bit(0),
+            write: bit(1),
+            fetch: bit(2),
+            readable: bit(3),
+            writable: bit(4),
+            executable: bit(5),
+            user_executable: bit(6),
+            linear_address_valid: bit(7),
+            translated_access: bit(8),
+            nmi_unblocking: bit(12),
+        }
+    }
+}
+
+/// VM-exit instruction-information field for VMX instructions, INVEPT,
+/// INVVPID and INVPCID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmxInstructionInfo {
+    pub scaling: u8,
+    pub address_size: u8,
+    pub register_operand: bool,
+    pub segment: u8,
+    pub index: Option<u8>,
+    pub base: Option<u8>,
+    pub reg2: u8,
+}
+
+impl VmxInstructionInfo {
+    pub fn decode(info: u32) -> Self {
+        Self {
+            scaling: (info & 0x3) as u8,
+            address_size: ((info >> 7) & 0x7) as u8,
+            register_operand: info & (1 << 10) != 0,
+            segment: ((info >> 15) & 0x7) as u8,
+            index: (info & (1 << 22) == 0).then_some(((info >> 18) & 0xf) as u8),
+            base: (info & (1 << 27) == 0).then_some(((info >> 23) & 0xf) as u8),
+            reg2: ((info >> 28) & 0xf) as u8,
+        }
+    }
+}
+
+/// A decoded VM exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmExit {
+    ExceptionOrNmi(InterruptionInfo),
+    ExternalInterrupt(InterruptionInfo),
+    TripleFault,
+    Cpuid {
+        leaf: u32,
+        subleaf: u32,
+    },
+    Hlt,
+    Vmcall,
+    /// Any VMX instruction (VMCLEAR, VMPTRLD, ..., INVEPT, INVVPID) executed
+    /// by the guest.
+    VmxInstruction {
+        instruction: BasicExitReason,
+        info: VmxInstructionInfo,
+        displacement: u64,
+    },
+    CrAccess(CrAccessQualification),
+    IoInstruction(IoQualification),
+    Rdmsr {
+        msr: u32,
+    },
+    Wrmsr {
+        msr: u32,
+        value: u64,
+    },
+    EptViolation {
+        qualification: EptViolationQualification,
+        gpa: u64,
+        gla: Option<u64>,
+    },
+    EptMisconfig {
+        gpa: u64,
+    },
+    PreemptionTimer,
+    /// VM entry failed, after the VMLAUNCH/VMRESUME instruction itself
+    /// succeeded.
+    EntryFailure {
+        reason: BasicExitReason,
+        qualification: u64,
+    },
+    Other {
+        reason: BasicExitReason,
+        qualification: u64,
+    },
+}
+
+impl VmExit {
+    pub fn decode(info: &ExitInfo) -> Result<Self, VirtError> {
+        let exit_reason = ExitReason::from(info.reason);
+        let reason = BasicExitReason::try_from(exit_reason.basic)
+            .map_err(|_| VirtError::UnknownExitReason(info.reason))?;
+        let qualification = info.qualification;
+
+        if exit_reason.entry_failure {
+            return Ok(Self::EntryFailure {
+                reason,
+                qualification,
+            });
+        }
+
+        let exit = match reason {
+            BasicExitReason::ExceptionOrNmi => Self::ExceptionOrNmi(InterruptionInfo::decode(
+                info.interruption_info,
+                info.interruption_error_code,
+            )),
+            BasicExitReason::ExternalInterrupt => Self::ExternalInterrupt(
+                InterruptionInfo::decode(info.interruption_info, info.interruption_error_code),
+            ),
+            BasicExitReason::TripleFault => Self::TripleFault,
+            BasicExitReason::Cpuid => Self::Cpuid {
+                leaf: info.regs.rax as u32,
+                subleaf: info.regs.rcx as u32,
+            },
+            BasicExitReason::Hlt => Self::Hlt,
+            BasicExitReason::Vmcall => Self::Vmcall,
+            BasicExitReason::Vmclear
+            | BasicExitReason::Vmlaunch
+            | BasicExitReason::Vmptrld
+            | BasicExitReason::Vmptrst
+            | BasicExitReason::Vmread
+            | BasicExitReason::Vmresume
+            | BasicExitReason::Vmwrite
+            | BasicExitReason::Vmxoff
+            | BasicExitReason::Vmxon
+            | BasicExitReason::Invept
+            | BasicExitReason::Invvpid => Self::VmxInstruction {
+                instruction: reason,
+                info: VmxInstructionInfo::decode(info.instruction_info),
+                displacement: qualification,
+            },
+            BasicExitReason::CrAccess => {
+                Self::CrAccess(CrAccessQualification::decode(qualification))
+            }
+            BasicExitReason::IoInstruction => {
+                Self::IoInstruction(IoQualification::decode(qualification))
+            }
+            BasicExitReason::Rdmsr => Self::Rdmsr {
+                msr: info.regs.rcx as u32,
+            },
+            BasicExitReason::Wrmsr => Self::Wrmsr {
+                msr: info.regs.rcx as u32,
+                value: (info.regs.rdx << 32) | (info.regs.rax & 0xffff_ffff),
+            },
+            BasicExitReason::EptViolation => {
+                let qualification = EptViolationQualification::decode(qualification);
+                Self::EptViolation {
+                    qualification,
+                    gpa: info.guest_physical_address,
+                    gla: qualification
+                        .linear_address_valid
+                        .then_some(info.guest_linear_address),
+                }
+            }
+            BasicExitReason::EptMisconfig => Self::EptMisconfig {
+                gpa: info.guest_physical_address,
+            },
+            BasicExitReason::PreemptionTimer => Self::PreemptionTimer,
+            _ => Self::Other {
+                reason,
+                qualification,
+            },
+        };
+
+        Ok(exit)
+    }
+
+    /// Returns the basic exit reason this exit was decoded from.
+    pub fn reason(&self) -> BasicExitReason {
+        match self {
+            Self::ExceptionOrNmi(_) => BasicExitReason::ExceptionOrNmi,
+            Self::ExternalInterrupt(_) => BasicExitReason::ExternalInterrupt,
+            Self::TripleFault => BasicExitReason::TripleFault,
+            Self::Cpuid { .. } => BasicExitReason::Cpuid,
+            Self::Hlt => BasicExitReason::Hlt,
+            Self::Vmcall => BasicExitReason::Vmcall,
+            Self::VmxInstruction { instruction, .. } => *instruction,
+            Self::CrAccess(_) => BasicExitReason::CrAccess,
+            Self::IoInstruction(_) => BasicExitReason::IoInstruction,
+            Self::Rdmsr { .. } => BasicExitReason::Rdmsr,
+            Self::Wrmsr { .. } => BasicExitReason::Wrmsr,
+            Self::EptViolation { .. } => BasicExitReason::EptViolation,
+            Self::EptMisconfig { .. } => BasicExitReason::EptMisconfig,
+            Self::PreemptionTimer => BasicExitReason::PreemptionTimer,
+            Self::EntryFailure { reason, .. } | Self::Other { reason, .. } => *reason,
+        }
+    }
+}
+
+/// What to do once an exit handler returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitAction {
+    /// Re-enter the guest.
+    Resume,
+    /// Return the exit to the caller of [`Vcpu::run_with`].
+    Stop,
+}
+
+pub type ExitHandler = fn(&mut Vcpu, &VmExit) -> Result<ExitAction, VirtError>;
+
+/// Per-exit-reason handler table.
+#[derive(Debug, Clone)]
+pub struct ExitHandlers {
+    handlers: [Option<ExitHandler>; BASIC_EXIT_REASON_COUNT],
+}
+
+impl ExitHandlers {
+    pub const fn new() -> Self {
+        Self {
+            handlers: [None; BASIC_EXIT_REASON_COUNT],
+        }
+    }
+
+    /// Registers handler for reason, returning the previous one if any.
+    pub fn register(
+        &mut self,
+        reason: BasicExitReason,
+        handler: ExitHandler,
+    ) -> Option<ExitHandler> {
+        self.handlers[reason as usize].replace(handler)
+    }
+
+    pub fn unregister(&mut self, reason: BasicExitReason) -> Option<ExitHandler> {
+        self.handlers[reason as usize].take()
+    }
+
+    pub fn get(&self, reason: BasicExitReason) -> Option<ExitHandler> {
+        self.handlers[reason as usize]
+    }
+}
+
+impl Default for ExitHandlers {
+    fn default() -> Self {
+        ExitHandlers::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(reason: u32, qualification: u64) -> ExitInfo {
+        ExitInfo {
+            reason,
+            qualification,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn basic_reasons_roundtrip() {
+        for &reason in BasicExitReason::ALL {
+            assert_eq!(BasicExitReason::try_from(reason as u16), Ok(reason));
+            assert!((reason as usize) < BASIC_EXIT_REASON_COUNT);
+        }
+        assert_eq!(BasicExitReason::try_from(35), Err(35));
+        assert_eq!(BasicExitReason::try_from(0xffff), Err(0xffff));
+    }
+
+    #[test]
+    fn unknown_reason() {
+        assert!(matches!(
+            VmExit::decode(&info(35, 0)),
+            Err(VirtError::UnknownExitReason(35))
+        ));
+    }
+
+    #[test]
+    fn simple_exits() {
+        assert_eq!(VmExit::decode(&info(12, 0)).unwrap(), VmExit::Hlt);
+        assert_eq!(VmExit::decode(&info(18, 0)).unwrap(), VmExit::Vmcall);
+        assert_eq!(VmExit::decode(&info(2, 0)).unwrap(), VmExit::TripleFault);
+        assert_eq!(
+            VmExit::decode(&info(54, 0x1234)).unwrap(),
+            VmExit::Other {
+                reason: BasicExitReason::Wbinvd,
+                qualification: 0x1234
+            }
+        );
+    }
+
+    #[test]
+    fn entry_failure() {
+        let exit = VmExit::decode(&info((1 << 31) | 33, 0)).unwrap();
+        assert_eq!(
+            exit,
+            VmExit::EntryFailure {
+                reason: BasicExitReason::InvalidGuestState,
+                qualification: 0
+            }
+        );
+        assert_eq!(exit.reason(), BasicExitReason::InvalidGuestState);
+    }
+
+    #[test]
+    fn cpuid_and_msr() {
+        let mut i = info(10, 0);
+        i.regs.rax = 0xdead_0000_0000_0007;
+        i.regs.rcx = 1;
+        assert_eq!(
+            VmExit::decode(&i).unwrap(),
+            VmExit::Cpuid {
+                leaf: 7,
+                subleaf: 1
+            }
+        );
+
+        let mut i = info(32, 0);
+        i.regs.rcx = 0xc000_0080;
+        i.regs.rdx = 0x1;
+        i.regs.rax = 0xffff_ffff_0000_0d01;
+        assert_eq!(
+            VmExit::decode(&i).unwrap(),
+            VmExit::Wrmsr {
+                msr: 0xc000_0080,
+                value: 0x1_0000_0d01
+            }
+        );
+    }
+
+    #[test]
+    fn io_instruction() {
+        // out dx, al to 0x3f8
+        let exit = VmExit::decode(&info(30, 0x03f8_0000)).unwrap();
+        let VmExit::IoInstruction(io) = exit else {
+            panic!("unexpected exit {:?}", exit);
+        };
+        assert_eq!(io.port, 0x3f8);
+        assert_eq!(io.size, 1);
+        assert!(!io.input && !io.string && !io.rep && !io.immediate);
+
+        // rep insd from an immediate port
+        let exit = VmExit::decode(&info(30, 0x0060_0000 | 0x7b)).unwrap();
+        let VmExit::IoInstruction(io) = exit else {
+            panic!("unexpected exit {:?}", exit);
+        };
+        assert_eq!(io.port, 0x60);
+        assert_eq!(io.size, 4);
+        assert!(io.input && io.string && io.rep && io.immediate);
+    }
+
+    #[test]
+    fn cr_access() {
+        // mov cr3, rbx
+        let exit = VmExit::decode(&info(28, 0x303)).unwrap();
+        assert_eq!(
+            exit,
+            VmExit::CrAccess(CrAccessQualification {
+                cr: 3,
+                access: CrAccessType::MovToCr,
+                lmsw_memory_operand: false,
+                gpr: 3,
+                lmsw_source: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn ept_violation() {
+        let mut i = info(48, 0x182 | 0x8);
+        i.guest_physical_address = 0x1000;
+        i.guest_linear_address = 0xffff_8000_0000_1000;
+        let VmExit::EptViolation {
+            qualification,
+            gpa,
+            gla,
+        } = VmExit::decode(&i).unwrap()
+        else {
+            panic!("not an EPT violation");
+        };
+        assert!(qualification.write && qualification.readable);
+        assert!(!qualification.read && !qualification.fetch && !qualification.writable);
+        assert!(qualification.translated_access);
+        assert_eq!(gpa, 0x1000);
+        assert_eq!(gla, Some(0xffff_8000_0000_1000));
+
+        i.qualification = 0x4;
+        let VmExit::EptViolation { gla, .. } = VmExit::decode(&i).unwrap() else {
+            panic!("not an EPT violation");
+        };
+        assert_eq!(gla, None);
+    }
+
+    #[test]
+    fn exception() {
+        let mut i = info(0, 0);
+        // #GP, hardware exception, error code valid
+        i.interruption_info = (1 << 31) | (1 << 11) | (3 << 8) | 13;
+        i.interruption_error_code = 0x10;
+        assert_eq!(
+            VmExit::decode(&i).unwrap(),
+            VmExit::ExceptionOrNmi(InterruptionInfo {
+                vector: 13,
+                kind: InterruptionType::HardwareException,
+                error_code: Some(0x10),
+                nmi_unblocking: false,
+            })
+        );
+    }
+
+    #[test]
+    fn vmx_instruction() {
+        let mut i = info(19, 0x10);
+        // base = rax, index invalid, segment DS, 64-bit addressing
+        i.instruction_info = (1 << 22) | (3 << 15) | (2 << 7);
+        assert_eq!(
+            VmExit::decode(&i).unwrap(),
+            VmExit::VmxInstruction {
+                instruction: BasicExitReason::Vmclear,
+                info: VmxInstructionInfo {
+                    scaling: 0,
+                    address_size: 2,
+                    register_operand: false,
+                    segment: 3,
+                    index: None,
+                    base: Some(0),
+                    reg2: 0,
+                },
+                displacement: 0x10,
+            }
+        );
+    }
+
+    #[test]
+    fn handler_registry() {
+        fn resume(_: &mut Vcpu, _: &VmExit) -> Result<ExitAction, VirtError> {
+            Ok(ExitAction::Resume)
+        }
+
+        let mut handlers = ExitHandlers::new();
+        assert!(handlers.get(BasicExitReason::Cpuid).is_none());
+        assert!(handlers.register(BasicExitReason::Cpuid, resume).is_none());
+        assert!(handlers.get(BasicExitReason::Cpuid).is_some());
+        assert!(handlers.register(BasicExitReason::Cpuid, resume).is_some());
+        assert!(handlers.unregister(BasicExitReason::Cpuid).is_some());
+        assert!(handlers.get(BasicExitReason::Cpuid).is_none());
+        assert!(handlers.get(BasicExitReason::Wrmsrlist).is_none());
+    }
+}
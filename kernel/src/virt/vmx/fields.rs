@@ -0,0 +1,517 @@
+//! VMCS field encodings, see Intel SDM Vol. 3C, Appendix B.
+//!
+//! A field encoding is a 32-bit value laid out as follows:
+//!
+//! | Bits  | Meaning                                                  |
+//! |-------|----------------------------------------------------------|
+//! | 0     | Access type (0 = full, 1 = high 32 bits of 64-bit field) |
+//! | 9:1   | Index                                                    |
+//! | 11:10 | Type (control, read-only data, guest state, host state)  |
+//! | 12    | Reserved (must be 0)                                     |
+//! | 14:13 | Width (16-bit, 64-bit, 32-bit, natural-width)            |
+//! | 31:15 | Reserved (must be 0)                                     |
+
+const ACCESS_TYPE_SHIFT: u32 = 0;
+const INDEX_SHIFT: u32 = 1;
+const INDEX_MASK: u32 = 0x1ff;
+const TYPE_SHIFT: u32 = 10;
+const TYPE_MASK: u32 = 0x3;
+const WIDTH_SHIFT: u32 = 13;
+const WIDTH_MASK: u32 = 0x3;
+const RESERVED_MASK: u32 = !0x6fff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessType {
+    Full = 0,
+    High = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    Control = 0,
+    ReadOnly = 1,
+    Guest = 2,
+    Host = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldWidth {
+    Bits16 = 0,
+    Bits64 = 1,
+    Bits32 = 2,
+    Natural = 3,
+}
+
+/// Builds a raw field encoding from its components.
+pub const fn encode(width: FieldWidth, ty: FieldType, index: u32, access: AccessType) -> u32 {
+    ((width as u32) << WIDTH_SHIFT)
+        | ((ty as u32) << TYPE_SHIFT)
+        | ((index & INDEX_MASK) << INDEX_SHIFT)
+        | ((access as u32) << ACCESS_TYPE_SHIFT)
+}
+
+/// Decoded view over a raw field encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldEncoding(u32);
+
+impl FieldEncoding {
+    /// Returns None if any reserved bit is set.
+    pub const fn new(raw: u32) -> Option<Self> {
+        if raw & RESERVED_MASK != 0 {
+            return None;
+        }
+        Some(Self(raw))
+    }
+
+    pub const fn raw(self) -> u32 {
+        self.0
+    }
+
+    pub const fn access_type(self) -> AccessType {
+        match (self.0 >> ACCESS_TYPE_SHIFT) & 1 {
+            0 => AccessType::Full,
+            _ => AccessType::High,
+        }
+    }
+
+    pub const fn index(self) -> u32 {
+        (self.0 >> INDEX_SHIFT) & INDEX_MASK
+    }
+
+    pub const fn field_type(self) -> FieldType {
+        match (self.0 >> TYPE_SHIFT) & TYPE_MASK {
+            0 => FieldType::Control,
+            1 => FieldType::ReadOnly,
+            2 => FieldType::Guest,
+            _ => FieldType::Host,
+        }
+    }
+
+    pub const fn width(self) -> FieldWidth {
+        match (self.0 >> WIDTH_SHIFT) & WIDTH_MASK {
+            0 => FieldWidth::Bits16,
+            1 => FieldWidth::Bits64,
+            2 => FieldWidth::Bits32,
+            _ => FieldWidth::Natural,
+        }
+    }
+}
+
+/// A typed VMCS field, carrying the width of the value it holds.
+pub trait VmcsField: Copy {
+    type Value: Copy;
+
+    fn encoding(self) -> FieldEncoding;
+
+    /// Truncates the raw value returned by vmread to the field width.
+    fn from_raw(raw: u64) -> Self::Value;
+
+    /// Extends a field value to the operand size of vmwrite.
+    fn into_raw(value: Self::Value) -> u64;
+}
+
+macro_rules! vmcs_fields {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $($(#[$vmeta:meta])* $variant:ident = $value:expr,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u32)]
+        pub enum $name {
+            $($(#[$vmeta])* $variant = $value,)*
+        }
+
+        impl $name {
+            pub const ALL: &'static [Self] = &[$(Self::$variant,)*];
+        }
+    };
+}
+
+vmcs_fields! {
+    /// 16-bit fields.
+    pub enum VmcsField16 {
+        // Control fields
+        VirtualProcessorId = 0x0000,
+        PostedInterruptNotificationVector = 0x0002,
+        EptpIndex = 0x0004,
+        HlatPrefixSize = 0x0006,
+        LastPidPointerIndex = 0x0008,
+
+        // Guest-state fields
+        GuestEsSelector = 0x0800,
+        GuestCsSelector = 0x0802,
+        GuestSsSelector = 0x0804,
+        GuestDsSelector = 0x0806,
+        GuestFsSelector = 0x0808,
+        GuestGsSelector = 0x080a,
+        GuestLdtrSelector = 0x080c,
+        GuestTrSelector = 0x080e,
+        GuestInterruptStatus = 0x0810,
+        PmlIndex = 0x0812,
+        GuestUinv = 0x0814,
+
+        // Host-state fields
+        HostEsSelector = 0x0c00,
+        HostCsSelector = 0x0c02,
+        HostSsSelector = 0x0c04,
+        HostDsSelector = 0x0c06,
+        HostFsSelector = 0x0c08,
+        HostGsSelector = 0x0c0a,
+        HostTrSelector = 0x0c0c,
+    }
+}
+
+vmcs_fields! {
+    /// 64-bit fields. Only the full encoding is listed, the high 32 bits can
+    /// be accessed with [`VmcsField64::high`].
+    pub enum VmcsField64 {
+        // Control fields
+        IoBitmapA = 0x2000,
+        IoBitmapB = 0x2002,
+        MsrBitmap = 0x2004,
+        VmExitMsrStoreAddr = 0x2006,
+        VmExitMsrLoadAddr = 0x2008,
+        VmEntryMsrLoadAddr = 0x200a,
+        ExecutiveVmcsPointer = 0x200c,
+        PmlAddress = 0x200e,
+        TscOffset = 0x2010,
+        VirtualApicPageAddr = 0x2012,
+        ApicAccessAddr = 0x2014,
+        PostedInterruptDescAddr = 0x2016,
+        VmFunctionControls = 0x2018,
+        EptPointer = 0x201a,
+        EoiExitBitmap0 = 0x201c,
+        EoiExitBitmap1 = 0x201e,
+        EoiExitBitmap2 = 0x2020,
+        EoiExitBitmap3 = 0x2022,
+        EptpListAddr = 0x2024,
+        VmreadBitmapAddr = 0x2026,
+        VmwriteBitmapAddr = 0x2028,
+        VirtExceptionInfoAddr = 0x202a,
+        XssExitingBitmap = 0x202c,
+        EnclsExitingBitmap = 0x202e,
+        SubPagePermissionTablePointer = 0x2030,
+        TscMultiplier = 0x2032,
+        TertiaryProcBasedControls = 0x2034,
+        EnclvExitingBitmap = 0x2036,
+        LowPasidDirectoryAddr = 0x2038,
+        HighPasidDirectoryAddr = 0x203a,
+        SharedEptPointer = 0x203c,
+        PconfigExitingBitmap = 0x203e,
+        HlatPointer = 0x2040,
+        PidPointerTableAddr = 0x2042,
+        SecondaryVmExitControls = 0x2044,
+        SpecCtrlMask = 0x204a,
+        SpecCtrlShadow = 0x204c,
+
+        // Read-only data fields
+        GuestPhysicalAddress = 0x2400,
+
+        // Guest-state fields
+        VmcsLinkPointer = 0x2800,
+        GuestIa32Debugctl = 0x2802,
+        GuestIa32Pat = 0x2804,
+        GuestIa32Efer = 0x2806,
+        GuestIa32PerfGlobalCtrl = 0x2808,
+        GuestPdpte0 = 0x280a,
+        GuestPdpte1 = 0x280c,
+        GuestPdpte2 = 0x280e,
+        GuestPdpte3 = 0x2810,
+        GuestIa32Bndcfgs = 0x2812,
+        GuestIa32RtitCtl = 0x2814,
+        GuestIa32LbrCtl = 0x2816,
+        GuestIa32Pkrs = 0x2818,
+
+        // Host-state fields
+        HostIa32Pat = 0x2c00,
+        HostIa32Efer = 0x2c02,
+        HostIa32PerfGlobalCtrl = 0x2c04,
+        HostIa32Pkrs = 0x2c06,
+    }
+}
+
+vmcs_fields! {
+    /// 32-bit fields.
+    pub enum VmcsField32 {
+        // Control fields
+        PinBasedVmExecControls = 0x4000,
+        ProcBasedVmExecControls = 0x4002,
+        ExceptionBitmap = 0x4004,
+        PageFaultErrorCodeMask = 0x4006,
+        PageFaultErrorCodeMatch = 0x4008,
+        Cr3TargetCount = 0x400a,
+        PrimaryVmExitControls = 0x400c,
+        VmExitMsrStoreCount = 0x400e,
+        VmExitMsrLoadCount = 0x4010,
+        VmEntryControls = 0x4012,
+        VmEntryMsrLoadCount = 0x4014,
+        VmEntryInterruptionInfo = 0x4016,
+        VmEntryExceptionErrorCode = 0x4018,
+        VmEntryInstructionLength = 0x401a,
+        TprThreshold = 0x401c,
+        SecondaryProcBasedVmExecControls = 0x401e,
+        PleGap = 0x4020,
+        PleWindow = 0x4022,
+        NotifyWindow = 0x4024,
+
+        // Read-only data fields
+        VmInstructionError = 0x4400,
+        VmExitReason = 0x4402,
+        VmExitInterruptionInfo = 0x4404,
+        VmExitInterruptionErrorCode = 0x4406,
+        IdtVectoringInfo = 0x4408,
+        IdtVectoringErrorCode = 0x440a,
+        VmExitInstructionLength = 0x440c,
+        VmExitInstructionInfo = 0x440e,
+
+        // Guest-state fields
+        GuestEsLimit = 0x4800,
+        GuestCsLimit = 0x4802,
+        GuestSsLimit = 0x4804,
+        GuestDsLimit = 0x4806,
+        GuestFsLimit = 0x4808,
+        GuestGsLimit = 0x480a,
+        GuestLdtrLimit = 0x480c,
+        GuestTrLimit = 0x480e,
+        GuestGdtrLimit = 0x4810,
+        GuestIdtrLimit = 0x4812,
+        GuestEsAccessRights = 0x4814,
+        GuestCsAccessRights = 0x4816,
+        GuestSsAccessRights = 0x4818,
+        GuestDsAccessRights = 0x481a,
+        GuestFsAccessRights = 0x481c,
+        GuestGsAccessRights = 0x481e,
+        GuestLdtrAccessRights = 0x4820,
+        GuestTrAccessRights = 0x4822,
+        GuestInterruptibilityState = 0x4824,
+        GuestActivityState = 0x4826,
+        GuestSmbase = 0x4828,
+        GuestIa32SysenterCs = 0x482a,
+        VmxPreemptionTimerValue = 0x482e,
+
+        // Host-state fields
+        HostIa32SysenterCs = 0x4c00,
+    }
+}
+
+vmcs_fields! {
+    /// Natural-width fields.
+    pub enum VmcsFieldNatural {
+        // Control fields
+        Cr0GuestHostMask = 0x6000,
+        Cr4GuestHostMask = 0x6002,
+        Cr0ReadShadow = 0x6004,
+        Cr4ReadShadow = 0x6006,
+        Cr3TargetValue0 = 0x6008,
+        Cr3TargetValue1 = 0x600a,
+        Cr3TargetValue2 = 0x600c,
+        Cr3TargetValue3 = 0x600e,
+
+        // Read-only data fields
+        ExitQualification = 0x6400,
+        IoRcx = 0x6402,
+        IoRsi = 0x6404,
+        IoRdi = 0x6406,
+        IoRip = 0x6408,
+        GuestLinearAddress = 0x640a,
+
+        // Guest-state fields
+        GuestCr0 = 0x6800,
+        GuestCr3 = 0x6802,
+        GuestCr4 = 0x6804,
+        GuestEsBase = 0x6806,
+        GuestCsBase = 0x6808,
+        GuestSsBase = 0x680a,
+        GuestDsBase = 0x680c,
+        GuestFsBase = 0x680e,
+        GuestGsBase = 0x6810,
+        GuestLdtrBase = 0x6812,
+        GuestTrBase = 0x6814,
+        GuestGdtrBase = 0x6816,
+        GuestIdtrBase = 0x6818,
+        GuestDr7 = 0x681a,
+        GuestRsp = 0x681c,
+        GuestRip = 0x681e,
+        GuestRflags = 0x6820,
+        GuestPendingDebugExceptions = 0x6822,
+        GuestIa32SysenterEsp = 0x6824,
+        GuestIa32SysenterEip = 0x6826,
+        GuestIa32SCet = 0x6828,
+        GuestSsp = 0x682a,
+        GuestIa32InterruptSspTableAddr = 0x682c,
+
+        // Host-state fields
+        HostCr0 = 0x6c00,
+        HostCr3 = 0x6c02,
+        HostCr4 = 0x6c04,
+        HostFsBase = 0x6c06,
+        HostGsBase = 0x6c08,
+        HostTrBase = 0x6c0a,
+        HostGdtrBase = 0x6c0c,
+        HostIdtrBase = 0x6c0e,
+        HostIa32SysenterEsp = 0x6c10,
+        HostIa32SysenterEip = 0x6c12,
+        HostRsp = 0x6c14,
+        HostRip = 0x6c16,
+        HostIa32SCet = 0x6c18,
+        HostSsp = 0x6c1a,
+        HostIa32InterruptSspTableAddr = 0x6c1c,
+    }
+}
+
+impl VmcsField64 {
+    /// Encoding used to access the high 32 bits of the field.
+    pub const fn high(self) -> FieldEncoding {
+        FieldEncoding(self as u32 | AccessType::High as u32)
+    }
+}
+
+impl VmcsField for VmcsField16 {
+    type Value = u16;
+
+    fn encoding(self) -> FieldEncoding {
+        FieldEncoding(self as u32)
+    }
+
+    fn from_raw(raw: u64) -> u16 {
+        raw as u16
+    }
+
+    fn into_raw(value: u16) -> u64 {
+        value.into()
+    }
+}
+
+impl VmcsField for VmcsField32 {
+    type Value = u32;
+
+    fn encoding(self) -> FieldEncoding {
+        FieldEncoding(self as u32)
+    }
+
+    fn from_raw(raw: u64) -> u32 {
+        raw as u32
+    }
+
+    fn into_raw(value: u32) -> u64 {
+        value.into()
+    }
+}
+
+impl VmcsField for VmcsField64 {
+    type Value = u64;
+
+    fn encoding(self) -> FieldEncoding {
+        FieldEncoding(self as u32)
+    }
+
+    fn from_raw(raw: u64) -> u64 {
+        raw
+    }
+
+    fn into_raw(value: u64) -> u64 {
+        value
+    }
+}
+
+impl VmcsField for VmcsFieldNatural {
+    type Value = u64;
+
+    fn encoding(self) -> FieldEncoding {
+        FieldEncoding(self as u32)
+    }
+
+    fn from_raw(raw: u64) -> u64 {
+        raw
+    }
+
+    fn into_raw(value: u64) -> u64 {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_all(fields: &[FieldEncoding], width: FieldWidth) {
+        for f in fields {
+            assert_eq!(f.width(), width, "{:#x}", f.raw());
+            assert_eq!(f.access_type(), AccessType::Full, "{:#x}", f.raw());
+            assert_eq!(FieldEncoding::new(f.raw()), Some(*f));
+            let rebuilt = encode(f.width(), f.field_type(), f.index(), f.access_type());
+            assert_eq!(rebuilt, f.raw());
+        }
+    }
+
+    #[test]
+    fn widths_match_enums() {
+        let all16: alloc::vec::Vec<_> = VmcsField16::ALL.iter().map(|f| f.encoding()).collect();
+        let all32: alloc::vec::Vec<_> = VmcsField32::ALL.iter().map(|f| f.encoding()).collect();
+        let all64: alloc::vec::Vec<_> = VmcsField64::ALL.iter().map(|f| f.encoding()).collect();
+        let natural: alloc::vec::Vec<_> =
+            VmcsFieldNatural::ALL.iter().map(|f| f.encoding()).collect();
+
+        check_all(&all16, FieldWidth::Bits16);
+        check_all(&all32, FieldWidth::Bits32);
+        check_all(&all64, FieldWidth::Bits64);
+        check_all(&natural, FieldWidth::Natural);
+    }
+
+    #[test]
+    fn decode_components() {
+        let f = VmcsFieldNatural::HostRip.encoding();
+        assert_eq!(f.field_type(), FieldType::Host);
+        assert_eq!(f.index(), 11);
+
+        let f = VmcsField32::VmInstructionError.encoding();
+        assert_eq!(f.field_type(), FieldType::ReadOnly);
+        assert_eq!(f.index(), 0);
+
+        let f = VmcsField16::GuestTrSelector.encoding();
+        assert_eq!(f.field_type(), FieldType::Guest);
+        assert_eq!(f.index(), 7);
+
+        let f = VmcsField64::EptPointer.encoding();
+        assert_eq!(f.field_type(), FieldType::Control);
+        assert_eq!(f.index(), 13);
+    }
+
+    #[test]
+    fn high_access() {
+        let high = VmcsField64::TscOffset.high();
+        assert_eq!(high.raw(), 0x2011);
+        assert_eq!(high.access_type(), AccessType::High);
+        assert_eq!(high.index(), VmcsField64::TscOffset.encoding().index());
+    }
+
+    #[test]
+    fn encode_matches_sdm() {
+        assert_eq!(
+            encode(FieldWidth::Bits32, FieldType::ReadOnly, 1, AccessType::Full),
+            VmcsField32::VmExitReason as u32
+        );
+        assert_eq!(
+            encode(FieldWidth::Natural, FieldType::Guest, 15, AccessType::Full),
+            VmcsFieldNatural::GuestRip as u32
+        );
+    }
+
+    #[test]
+    fn reserved_bits() {
+        assert_eq!(FieldEncoding::new(0x1000), None);
+        assert_eq!(FieldEncoding::new(0x8000), None);
+        assert!(FieldEncoding::new(0x6c16).is_some());
+    }
+
+    #[test]
+    fn value_width() {
+        assert_eq!(VmcsField16::from_raw(0x1_2345), 0x2345);
+        assert_eq!(VmcsField32::from_raw(0x1_0000_0002), 2);
+        assert_eq!(VmcsField64::from_raw(u64::MAX), u64::MAX);
+        assert_eq!(VmcsField16::into_raw(0xffff), 0xffff);
+    }
+}
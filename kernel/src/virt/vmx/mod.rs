@@ -1,4 +1,9 @@
 pub mod asm;
+pub mod caps;
+pub mod ept;
 pub mod errors;
+pub mod exit;
+pub mod fields;
+pub mod vcpu;
 pub mod vmcs;
 pub mod vmxon;
@@ -0,0 +1,377 @@
+use alloc::boxed::Box;
+
+use x86_64::{
+    instructions::tables::{sgdt, sidt},
+    registers::control::{Cr0, Cr3, Cr4},
+};
+
+use super::asm::{asm_vmentry, vmexit_rip};
+use super::caps::{
+    adjust_controls, ControlCaps, VmxCaps, PROCBASED2_ENABLE_EPT,
+    PROCBASED_ACTIVATE_SECONDARY_CONTROLS,
+};
+use super::exit::{ExitAction, ExitHandlers, ExitInfo, VmExit};
+use super::fields::{VmcsField16, VmcsField32, VmcsField64, VmcsFieldNatural};
+use super::vmcs::VMCS;
+use crate::cpu::insn::Segment;
+use crate::mm::stack::KernelStack;
+use crate::virt::VirtError;
+
+const GUEST_STACK_SIZE: usize = 16 * 1024;
+
+const PROCBASED_HLT_EXITING: u32 = 1 << 7;
+const EXIT_HOST_ADDR_SPACE_SIZE: u32 = 1 << 9;
+const ENTRY_IA32E_MODE_GUEST: u32 = 1 << 9;
+
+const ACCESS_RIGHTS_UNUSABLE: u32 = 1 << 16;
+
+/// Guest general purpose registers not held in the VMCS.
+///
+/// The layout is shared with `vmx_run` and `vmx_vmexit` in `asm.rs`.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct GuestRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+/// A virtual CPU running a 64-bit guest that shares the host address space.
+#[derive(Debug)]
+pub struct Vcpu {
+    vmcs: Box<VMCS>,
+    regs: GuestRegisters,
+    /// Guest stack, with a guard page catching overflows.
+    stack: KernelStack,
+    entry: extern "C" fn() -> !,
+    launched: bool,
+}
+
+impl Vcpu {
+    pub fn new(entry: extern "C" fn() -> !) -> Self {
+        Self {
+            vmcs: Box::new(VMCS::new()),
+            regs: GuestRegisters::default(),
+            stack: KernelStack::new(GUEST_STACK_SIZE).expect("failed to allocate the guest stack"),
+            entry,
+            launched: false,
+        }
+    }
+
+    pub fn vmcs(&self) -> &VMCS {
+        &self.vmcs
+    }
+
+    pub fn vmcs_mut(&mut self) -> &mut VMCS {
+        &mut self.vmcs
+    }
+
+    pub fn regs(&self) -> &GuestRegisters {
+        &self.regs
+    }
+
+    pub fn regs_mut(&mut self) -> &mut GuestRegisters {
+        &mut self.regs
+    }
+
+    /// Loads the VMCS and fills the control, host-state and guest-state
+    /// fields. VMX operation must already be enabled, and a TSS loaded.
+    pub fn setup(&mut self) -> Result<(), VirtError> {
+        // The host TR selector must not be null, and the guest TR, copied
+        // from the host, must be usable (SDM 26.2.3 and 26.3.1.2). init_gdt
+        // loads a TSS.
+        if Segment::TR(0).read() == 0 {
+            return Err(VirtError::NoTss);
+        }
+
+        self.vmcs.setup()?;
+        self.launched = false;
+
+        // SAFETY: the host state is the state of the current CPU, and the
+        // guest gets its own stack.
+        unsafe {
+            self.setup_controls()?;
+            self.setup_host_state()?;
+            self.setup_guest_state()
+        }
+    }
+
+    /// Enters the guest and returns the next decoded VM exit.
+    pub fn run(&mut self) -> Result<VmExit, VirtError> {
+        // SAFETY: setup() filled the VMCS, and HOST_RIP points to vmx_vmexit.
+        unsafe { asm_vmentry(&mut self.regs, self.launched)? };
+        self.launched = true;
+
+        let info = ExitInfo::read(&self.vmcs, &self.regs)?;
+        let exit = VmExit::decode(&info)?;
+        log::debug!("VM exit: {:?}", exit);
+        Ok(exit)
+    }
+
+    /// Runs the guest, dispatching each exit to its handler, until a handler
+    /// returns [`ExitAction::Stop`] or an exit has no registered handler.
+    pub fn run_with(&mut self, handlers: &ExitHandlers) -> Result<VmExit, VirtError> {
+        loop {
+            let exit = self.run()?;
+            let Some(handler) = handlers.get(exit.reason()) else {
+                return Ok(exit);
+            };
+
+            if handler(self, &exit)? == ExitAction::Stop {
+                return Ok(exit);
+            }
+        }
+    }
+
+    /// Moves the guest RIP past the instruction that caused the last exit.
+    pub fn skip_instruction(&mut self) -> Result<(), VirtError> {
+        let len = self.vmcs.vmread(VmcsField32::VmExitInstructionLength)?;
+        let rip = self.vmcs.vmread(VmcsFieldNatural::GuestRip)?;
+        // SAFETY: only the guest state is modified.
+        unsafe {
+            self.vmcs
+                .vmwrite(VmcsFieldNatural::GuestRip, rip + u64::from(len))
+        }
+    }
+
+    /// Enables EPT translation of guest-physical addresses with the given
+    /// EPTP. Must be called after [`Vcpu::setup`].
+    ///
+    /// # Safety
+    ///
+    /// The guest shares the host CR3, so the EPT must identity map every host
+    /// physical page the guest touches, including its page tables.
+    pub unsafe fn enable_ept(&mut self, eptp: u64) -> Result<(), VirtError> {
+        let caps = VmxCaps::read();
+        let vmcs = &mut self.vmcs;
+
+        let proc = vmcs.vmread(VmcsField32::ProcBasedVmExecControls)?;
+        vmcs.vmwrite(
+            VmcsField32::ProcBasedVmExecControls,
+            adjust(
+                "processor-based",
+                proc | PROCBASED_ACTIVATE_SECONDARY_CONTROLS,
+                caps.procbased,
+            ),
+        )?;
+        vmcs.vmwrite(
+            VmcsField32::SecondaryProcBasedVmExecControls,
+            adjust(
+                "secondary processor-based",
+                PROCBASED2_ENABLE_EPT,
+                caps.procbased2,
+            ),
+        )?;
+        vmcs.vmwrite(VmcsField64::EptPointer, eptp)
+    }
+
+    unsafe fn setup_controls(&mut self) -> Result<(), VirtError> {
+        let caps = VmxCaps::read();
+        let vmcs = &mut self.vmcs;
+
+        vmcs.vmwrite(
+            VmcsField32::PinBasedVmExecControls,
+            adjust("pin-based", 0, caps.pinbased),
+        )?;
+        vmcs.vmwrite(
+            VmcsField32::ProcBasedVmExecControls,
+            adjust("processor-based", PROCBASED_HLT_EXITING, caps.procbased),
+        )?;
+        vmcs.vmwrite(
+            VmcsField32::PrimaryVmExitControls,
+            adjust("VM-exit", EXIT_HOST_ADDR_SPACE_SIZE, caps.exit),
+        )?;
+        vmcs.vmwrite(
+            VmcsField32::VmEntryControls,
+            adjust("VM-entry", ENTRY_IA32E_MODE_GUEST, caps.entry),
+        )?;
+
+        vmcs.vmwrite(VmcsField32::ExceptionBitmap, 0)?;
+        vmcs.vmwrite(VmcsField32::Cr3TargetCount, 0)?;
+        vmcs.vmwrite(VmcsField32::VmExitMsrStoreCount, 0)?;
+        vmcs.vmwrite(VmcsField32::VmExitMsrLoadCount, 0)?;
+        vmcs.vmwrite(VmcsField32::VmEntryMsrLoadCount, 0)?;
+        vmcs.vmwrite(VmcsField32::VmEntryInterruptionInfo, 0)?;
+
+        vmcs.vmwrite(VmcsFieldNatural::Cr0GuestHostMask, 0)?;
+        vmcs.vmwrite(VmcsFieldNatural::Cr4GuestHostMask, 0)?;
+        vmcs.vmwrite(VmcsFieldNatural::Cr0ReadShadow, Cr0::read_raw())?;
+        vmcs.vmwrite(VmcsFieldNatural::Cr4ReadShadow, Cr4::read_raw())?;
+
+        Ok(())
+    }
+
+    unsafe fn setup_host_state(&mut self) -> Result<(), VirtError> {
+        let vmcs = &mut self.vmcs;
+
+        vmcs.vmwrite(VmcsFieldNatural::HostCr0, Cr0::read_raw())?;
+        vmcs.vmwrite(VmcsFieldNatural::HostCr3, cr3_raw())?;
+        vmcs.vmwrite(VmcsFieldNatural::HostCr4, Cr4::read_raw())?;
+
+        // Host selectors must have RPL and TI cleared.
+        let selector = |s: Segment| (s.read() & !0x7) as u16;
+        vmcs.vmwrite(VmcsField16::HostCsSelector, selector(Segment::CS(0)))?;
+        vmcs.vmwrite(VmcsField16::HostSsSelector, selector(Segment::SS(0)))?;
+        vmcs.vmwrite(VmcsField16::HostDsSelector, selector(Segment::DS(0)))?;
+        vmcs.vmwrite(VmcsField16::HostEsSelector, selector(Segment::ES(0)))?;
+        vmcs.vmwrite(VmcsField16::HostFsSelector, selector(Segment::FS(0)))?;
+        vmcs.vmwrite(VmcsField16::HostGsSelector, selector(Segment::GS(0)))?;
+        vmcs.vmwrite(VmcsField16::HostTrSelector, selector(Segment::TR(0)))?;
+
+        vmcs.vmwrite(VmcsFieldNatural::HostFsBase, Segment::FS(0).base())?;
+        vmcs.vmwrite(VmcsFieldNatural::HostGsBase, Segment::GS(0).base())?;
+        vmcs.vmwrite(VmcsFieldNatural::HostTrBase, Segment::TR(0).base())?;
+        vmcs.vmwrite(VmcsFieldNatural::HostGdtrBase, sgdt().base.as_u64())?;
+        vmcs.vmwrite(VmcsFieldNatural::HostIdtrBase, sidt().base.as_u64())?;
+
+        vmcs.vmwrite(VmcsField32::HostIa32SysenterCs, 0)?;
+        vmcs.vmwrite(VmcsFieldNatural::HostIa32SysenterEsp, 0)?;
+        vmcs.vmwrite(VmcsFieldNatural::HostIa32SysenterEip, 0)?;
+
+        // HOST_RSP is written by vmx_run right before entering the guest.
+        vmcs.vmwrite(VmcsFieldNatural::HostRip, vmexit_rip().as_u64())?;
+
+        Ok(())
+    }
+
+    unsafe fn setup_guest_state(&mut self) -> Result<(), VirtError> {
+        let vmcs = &mut self.vmcs;
+
+        vmcs.vmwrite(VmcsFieldNatural::GuestCr0, Cr0::read_raw())?;
+        vmcs.vmwrite(VmcsFieldNatural::GuestCr3, cr3_raw())?;
+        vmcs.vmwrite(VmcsFieldNatural::GuestCr4, Cr4::read_raw())?;
+        vmcs.vmwrite(VmcsFieldNatural::GuestDr7, 0x400)?;
+
+        let segments = [
+            (
+                Segment::ES(0),
+                VmcsField16::GuestEsSelector,
+                VmcsFieldNatural::GuestEsBase,
+                VmcsField32::GuestEsLimit,
+                VmcsField32::GuestEsAccessRights,
+            ),
+            (
+                Segment::CS(0),
+                VmcsField16::GuestCsSelector,
+                VmcsFieldNatural::GuestCsBase,
+                VmcsField32::GuestCsLimit,
+                VmcsField32::GuestCsAccessRights,
+            ),
+            (
+                Segment::SS(0),
+                VmcsField16::GuestSsSelector,
+                VmcsFieldNatural::GuestSsBase,
+                VmcsField32::GuestSsLimit,
+                VmcsField32::GuestSsAccessRights,
+            ),
+            (
+                Segment::DS(0),
+                VmcsField16::GuestDsSelector,
+                VmcsFieldNatural::GuestDsBase,
+                VmcsField32::GuestDsLimit,
+                VmcsField32::GuestDsAccessRights,
+            ),
+            (
+                Segment::FS(0),
+                VmcsField16::GuestFsSelector,
+                VmcsFieldNatural::GuestFsBase,
+                VmcsField32::GuestFsLimit,
+                VmcsField32::GuestFsAccessRights,
+            ),
+            (
+                Segment::GS(0),
+                VmcsField16::GuestGsSelector,
+                VmcsFieldNatural::GuestGsBase,
+                VmcsField32::GuestGsLimit,
+                VmcsField32::GuestGsAccessRights,
+            ),
+            (
+                Segment::LDTR(0),
+                VmcsField16::GuestLdtrSelector,
+                VmcsFieldNatural::GuestLdtrBase,
+                VmcsField32::GuestLdtrLimit,
+                VmcsField32::GuestLdtrAccessRights,
+            ),
+            (
+                Segment::TR(0),
+                VmcsField16::GuestTrSelector,
+                VmcsFieldNatural::GuestTrBase,
+                VmcsField32::GuestTrLimit,
+                VmcsField32::GuestTrAccessRights,
+            ),
+        ];
+
+        for (segment, selector, base, limit, access_rights) in segments {
+            vmcs.vmwrite(selector, segment.read() as u16)?;
+            vmcs.vmwrite(base, segment.base())?;
+            vmcs.vmwrite(limit, segment.limit().unwrap_or(0))?;
+            vmcs.vmwrite(access_rights, vmx_access_rights(segment))?;
+        }
+
+        let gdt = sgdt();
+        let idt = sidt();
+        vmcs.vmwrite(VmcsFieldNatural::GuestGdtrBase, gdt.base.as_u64())?;
+        vmcs.vmwrite(VmcsField32::GuestGdtrLimit, gdt.limit.into())?;
+        vmcs.vmwrite(VmcsFieldNatural::GuestIdtrBase, idt.base.as_u64())?;
+        vmcs.vmwrite(VmcsField32::GuestIdtrLimit, idt.limit.into())?;
+
+        vmcs.vmwrite(VmcsField32::GuestIa32SysenterCs, 0)?;
+        vmcs.vmwrite(VmcsFieldNatural::GuestIa32SysenterEsp, 0)?;
+        vmcs.vmwrite(VmcsFieldNatural::GuestIa32SysenterEip, 0)?;
+
+        vmcs.vmwrite(VmcsField32::GuestInterruptibilityState, 0)?;
+        vmcs.vmwrite(VmcsField32::GuestActivityState, 0)?;
+        vmcs.vmwrite(VmcsFieldNatural::GuestPendingDebugExceptions, 0)?;
+        vmcs.vmwrite(VmcsField64::VmcsLinkPointer, u64::MAX)?;
+
+        // Keep the stack 16-byte aligned as after a call instruction.
+        let stack_top = self.stack.top().as_u64() - 8;
+        vmcs.vmwrite(VmcsFieldNatural::GuestRsp, stack_top)?;
+        vmcs.vmwrite(VmcsFieldNatural::GuestRip, self.entry as *const () as u64)?;
+        vmcs.vmwrite(VmcsFieldNatural::GuestRflags, 0x2)?;
+
+        Ok(())
+    }
+}
+
+/// Adjusts the desired controls to the CPU capabilities, logging the bits
+/// that couldn't be set.
+fn adjust(name: &str, desired: u32, caps: ControlCaps) -> u32 {
+    let adjusted = adjust_controls(desired, caps);
+    if !adjusted.is_complete() {
+        log::error!(
+            "Unsupported {} controls: {:#010x}",
+            name,
+            adjusted.unsupported
+        );
+    }
+    adjusted.value
+}
+
+fn cr3_raw() -> u64 {
+    let (frame, flags) = Cr3::read_raw();
+    frame.start_address().as_u64() | u64::from(flags)
+}
+
+/// Converts the access rights returned by `lar` to the VMCS format.
+fn vmx_access_rights(segment: Segment) -> u32 {
+    if segment.read() & !0x7 == 0 {
+        return ACCESS_RIGHTS_UNUSABLE;
+    }
+
+    segment
+        .access_rights()
+        .map_or(ACCESS_RIGHTS_UNUSABLE, |ar| (ar >> 8) & 0xf0ff)
+}
@@ -1,8 +1,11 @@
-use x86_64::{registers::model_specific::Msr, PhysAddr, VirtAddr};
+use x86_64::{PhysAddr, VirtAddr};
 
-use crate::{cpu::msr::IA32_VMX_BASIC, mm::memory::virt_to_phys, virt::VirtError};
+use crate::{mm::memory::virt_to_phys, virt::VirtError};
 
-use super::asm::{asm_vmptrld, asm_vmread};
+use super::asm::{asm_vmclear, asm_vmptrld, asm_vmptrst, asm_vmread, asm_vmwrite};
+use super::caps::VmxBasic;
+use super::fields::VmcsField;
+use super::vmxon::vmx_enabled;
 
 const _: () = assert!(core::mem::size_of::<VMCS>() == 0x1000);
 const _: () = assert!(core::mem::align_of::<VMCS>() == 0x1000);
@@ -41,22 +44,47 @@ impl VMCS {
         unsafe { asm_vmptrld(self.paddr()?) }
     }
 
+    #[inline]
+    pub fn vmclear(&self) -> Result<(), VirtError> {
+        // SAFETY: we rely on the borrow checker to validate that self is
+        // always valid.
+        unsafe { asm_vmclear(self.paddr()?) }
+    }
+
+    /// Returns true if self is the current VMCS.
+    pub fn is_current(&self) -> Result<bool, VirtError> {
+        let paddr = self.paddr()?;
+        // SAFETY: vmptrst only stores the current VMCS pointer.
+        let current = unsafe { asm_vmptrst()? };
+        Ok(current == Some(paddr))
+    }
+
     fn init_revision(&mut self) {
-        // SAFETY: Reading IA32_VMX_BASIC is safe
-        let msr = unsafe { Msr::read(&Msr::new(IA32_VMX_BASIC)) };
-        self.revision = msr as u32;
-        self.revision &= !(1 << 31);
+        self.revision = VmxBasic::read().revision_id;
     }
 
     pub fn setup(&mut self) -> Result<(), VirtError> {
         self.init_revision();
+        self.vmclear()?;
         self.vmptrld()
     }
 
-    pub fn vmread(&self, field: u32) -> Result<u32, VirtError> {
+    pub fn vmread<F: VmcsField>(&self, field: F) -> Result<F::Value, VirtError> {
         // SAFETY: we rely on the borrow checker to validate that self is
         // always valid.
-        unsafe { asm_vmread(field) }
+        unsafe { asm_vmread(field.encoding().raw()).map(F::from_raw) }
+    }
+
+    /// # Safety
+    ///
+    /// Caller should ensure that the written value doesn't break the host
+    /// state (e.g. host RIP/RSP) used on the next VM exit.
+    pub unsafe fn vmwrite<F: VmcsField>(
+        &mut self,
+        field: F,
+        value: F::Value,
+    ) -> Result<(), VirtError> {
+        asm_vmwrite(field.encoding().raw(), F::into_raw(value))
     }
 
     pub fn is_shadow(&self) -> bool {
@@ -68,6 +96,22 @@ impl VMCS {
     }
 }
 
+impl Drop for VMCS {
+    fn drop(&mut self) {
+        // Outside VMX operation the VMCS can't be in use anymore, and vmclear
+        // would #UD.
+        if !vmx_enabled() {
+            return;
+        }
+
+        // Flush the VMCS data and make it inactive, so that the processor
+        // stops using the region before it is freed.
+        if let Err(e) = self.vmclear() {
+            log::error!("vmclear failed: {}", e);
+        }
+    }
+}
+
 impl Default for VMCS {
     fn default() -> Self {
         VMCS::new()
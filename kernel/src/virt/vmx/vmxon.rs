@@ -1,21 +1,30 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use x86_64::{
-    registers::{
-        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
-        model_specific::Msr,
-    },
+    registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
     PhysAddr, VirtAddr,
 };
 
-use super::asm::asm_vmxon;
+use super::asm::{asm_vmxoff, asm_vmxon};
+use super::caps::VmxBasic;
 use crate::virt::VirtError;
 use crate::{
     cpu::msr::{
-        IA32_VMX_BASIC, IA32_VMX_CR0_FIXED0, IA32_VMX_CR0_FIXED1, IA32_VMX_CR4_FIXED0,
+        try_rdmsr, IA32_VMX_CR0_FIXED0, IA32_VMX_CR0_FIXED1, IA32_VMX_CR4_FIXED0,
         IA32_VMX_CR4_FIXED1,
     },
     mm::memory::virt_to_phys,
 };
 
+/// Physical address of the VMXON region in use, or 0 outside VMX operation.
+static ACTIVE_VMXON: AtomicU64 = AtomicU64::new(0);
+
+/// Returns true if the CPU is in VMX operation.
+#[inline]
+pub fn vmx_enabled() -> bool {
+    ACTIVE_VMXON.load(Ordering::Acquire) != 0
+}
+
 const _: () = assert!(core::mem::size_of::<VmxOn>() == 0x1000);
 const _: () = assert!(core::mem::align_of::<VmxOn>() == 0x1000);
 
@@ -46,42 +55,68 @@ impl VmxOn {
 
     #[inline]
     pub fn vmxon(&self) -> Result<(), VirtError> {
+        let paddr = self.paddr()?;
         // SAFETY: we rely on the borrow checker to validate that self is
         // always valid.
-        unsafe { asm_vmxon(self.paddr()?) }
+        unsafe { asm_vmxon(paddr)? };
+        ACTIVE_VMXON.store(paddr.as_u64(), Ordering::Release);
+        Ok(())
     }
 
-    pub fn enable_vmxe(&self) {
-        unsafe {
-            Cr0::update(|cr0| cr0.set(Cr0Flags::PROTECTED_MODE_ENABLE, true));
-            Cr4::update(|cr4| cr4.set(Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS, true));
+    /// Leaves VMX operation, if it was entered with this region.
+    pub fn vmxoff(&self) -> Result<(), VirtError> {
+        if !self.is_active() {
+            return Ok(());
+        }
+
+        // SAFETY: the CPU is in VMX operation, entered with self.
+        unsafe { asm_vmxoff()? };
+        ACTIVE_VMXON.store(0, Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns true if the CPU entered VMX operation with this region.
+    pub fn is_active(&self) -> bool {
+        self.paddr()
+            .is_ok_and(|paddr| ACTIVE_VMXON.load(Ordering::Acquire) == paddr.as_u64())
+    }
 
-            let msr_cr0_0 = Msr::read(&Msr::new(IA32_VMX_CR0_FIXED0));
-            let msr_cr0_1 = Msr::read(&Msr::new(IA32_VMX_CR0_FIXED1));
-            let msr_cr4_0 = Msr::read(&Msr::new(IA32_VMX_CR4_FIXED0));
-            let msr_cr4_1 = Msr::read(&Msr::new(IA32_VMX_CR4_FIXED1));
+    /// Sets CR4.VMXE and the CR0 and CR4 bits fixed to 1 in VMX operation.
+    /// The fixed bits MSRs are read first, so that a CPU without VMX returns
+    /// their #GP instead of faulting on the CR4 write.
+    pub fn enable_vmxe(&self) -> Result<(), VirtError> {
+        let cr0_fixed0 = try_rdmsr(IA32_VMX_CR0_FIXED0)?;
+        let cr0_fixed1 = try_rdmsr(IA32_VMX_CR0_FIXED1)?;
+        let cr4_fixed0 = try_rdmsr(IA32_VMX_CR4_FIXED0)?;
+        let cr4_fixed1 = try_rdmsr(IA32_VMX_CR4_FIXED1)?;
 
+        // SAFETY: the CPU supports VMX, and the fixed bits are already set
+        // in the kernel's CR0 and CR4, apart from VMXE.
+        unsafe {
             Cr0::update(|cr0| {
-                *cr0 |= Cr0Flags::from_bits_truncate(msr_cr0_0)
-                    & Cr0Flags::from_bits_truncate(msr_cr0_1)
+                cr0.set(Cr0Flags::PROTECTED_MODE_ENABLE, true);
+                *cr0 |= Cr0Flags::from_bits_truncate(cr0_fixed0)
+                    & Cr0Flags::from_bits_truncate(cr0_fixed1)
             });
             Cr4::update(|cr4| {
-                *cr4 |= Cr4Flags::from_bits_truncate(msr_cr4_0)
-                    & Cr4Flags::from_bits_truncate(msr_cr4_1)
+                cr4.set(Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS, true);
+                *cr4 |= Cr4Flags::from_bits_truncate(cr4_fixed0)
+                    & Cr4Flags::from_bits_truncate(cr4_fixed1)
             });
         }
+        Ok(())
     }
 
-    fn init_revision(&mut self) {
-        // SAFETY: Reading IA32_VMX_BASIC is safe
-        let msr = unsafe { Msr::read(&Msr::new(IA32_VMX_BASIC)) };
-        self.revision = msr as u32;
-        self.revision &= !(1 << 31);
+    fn init_revision(&mut self) -> Result<(), VirtError> {
+        self.revision = VmxBasic::try_read()?.revision_id;
+        Ok(())
     }
 
+    /// Enters VMX operation. A CPU without VMX returns the #GP raised when
+    /// probing its capabilities.
     pub fn setup(&mut self) -> Result<(), VirtError> {
-        self.enable_vmxe();
-        self.init_revision();
+        self.init_revision()?;
+        self.enable_vmxe()?;
         self.vmxon()
     }
 }
@@ -91,3 +126,11 @@ impl Default for VmxOn {
         VmxOn::new()
     }
 }
+
+impl Drop for VmxOn {
+    fn drop(&mut self) {
+        if let Err(e) = self.vmxoff() {
+            log::error!("vmxoff failed: {}", e);
+        }
+    }
+}
@@ -1,19 +1,84 @@
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{Entry, Type};
+use xmas_elf::ElfFile;
 
 fn main() {
     let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap());
+    let ktest = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_ktest").unwrap());
+
+    create_images(&out_dir, &kernel, "", "");
+    create_images(&out_dir, &ktest, "test-", "TEST_");
+}
+
+/// Creates the UEFI and BIOS disk images of kernel, with its symbol table as
+/// the ramdisk, and exports their paths in the {env_prefix}UEFI_PATH and
+/// {env_prefix}BIOS_PATH variables.
+fn create_images(out_dir: &Path, kernel: &Path, file_prefix: &str, env_prefix: &str) {
+    let symbols_path = out_dir.join(format!("{file_prefix}symbols.bin"));
+    fs::write(&symbols_path, symbol_table(kernel)).unwrap();
 
-    let uefi_path = out_dir.join("uefi.img");
-    bootloader::UefiBoot::new(&kernel)
+    let uefi_path = out_dir.join(format!("{file_prefix}uefi.img"));
+    bootloader::UefiBoot::new(kernel)
+        .set_ramdisk(&symbols_path)
         .create_disk_image(&uefi_path)
         .unwrap();
 
-    let bios_path = out_dir.join("bios.img");
-    bootloader::BiosBoot::new(&kernel)
+    let bios_path = out_dir.join(format!("{file_prefix}bios.img"));
+    bootloader::BiosBoot::new(kernel)
+        .set_ramdisk(&symbols_path)
         .create_disk_image(&bios_path)
         .unwrap();
 
-    println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
-    println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
+    println!(
+        "cargo:rustc-env={env_prefix}UEFI_PATH={}",
+        uefi_path.display()
+    );
+    println!(
+        "cargo:rustc-env={env_prefix}BIOS_PATH={}",
+        bios_path.display()
+    );
+}
+
+/// Builds the table of the function symbols of kernel, in the format read by
+/// `kernel::cpu::symbols`.
+fn symbol_table(kernel: &Path) -> Vec<u8> {
+    let data = fs::read(kernel).unwrap();
+    let elf = ElfFile::new(&data).unwrap();
+
+    let mut symbols = Vec::new();
+    for section in elf.section_iter() {
+        let Ok(SectionData::SymbolTable64(entries)) = section.get_data(&elf) else {
+            continue;
+        };
+        for entry in entries {
+            if !matches!(entry.get_type(), Ok(Type::Func)) || entry.value() == 0 {
+                continue;
+            }
+            let Ok(name) = entry.get_name(&elf) else {
+                continue;
+            };
+            let name = format!("{:#}", rustc_demangle::demangle(name));
+            symbols.push((entry.value(), entry.size() as u32, name));
+        }
+    }
+    // Aliases share an address, keep one of them.
+    symbols.sort_by_key(|&(addr, size, _)| (addr, std::cmp::Reverse(size)));
+    symbols.dedup_by_key(|&mut (addr, _, _)| addr);
+
+    let mut table = Vec::from(*b"KSYM");
+    table.extend((symbols.len() as u32).to_le_bytes());
+    let mut names = Vec::new();
+    for (addr, size, name) in &symbols {
+        table.extend(addr.to_le_bytes());
+        table.extend(size.to_le_bytes());
+        table.extend((names.len() as u32).to_le_bytes());
+        names.extend(name.as_bytes());
+        names.push(0);
+    }
+    table.extend(names);
+    table
 }
@@ -1,36 +1,239 @@
-use std::{env::current_dir, fs, path::Path};
+use std::{
+    env::current_dir,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::{Command, ExitCode, Stdio},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use report::ReportFormat;
+
+mod report;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Accel {
+    /// Hardware virtualization, required by the VMX tests.
+    Kvm,
+    /// Software emulation, without VMX.
+    Tcg,
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Boot the ktest kernel and report its results, instead of the kernel.
     #[arg(long, short, default_value_t = false)]
     test: bool,
 
-    #[arg(long, short, default_value_t = true)]
+    /// Only build the kernel and copy its UEFI image to the current
+    /// directory, without booting it.
+    #[arg(long, conflicts_with = "test")]
+    no_boot: bool,
+
+    /// Boot with UEFI firmware (default).
+    #[arg(long, short, overrides_with = "bios")]
     uefi: bool,
+
+    /// Boot with legacy BIOS firmware.
+    #[arg(long, short, overrides_with = "uefi")]
+    bios: bool,
+
+    /// QEMU accelerator.
+    #[arg(long, value_enum, default_value_t = Accel::Kvm)]
+    accel: Accel,
+
+    /// QEMU CPU model, defaults to host with KVM and max with TCG.
+    #[arg(long)]
+    cpu: Option<String>,
+
+    /// CPU feature flags appended to the CPU model, e.g. `+vmx,-la57`.
+    #[arg(long, value_delimiter = ',')]
+    cpu_features: Vec<String>,
+
+    /// Guest memory size, e.g. `512M`.
+    #[arg(long, short)]
+    memory: Option<String>,
+
+    /// Number of vCPUs.
+    #[arg(long)]
+    smp: Option<u32>,
+
+    /// Start the gdb stub on tcp::1234 and wait for gdb before booting.
+    #[arg(long, conflicts_with = "timeout")]
+    gdb: bool,
+
+    /// Kill QEMU and fail if the run takes longer than this many seconds.
+    #[arg(long)]
+    timeout: Option<u64>,
+
+    /// Test report format.
+    #[arg(long, value_enum)]
+    format: Option<ReportFormat>,
+
+    /// Write the test report to this file instead of stdout.
+    #[arg(long, requires = "format")]
+    output: Option<PathBuf>,
+
+    /// Extra raw QEMU arguments, after `--`.
+    #[arg(last = true)]
+    qemu_args: Vec<String>,
+}
+
+/// QEMU exit status when the kernel writes `QemuExitCode::Success` (0x10) to
+/// the isa-debug-exit device: `(0x10 << 1) | 1`.
+const QEMU_SUCCESS: i32 = 33;
+
+fn qemu_command(args: &Args, uefi_image: &str, bios_image: &str) -> Command {
+    let mut cmd = Command::new("qemu-system-x86_64");
+    if args.bios {
+        cmd.arg("-drive")
+            .arg(format!("format=raw,file={bios_image}"));
+    } else {
+        cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
+        cmd.arg("-drive")
+            .arg(format!("format=raw,file={uefi_image}"));
+    }
+    cmd.arg("-nographic");
+
+    let (accel, default_cpu) = match args.accel {
+        Accel::Kvm => ("kvm", "host"),
+        Accel::Tcg => ("tcg", "max"),
+    };
+    let mut cpu = args.cpu.clone().unwrap_or_else(|| default_cpu.to_string());
+    for feature in &args.cpu_features {
+        cpu.push(',');
+        cpu.push_str(feature);
+    }
+    cmd.args(["-accel", accel, "-cpu", &cpu]);
+
+    if let Some(memory) = &args.memory {
+        cmd.args(["-m", memory]);
+    }
+    if let Some(smp) = args.smp {
+        cmd.args(["-smp", &smp.to_string()]);
+    }
+    if args.gdb {
+        cmd.args(["-s", "-S"]);
+    }
+    cmd.args(&args.qemu_args);
+    cmd
 }
 
-fn main() {
+fn main() -> ExitCode {
     let args = Args::parse();
 
     let uefi_path = env!("UEFI_PATH");
-    let bios_path = env!("BIOS_PATH");
 
+    let mut status = ExitCode::SUCCESS;
     if args.test {
-        let mut cmd = std::process::Command::new("qemu-system-x86_64");
-        if args.uefi {
-            cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
-            cmd.arg("-drive")
-                .arg(format!("format=raw,file={uefi_path}"));
-        } else {
-            cmd.arg("-drive")
-                .arg(format!("format=raw,file={bios_path}"));
-        }
-        cmd.args(["-nographic", "-cpu", "host", "-enable-kvm"]);
+        let mut cmd = qemu_command(&args, env!("TEST_UEFI_PATH"), env!("TEST_BIOS_PATH"));
+        cmd.args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"]);
+        cmd.stdout(Stdio::piped());
         let mut child = cmd.spawn().unwrap();
-        child.wait().unwrap();
+
+        // Echo the serial output from a separate thread, so that the timeout
+        // can fire while QEMU is silent.
+        let serial = child.stdout.take().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut serial = BufReader::new(serial);
+            let mut stdout = io::stdout();
+            let mut line = Vec::new();
+            while serial.read_until(b'\n', &mut line).unwrap_or(0) > 0 {
+                stdout.write_all(&line).unwrap();
+                let text = String::from_utf8_lossy(&line);
+                if tx.send(text.trim_end_matches('\n').to_string()).is_err() {
+                    break;
+                }
+                line.clear();
+            }
+        });
+
+        let deadline = args
+            .timeout
+            .map(|secs| Instant::now() + Duration::from_secs(secs));
+        let mut parser = report::Parser::new();
+        let mut timed_out = false;
+        loop {
+            let line = match deadline {
+                Some(deadline) => {
+                    rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            match line {
+                Ok(line) => parser.feed(&line),
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => {
+                    child.kill().unwrap();
+                    timed_out = true;
+                    parser.abort("timed out, QEMU was killed");
+                    break;
+                }
+            }
+        }
+        let exit = child.wait().unwrap();
+        let run = parser.finish();
+
+        if let Some(format) = args.format {
+            let report = run.report(format);
+            match &args.output {
+                Some(path) => fs::write(path, report).expect("failed to write the test report"),
+                None => print!("{report}"),
+            }
+        }
+
+        eprintln!("kernel tests: {}", run.summary());
+        if timed_out {
+            eprintln!(
+                "QEMU hung, killed after {}s",
+                args.timeout.unwrap_or_default()
+            );
+        } else if exit.code() != Some(QEMU_SUCCESS) {
+            eprintln!("QEMU exited with {exit}");
+        }
+        if !run.success() || exit.code() != Some(QEMU_SUCCESS) {
+            status = ExitCode::FAILURE;
+        }
+    } else if !args.no_boot {
+        // The serial console stays on the terminal, for input too.
+        let mut child = qemu_command(&args, uefi_path, env!("BIOS_PATH"))
+            .spawn()
+            .unwrap();
+        let deadline = args
+            .timeout
+            .map(|secs| Instant::now() + Duration::from_secs(secs));
+        let exit = loop {
+            if let Some(exit) = child.try_wait().unwrap() {
+                break Some(exit);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                child.kill().unwrap();
+                child.wait().unwrap();
+                break None;
+            }
+            thread::sleep(Duration::from_millis(100));
+        };
+
+        match exit {
+            Some(exit) if exit.success() => {}
+            Some(exit) => {
+                eprintln!("QEMU exited with {exit}");
+                status = ExitCode::FAILURE;
+            }
+            None => {
+                eprintln!(
+                    "kernel still running, QEMU killed after {}s",
+                    args.timeout.unwrap_or_default()
+                );
+                status = ExitCode::FAILURE;
+            }
+        }
     }
 
     let src = Path::new(uefi_path);
@@ -39,4 +242,6 @@ fn main() {
         .join(Path::new(uefi_path).file_name().unwrap());
 
     fs::copy(src, dst).expect("failed to copy the uefi file");
+
+    status
 }
@@ -0,0 +1,410 @@
+//! Parsing of the kernel test results printed over serial, and their TAP and
+//! JUnit XML reports.
+//!
+//! The line format, and the escaping of the messages, are documented in
+//! `kernel::testing`.
+
+use std::fmt::Write;
+
+use clap::ValueEnum;
+
+const TAG: &str = "[TEST] ";
+const NO_RESULT: &str = "no result, the kernel stopped";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    Tap,
+    Junit,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TestStatus {
+    Passed,
+    Failed(String),
+    Skipped(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestCase {
+    pub name: String,
+    pub status: TestStatus,
+    /// Serial lines printed while the test was running.
+    pub output: Vec<String>,
+}
+
+/// Results of a kernel test run.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TestRun {
+    /// Number of tests the kernel announced.
+    pub planned: Option<usize>,
+    pub cases: Vec<TestCase>,
+    /// Whether the kernel printed its final result line.
+    pub completed: bool,
+}
+
+impl TestRun {
+    pub fn passed(&self) -> usize {
+        self.count(|status| matches!(status, TestStatus::Passed))
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|status| matches!(status, TestStatus::Failed(_)))
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.count(|status| matches!(status, TestStatus::Skipped(_)))
+    }
+
+    /// Tests announced by the kernel but never started.
+    pub fn not_run(&self) -> usize {
+        self.planned.unwrap_or(0).saturating_sub(self.cases.len())
+    }
+
+    /// Returns true if every announced test ran, and none failed.
+    pub fn success(&self) -> bool {
+        self.completed && self.planned.is_some() && self.failed() == 0 && self.not_run() == 0
+    }
+
+    fn count(&self, filter: impl Fn(&TestStatus) -> bool) -> usize {
+        self.cases
+            .iter()
+            .filter(|case| filter(&case.status))
+            .count()
+    }
+
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} passed, {} failed, {} skipped",
+            self.passed(),
+            self.failed(),
+            self.skipped()
+        );
+        if self.not_run() > 0 {
+            write!(summary, ", {} not run", self.not_run()).unwrap();
+        }
+        if !self.completed {
+            summary.push_str(" (incomplete run)");
+        }
+        summary
+    }
+
+    pub fn report(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Tap => self.to_tap(),
+            ReportFormat::Junit => self.to_junit(),
+        }
+    }
+
+    pub fn to_tap(&self) -> String {
+        let mut tap = String::from("TAP version 13\n");
+        writeln!(tap, "1..{}", self.planned.unwrap_or(self.cases.len())).unwrap();
+
+        for (i, case) in self.cases.iter().enumerate() {
+            let n = i + 1;
+            match &case.status {
+                TestStatus::Passed => writeln!(tap, "ok {n} - {}", case.name),
+                TestStatus::Skipped(reason) => {
+                    writeln!(tap, "ok {n} - {} # SKIP {reason}", case.name)
+                }
+                TestStatus::Failed(message) => {
+                    writeln!(tap, "not ok {n} - {}", case.name).unwrap();
+                    writeln!(tap, "  ---").unwrap();
+                    writeln!(tap, "  message: {:?}", message).unwrap();
+                    if !case.output.is_empty() {
+                        writeln!(tap, "  output: |").unwrap();
+                        for line in &case.output {
+                            writeln!(tap, "    {line}").unwrap();
+                        }
+                    }
+                    writeln!(tap, "  ...")
+                }
+            }
+            .unwrap();
+        }
+
+        if !self.completed {
+            writeln!(
+                tap,
+                "Bail out! the kernel stopped before the end of the run"
+            )
+            .unwrap();
+        }
+        tap
+    }
+
+    pub fn to_junit(&self) -> String {
+        let tests = self.cases.len() + self.not_run();
+        let failures = self.failed() + self.not_run();
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        writeln!(
+            xml,
+            "<testsuites tests=\"{tests}\" failures=\"{failures}\" skipped=\"{}\">",
+            self.skipped()
+        )
+        .unwrap();
+        writeln!(
+            xml,
+            "  <testsuite name=\"ktest\" tests=\"{tests}\" failures=\"{failures}\" skipped=\"{}\">",
+            self.skipped()
+        )
+        .unwrap();
+
+        for case in &self.cases {
+            let (classname, name) = case.name.rsplit_once("::").unwrap_or(("ktest", &case.name));
+            write!(
+                xml,
+                "    <testcase classname=\"{}\" name=\"{}\"",
+                escape(classname),
+                escape(name)
+            )
+            .unwrap();
+
+            if case.status == TestStatus::Passed && case.output.is_empty() {
+                xml.push_str("/>\n");
+                continue;
+            }
+
+            xml.push_str(">\n");
+            match &case.status {
+                TestStatus::Passed => {}
+                TestStatus::Failed(message) => {
+                    writeln!(xml, "      <failure message=\"{}\"/>", escape(message)).unwrap()
+                }
+                TestStatus::Skipped(reason) => {
+                    writeln!(xml, "      <skipped message=\"{}\"/>", escape(reason)).unwrap()
+                }
+            }
+            if !case.output.is_empty() {
+                writeln!(
+                    xml,
+                    "      <system-out>{}</system-out>",
+                    escape(&case.output.join("\n"))
+                )
+                .unwrap();
+            }
+            xml.push_str("    </testcase>\n");
+        }
+
+        if self.not_run() > 0 {
+            writeln!(
+                xml,
+                "    <testcase classname=\"ktest\" name=\"not-run\">\n      \
+                 <failure message=\"{} tests were not run\"/>\n    </testcase>",
+                self.not_run()
+            )
+            .unwrap();
+        }
+
+        xml.push_str("  </testsuite>\n</testsuites>\n");
+        xml
+    }
+}
+
+/// Builds a [`TestRun`] from serial lines.
+#[derive(Debug, Default)]
+pub struct Parser {
+    run: TestRun,
+    current: Option<(String, Vec<String>)>,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds a serial line, without its line terminator.
+    pub fn feed(&mut self, line: &str) {
+        let line = line.trim_end_matches('\r');
+        // Firmware escape sequences may precede the tag on the same line.
+        let Some(event) = line.find(TAG).map(|i| &line[i + TAG.len()..]) else {
+            if let Some((_, output)) = &mut self.current {
+                output.push(line.to_string());
+            }
+            return;
+        };
+
+        if let Some(count) = event.strip_prefix("running ") {
+            self.run.planned = count.trim_end_matches(" tests").parse().ok();
+        } else if let Some(name) = event.strip_prefix("start ") {
+            self.abort(NO_RESULT);
+            self.current = Some((name.to_string(), Vec::new()));
+        } else if let Some(name) = event.strip_prefix("ok ") {
+            self.push(name, TestStatus::Passed);
+        } else if let Some(rest) = event.strip_prefix("fail ") {
+            let (name, message) = rest.split_once(": ").unwrap_or((rest, ""));
+            self.push(name, TestStatus::Failed(unescape(message)));
+        } else if let Some(rest) = event.strip_prefix("skip ") {
+            let (name, reason) = rest.split_once(": ").unwrap_or((rest, ""));
+            self.push(name, TestStatus::Skipped(unescape(reason)));
+        } else if event.starts_with("result:") {
+            self.abort(NO_RESULT);
+            self.run.completed = true;
+        }
+    }
+
+    /// Returns the results, failing a test left without a verdict.
+    pub fn finish(mut self) -> TestRun {
+        self.abort(NO_RESULT);
+        self.run
+    }
+
+    fn push(&mut self, name: &str, status: TestStatus) {
+        let output = match self.current.take() {
+            Some((current, output)) if current == name => output,
+            current => {
+                self.current = current;
+                self.abort(NO_RESULT);
+                Vec::new()
+            }
+        };
+
+        self.run.cases.push(TestCase {
+            name: name.to_string(),
+            status,
+            output,
+        });
+    }
+
+    /// Fails the running test, if any, with reason (e.g. a timeout).
+    pub fn abort(&mut self, reason: &str) {
+        if let Some((name, output)) = self.current.take() {
+            self.run.cases.push(TestCase {
+                name,
+                status: TestStatus::Failed(reason.to_string()),
+                output,
+            });
+        }
+    }
+}
+
+/// Restores the line breaks and backslashes the kernel escapes in messages.
+fn unescape(message: &str) -> String {
+    let mut unescaped = String::with_capacity(message.len());
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Escapes text for XML attributes and content, dropping the control
+/// characters XML 1.0 can't represent.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' | '\t' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(log: &str) -> TestRun {
+        let mut parser = Parser::new();
+        for line in log.lines() {
+            parser.feed(line);
+        }
+        parser.finish()
+    }
+
+    /// Serial output of a run: logger lines, prefixed with the time and CPU,
+    /// and test events, possibly after firmware escape sequences or a logger
+    /// prefix.
+    const LOG: &str = "\x1b[2J[    0.001024] cpu0 INFO  kernel::cpu::symbols: Loaded 1847 symbols\r
+[TEST] running 3 tests\r
+[TEST] start ktest::vmx::a\r
+[    0.052001] cpu0 DEBUG kernel::virt::vmx::vcpu: VM exit: Hlt\r
+[TEST] ok ktest::vmx::a\r
+[    0.052317] cpu0 INFO  ktest: [TEST] start ktest::vmx::b\r
+[TEST] fail ktest::vmx::b: VMInstruction(FailInvalid)\r
+[TEST] start ktest::vmx::c\r
+[TEST] skip ktest::vmx::c: no INVVPID\r
+[TEST] result: passed=1 failed=1 skipped=1\r
+";
+
+    #[test]
+    fn parses_results() {
+        let run = parse(LOG);
+        assert_eq!(run.planned, Some(3));
+        assert!(run.completed);
+        assert_eq!((run.passed(), run.failed(), run.skipped()), (1, 1, 1));
+        assert_eq!(
+            run.cases[0].output,
+            ["[    0.052001] cpu0 DEBUG kernel::virt::vmx::vcpu: VM exit: Hlt"]
+        );
+        assert_eq!(run.cases[1].name, "ktest::vmx::b");
+        assert_eq!(
+            run.cases[1].status,
+            TestStatus::Failed("VMInstruction(FailInvalid)".to_string())
+        );
+        assert!(!run.success());
+    }
+
+    #[test]
+    fn multi_line_failure() {
+        let run = parse(
+            "[TEST] running 1 tests\n[TEST] start ktest::mm::a\n[TEST] fail ktest::mm::a: \
+             assertion `left == right` failed\\n  left: 1\\n right: 2 (C:\\\\)\n",
+        );
+        assert_eq!(
+            run.cases[0].status,
+            TestStatus::Failed(
+                "assertion `left == right` failed\n  left: 1\n right: 2 (C:\\)".to_string()
+            )
+        );
+        assert!(run.cases[0].output.is_empty());
+    }
+
+    #[test]
+    fn interrupted_run() {
+        let panic = "[    0.104857] cpu0 ERROR kernel: Panic: oops";
+        let run = parse(&format!(
+            "[TEST] running 2 tests\n[TEST] start a\n{panic}\n"
+        ));
+        assert!(!run.completed);
+        assert_eq!(run.failed(), 1);
+        assert_eq!(run.not_run(), 1);
+        assert_eq!(run.cases[0].output, [panic]);
+        assert_eq!(
+            run.summary(),
+            "0 passed, 1 failed, 0 skipped, 1 not run (incomplete run)"
+        );
+    }
+
+    #[test]
+    fn tap() {
+        let tap = parse(LOG).to_tap();
+        assert!(tap.starts_with("TAP version 13\n1..3\nok 1 - ktest::vmx::a\nnot ok 2"));
+        assert!(tap.contains("ok 3 - ktest::vmx::c # SKIP no INVVPID\n"));
+        assert!(!tap.contains("Bail out!"));
+    }
+
+    #[test]
+    fn junit() {
+        let xml = parse(LOG).to_junit();
+        assert!(xml.contains("<testsuite name=\"ktest\" tests=\"3\" failures=\"1\" skipped=\"1\">"));
+        assert!(xml.contains("<testcase classname=\"ktest::vmx\" name=\"a\">"));
+        assert!(xml.contains("<failure message=\"VMInstruction(FailInvalid)\"/>"));
+        assert_eq!(escape("<a & \"b\"\x1b>"), "&lt;a &amp; &quot;b&quot;&gt;");
+    }
+}